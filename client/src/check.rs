@@ -0,0 +1,177 @@
+use std::time::{Duration, Instant};
+use remote64_common::Frame;
+use crate::sink::Sink;
+
+
+/// Summed RGB difference above which a pixel counts as changed. Analog capture is never perfectly still,
+/// so smaller differences are noise.
+const NOISE_LEVEL: u32 = 32;
+/// Fraction of the pixels that must change for a frame to count as different.
+const CHANGED_PIXELS: f64 = 0.0005;
+
+
+/// Detects the video staying unchanged (apart from capture noise) for longer than a timeout, or no video
+/// arriving at all, which usually means the console has crashed.
+/// 
+/// Frames are compared against the last one that changed, rather than the one before, so a picture that
+/// keeps drifting by less than the noise level still counts as a change eventually.
+pub struct FreezeCheck {
+    timeout: Duration,
+    last_change: Vec<u8>,
+    unchanged_since: Instant,
+}
+impl FreezeCheck {
+    pub fn new(timeout: Duration) -> Self { Self {
+        timeout,
+        last_change: vec![],
+        unchanged_since: Instant::now(),
+    }}
+    
+    /// Restarts the timeout, e.g. once the ROM has actually been started.
+    pub fn reset(&mut self) {
+        self.unchanged_since = Instant::now();
+    }
+    
+    /// True once the video hasn't changed for the timeout, whether or not frames kept arriving.
+    pub fn frozen(&self) -> bool { self.unchanged_for() > self.timeout }
+    
+    /// How long the video has been unchanged for.
+    pub fn unchanged_for(&self) -> Duration { self.unchanged_since.elapsed() }
+}
+impl Sink for FreezeCheck {
+    fn frame(&mut self, frame: &Frame) {
+        if changed(&self.last_change, &frame.video) {
+            self.last_change.clone_from(&frame.video);
+            self.unchanged_since = Instant::now();
+        }
+    }
+}
+
+/// Whether enough pixels differ by more than noise between two frames, or they differ in size.
+fn changed(before: &[u8], after: &[u8]) -> bool {
+    if before.len() != after.len() { return true }
+    
+    let changed = before.chunks_exact(3).zip(after.chunks_exact(3)).filter(|(a, b)| {
+        a.iter().zip(b.iter()).map(|(a, b)| a.abs_diff(*b) as u32).sum::<u32>() > NOISE_LEVEL
+    }).count();
+    
+    changed as f64 > (before.len() / 3) as f64 * CHANGED_PIXELS
+}
+
+
+/// Detects the screen being filled with a specific color, which a test ROM can use to signal that
+/// it has finished.
+pub struct ColorCheck {
+    color: [u8; 3],
+    reached: bool,
+}
+impl ColorCheck {
+    pub fn new(color: [u8; 3]) -> Self { Self {
+        color,
+        reached: false,
+    }}
+    
+    pub fn color(&self) -> [u8; 3] { self.color }
+    
+    pub fn reached(&self) -> bool { self.reached }
+}
+impl Sink for ColorCheck {
+    fn frame(&mut self, frame: &Frame) {
+        if !self.reached {
+            self.reached = matches_color(&frame.video, self.color);
+        }
+    }
+}
+
+/// Whether at least 90% of the frame's pixels are close to the given color.
+pub fn matches_color(video: &[u8], color: [u8; 3]) -> bool {
+    let pixels = video.len() / 3;
+    if pixels == 0 { return false }
+    
+    let matching = video.chunks_exact(3).filter(|px| {
+        px.iter().zip(color.iter()).all(|(a, b)| a.abs_diff(*b) <= 16)
+    }).count();
+    
+    matching * 10 >= pixels * 9
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const TIMEOUT: Duration = Duration::from_millis(50);
+    
+    /// A 64x64 frame of one gray level, with a few pixels of noise.
+    fn frame(level: u8, noise: u8) -> Frame {
+        let mut video = vec![level; 64 * 64 * 3];
+        for (i, byte) in video.iter_mut().enumerate().step_by(7) {
+            *byte = byte.saturating_add(noise * (i % 3) as u8);
+        }
+        
+        Frame::new(0, 64, 64, video, vec![])
+    }
+    
+    #[test]
+    fn noise_does_not_count_as_a_change() {
+        let mut check = FreezeCheck::new(TIMEOUT);
+        check.frame(&frame(100, 0));
+        for i in 0..10 {
+            check.frame(&frame(100, i % 4));
+            std::thread::sleep(TIMEOUT / 5);
+        }
+        
+        assert!(check.frozen());
+    }
+    
+    #[test]
+    fn changing_video_is_not_frozen() {
+        let mut check = FreezeCheck::new(TIMEOUT);
+        for i in 0..10 {
+            check.frame(&frame(if i % 2 == 0 { 16 } else { 235 }, 3));
+            std::thread::sleep(TIMEOUT / 5);
+        }
+        
+        assert!(!check.frozen());
+    }
+    
+    #[test]
+    fn small_moving_part_is_a_change() {
+        let mut check = FreezeCheck::new(TIMEOUT);
+        let still = frame(16, 0);
+        check.frame(&still);
+        std::thread::sleep(TIMEOUT * 2);
+        
+        // An 8x8 sprite appearing on an otherwise still screen
+        let mut moved = still.clone();
+        for y in 0..8 {
+            moved.video[(y * 64 * 3)..][..(8 * 3)].fill(255);
+        }
+        check.frame(&moved);
+        
+        assert!(!check.frozen());
+    }
+    
+    #[test]
+    fn no_frames_at_all_is_frozen() {
+        let mut check = FreezeCheck::new(TIMEOUT);
+        check.frame(&frame(100, 0));
+        assert!(!check.frozen());
+        
+        std::thread::sleep(TIMEOUT * 2);
+        assert!(check.frozen());
+        
+        check.reset();
+        assert!(!check.frozen());
+    }
+    
+    #[test]
+    fn color_must_fill_most_of_the_screen() {
+        let mut video = [10, 200, 30].repeat(100);
+        assert!(matches_color(&video, [0, 210, 20]));
+        
+        video[..(11 * 3)].fill(255);
+        assert!(!matches_color(&video, [0, 210, 20]));
+        assert!(!matches_color(&[], [0, 0, 0]));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use clap::ArgMatches;
+use serde::Deserialize;
+use toml_edit::{DocumentMut, Item, Table, value};
+use remote64_common::network::tls::Trust;
+
+/// Default location of the client's configuration file.
+pub const CONFIG_PATH: &str = "remote64-client.toml";
+
+
+/// Settings that are kept between runs of the client.
+/// 
+/// Loaded from a TOML file, where every table and key is optional and falls back to the defaults
+/// below. Command line arguments override whatever the file says.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+}
+
+/// Connects to the server over TLS, when enabled. Otherwise, over plain TCP.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// How the server's certificate is trusted.
+    pub trust: TrustMode,
+    /// PEM file with the certificate of the authority trusted by `TrustMode::Ca`.
+    pub ca: Option<PathBuf>,
+    /// Fingerprints of the certificates trusted by `TrustMode::Pin`, by server address (e.g. `example.com:6400`).
+    pub pins: BTreeMap<String, String>,
+}
+impl TlsConfig {
+    /// How to trust the server at `addr`.
+    pub fn trust(&self, addr: &str) -> Trust {
+        match self.trust {
+            TrustMode::System => Trust::System,
+            TrustMode::Ca => Trust::Ca(self.ca.clone().unwrap_or_default()),
+            TrustMode::Pin => Trust::Pinned(self.pins.get(addr).cloned()),
+        }
+    }
+}
+
+/// Authenticates with the server as this user, when set. Servers may require it, or only allow certain users.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub user: Option<String>,
+    /// The user's password or token. Only used to answer the server's challenge, and never sent itself.
+    pub secret: Option<String>,
+}
+impl AuthConfig {
+    /// The user and secret to authenticate with, if any.
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        self.user.as_deref().zip(self.secret.as_deref())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrustMode {
+    /// Certificates issued by the system's root authorities.
+    #[default]
+    System,
+    /// Certificates issued by the authority in `ca`.
+    Ca,
+    /// The certificate pinned for the server, whoever issued it. A server without a pin is trusted on first
+    /// use, and its certificate pinned.
+    Pin,
+}
+
+impl Config {
+    /// Reads the configuration file at `path`. A missing file is not an error, and gives the defaults.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No configuration file at {}, using defaults.", path.display());
+                return Ok(Self::default());
+            },
+            Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+        };
+        
+        toml::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+    
+    /// Replaces any settings that were also given as command line arguments. `addr` is the server's address.
+    pub fn override_with(&mut self, matches: &ArgMatches, addr: &str) {
+        if matches.is_present("tls") {
+            self.tls.enabled = true;
+        }
+        if let Some(ca) = matches.value_of("tls-ca") {
+            self.tls.enabled = true;
+            self.tls.trust = TrustMode::Ca;
+            self.tls.ca = Some(PathBuf::from(ca));
+        }
+        if let Some(pin) = matches.value_of("tls-pin") {
+            self.tls.enabled = true;
+            self.tls.trust = TrustMode::Pin;
+            self.tls.pins.insert(addr.to_owned(), pin.to_owned());
+        }
+        if let Some(user) = matches.value_of("user") {
+            self.auth.user = Some(user.to_owned());
+        }
+        if let Some(secret) = matches.value_of("secret") {
+            self.auth.secret = Some(secret.to_owned());
+        }
+    }
+    
+    /// Checks that the settings make sense together, describing the first problem found.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tls.trust == TrustMode::Ca && self.tls.ca.is_none() {
+            return Err("tls.ca must be set to trust a custom authority".to_owned());
+        }
+        if let Some((addr, pin)) = self.tls.pins.iter().find(|(_, pin)| !valid_fingerprint(pin)) {
+            return Err(format!("tls.pins has an invalid fingerprint for {}, expected 32 hex bytes such as AB:CD:..., not {:?}", addr, pin));
+        }
+        
+        if self.auth.user.is_some() != self.auth.secret.is_some() {
+            return Err("auth.user and auth.secret must be set together".to_owned());
+        }
+        if self.auth.user.as_ref().map(|user| user.is_empty() || user.len() > u8::MAX as usize).unwrap_or(false) {
+            return Err("auth.user must be 1 to 255 bytes long".to_owned());
+        }
+        
+        Ok(())
+    }
+}
+
+fn valid_fingerprint(text: &str) -> bool {
+    let digits = text.chars().filter(|c| *c != ':').collect::<String>();
+    digits.len() == 64 && digits.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+
+/// Pins the certificate fingerprint of the server at `addr` into a configuration file, creating it if needed.
+/// Anything else in the file, including comments, is left as it was.
+pub fn save_pin<P: AsRef<Path>>(path: P, addr: &str, fingerprint: &str) -> Result<(), String> {
+    let path = path.as_ref();
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+    };
+    let mut doc = text.parse::<DocumentMut>().map_err(|err| format!("failed to parse {}: {}", path.display(), err))?;
+    
+    let tls = doc.entry("tls").or_insert(Item::Table(Table::new()));
+    let tls = tls.as_table_mut().ok_or(format!("`tls` in {} is not a table", path.display()))?;
+    let pins = tls.entry("pins").or_insert(Item::Table(Table::new()));
+    let pins = pins.as_table_mut().ok_or(format!("`tls.pins` in {} is not a table", path.display()))?;
+    pins[addr] = value(fingerprint);
+    
+    std::fs::write(path, doc.to_string()).map_err(|err| format!("failed to write {}: {}", path.display(), err))
+}
@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use remote64_common::intercom::{Endpoint, InterMessage};
+use remote64_common::{Packet, Packet::*, RecordingInfo};
+use remote64_common::util::sha256_file;
+
+/// How long the server may take to finish saving the session's recording.
+const PROCESSING_TIMEOUT: Duration = Duration::from_secs(600);
+/// How long a download may go without receiving any data.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// Ends the current session, then downloads the server's recording of it into `dir`.
+/// 
+/// `ready` is the `RecordingReady` already received, if the server ended the session itself. Otherwise
+/// this must be called while this client is being serviced, or its session was ended by the server,
+/// or the server refuses. Every file is checked against the hash sent by the server, before telling
+/// the server it may delete its copy.
+/// 
+/// Returns the paths of the downloaded files.
+pub fn download_recording(endpoint: &Endpoint, dir: &Path, ready: Option<RecordingInfo>) -> Result<Vec<PathBuf>, String> {
+    let send = |packet: Packet| endpoint.send.try_send(InterMessage::SocketPacket(packet)).unwrap_or_default();
+    
+    let info = match ready {
+        Some(info) => info,
+        None => {
+            send(SessionEnd);
+            info!("Waiting for the server to save its recording...");
+            let deadline = Instant::now() + PROCESSING_TIMEOUT;
+            loop {
+                match next_packet(endpoint, deadline)? {
+                    RecordingReady(info) => break info,
+                    RequestDenied => return Err("server refused to end the session".to_owned()),
+                    Close => return Err("server closed the connection".to_owned()),
+                    _ => ()
+                }
+            }
+        },
+    };
+    if info.files.is_empty() {
+        warn!("Server has no recording of this session.");
+        return Ok(vec![]);
+    }
+    
+    std::fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    let mut files = vec![];
+    for file in &info.files {
+        // Never trust the server with anything more than a file name
+        let name = Path::new(&file.name).file_name().ok_or(format!("invalid file name {:?}", file.name))?;
+        let path = dir.join(name);
+        let writer = BufWriter::new(File::create(&path).map_err(|err| err.to_string())?);
+        files.push((path, writer, 0u64));
+    }
+    
+    let total: u64 = info.files.iter().map(|file| file.size).sum();
+    let mut received = 0u64;
+    let mut next_progress = 0;
+    send(RecordingRequest(info.session));
+    while received < total {
+        match next_packet(endpoint, Instant::now() + STALL_TIMEOUT)? {
+            RecordingData(chunk) if chunk.session == info.session => {
+                let (path, writer, written) = files.get_mut(chunk.file as usize).ok_or("server sent an unknown file")?;
+                if chunk.offset != *written {
+                    return Err(format!("{} was received out of order", path.display()));
+                }
+                writer.write_all(&chunk.data).map_err(|err| err.to_string())?;
+                *written += chunk.data.len() as u64;
+                received += chunk.data.len() as u64;
+                
+                let percent = received * 100 / total;
+                if percent >= next_progress {
+                    info!("Downloading recording: {}% ({:.1} / {:.1} MiB)", percent, received as f64 / 1048576.0, total as f64 / 1048576.0);
+                    next_progress = percent - percent % 10 + 10;
+                }
+            },
+            RequestDenied => return Err("server refused to send the recording".to_owned()),
+            Close => return Err("server closed the connection".to_owned()),
+            _ => ()
+        }
+    }
+    
+    let mut paths = vec![];
+    for ((path, mut writer, _), file) in files.into_iter().zip(info.files.iter()) {
+        writer.flush().map_err(|err| err.to_string())?;
+        drop(writer);
+        
+        let hash = sha256_file(&path).map_err(|err| err.to_string())?;
+        if hash != file.sha256 {
+            return Err(format!("{} does not match the server's hash", path.display()));
+        }
+        paths.push(path);
+    }
+    
+    send(RecordingReceived(info.session));
+    info!("Downloaded the server's recording into {}", dir.display());
+    
+    Ok(paths)
+}
+
+/// Waits for the next packet received from the server, ignoring any other messages.
+fn next_packet(endpoint: &Endpoint, deadline: Instant) -> Result<Packet, String> {
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match endpoint.recv.recv_timeout(timeout) {
+            Ok(InterMessage::ReceivedPacket(packet)) => return Ok(packet),
+            Ok(_) => (),
+            Err(_) => return Err("timed out waiting for the server".to_owned()),
+        }
+    }
+}
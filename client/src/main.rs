@@ -2,21 +2,33 @@
 extern crate env_logger;
 #[macro_use] extern crate log;
 
-use std::cmp::max;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
 use std::time::{Duration, Instant};
 use clap::{AppSettings, Arg, Command};
-use cpal::{BufferSize, SampleRate, StreamConfig};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use crossbeam_queue::SegQueue;
 use log::LevelFilter;
-use minifb::{Key, Scale, ScaleMode, Window, WindowOptions};
 use remote64_common::intercom::{BroadcastNetwork, InterMessage};
 use remote64_common::{Feature, Packet};
-use crate::socket::SocketManager;
+use remote64_common::network::tls::TlsClient;
+use remote64_common::util::parse_duration;
+use crate::config::{CONFIG_PATH, Config, TrustMode, save_pin};
+use crate::download::download_recording;
+use crate::playback::Playback;
+use crate::record::{Recorder, VideoFormat};
+use crate::run::RunOptions;
+use crate::sink::{Sink, Stats};
+use crate::socket::{DEFAULT_DOMAIN, PORT, SocketManager};
 
 
+mod check;
+mod config;
+mod download;
+mod playback;
+mod record;
+mod report;
+mod run;
+mod shutdown;
+mod sink;
 mod socket;
 
 
@@ -33,11 +45,78 @@ fn main() {
             .takes_value(true)
             .multiple_occurrences(true)
             .possible_values(["LivePlayback", "AudioRecording", "InputHandling"])
+            .global(true)
             .help("Specify a feature you wish to use if available. Use multiple -f/--feature args to specify multiple features."))
         .arg(Arg::new("domain")
             .long("domain")
-            .takes_value(true))
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::new("tls")
+            .long("tls")
+            .global(true)
+            .help("Connect over TLS, trusting the server's certificate as configured (by default, if issued by the system's root authorities)."))
+        .arg(Arg::new("tls-ca")
+            .long("tls-ca")
+            .takes_value(true)
+            .global(true)
+            .help("Connect over TLS, trusting the server's certificate if issued by the authority in this PEM file."))
+        .arg(Arg::new("tls-pin")
+            .long("tls-pin")
+            .takes_value(true)
+            .global(true)
+            .help("Connect over TLS, trusting only the server certificate with this SHA-256 fingerprint (e.g. a self-signed one)."))
+        .arg(Arg::new("user")
+            .long("user")
+            .takes_value(true)
+            .global(true)
+            .help("Authenticate with the server as this user."))
+        .arg(Arg::new("secret")
+            .long("secret")
+            .takes_value(true)
+            .env("REMOTE64_SECRET")
+            .hide_env_values(true)
+            .global(true)
+            .help("Password or token of the user given by --user. Prefer setting it in the environment, where other users can't see it."))
+        .arg(Arg::new("config")
+            .long("config")
+            .takes_value(true)
+            .default_value(CONFIG_PATH)
+            .global(true)
+            .help("Path of the client's configuration file. Command line arguments override the settings in it."))
+        .arg(Arg::new("headless")
+            .long("headless")
+            .global(true)
+            .help("Run without a window or audio output. Received frames are only passed on to sinks (stats, checks, file writers)."))
+        .arg(Arg::new("record")
+            .long("record")
+            .takes_value(true)
+            .global(true)
+            .help("Save the received video and audio into this directory."))
+        .arg(Arg::new("record-format")
+            .long("record-format")
+            .takes_value(true)
+            .default_value("y4m")
+            .possible_values(["y4m", "mp4"])
+            .global(true)
+            .help("Video format used by --record. mp4 requires ffmpeg to be installed."))
+        .arg(Arg::new("download-recording")
+            .long("download-recording")
+            .takes_value(true)
+            .global(true)
+            .help("When done, end the session and download the server's own recording of it into this directory."))
+        .arg(Arg::new("duration")
+            .long("duration")
+            .takes_value(true)
+            .validator(validate_duration)
+            .help("Stop after this long (e.g. 30s, 10m). Otherwise runs until the window is closed, the server disconnects, or it is interrupted (Ctrl+C)."))
+        .arg(Arg::new("screenshot-dir")
+            .long("screenshot-dir")
+            .takes_value(true)
+            .default_value(".")
+            .global(true)
+            .help("Directory where screenshots are saved when pressing F12."))
         .arg(Arg::new("verbose")
+            .global(true)
             .short('v')
             .long("verbose")
             .takes_value(true)
@@ -45,12 +124,53 @@ fn main() {
             .default_value("info")
             .possible_values(["error", "warn", "info", "debug", "trace"])
             .help("Specify the console log level. Environment variable 'RUST_LOG' will override this option."))
+        .subcommand(Command::new("run")
+            .about("Runs a ROM on the server without user interaction, then exits with a code describing the result.")
+            .after_help("Exit codes: 0 success, 1 client error, 3 request denied, 4 timed out, 5 crash detected, 6 checkpoint missed.")
+            .arg(Arg::new("rom")
+                .required(true)
+                .help("Path of the ROM to upload."))
+            .arg(Arg::new("duration")
+                .long("duration")
+                .takes_value(true)
+                .default_value("30s")
+                .validator(validate_duration)
+                .help("How long to run the ROM for, once loaded (e.g. 30s, 2m)."))
+            .arg(Arg::new("out")
+                .long("out")
+                .takes_value(true)
+                .default_value("remote64-run")
+                .help("Directory where run artifacts are saved."))
+            .arg(Arg::new("queue-timeout")
+                .long("queue-timeout")
+                .takes_value(true)
+                .validator(validate_duration)
+                .help("Give up if still waiting in the server's queue after this long."))
+            .arg(Arg::new("crash-on-freeze")
+                .long("crash-on-freeze")
+                .takes_value(true)
+                .validator(validate_duration)
+                .help("Treat the video not changing (beyond capture noise), or not arriving at all, for this long as a crash."))
+            .arg(Arg::new("end-color")
+                .long("end-color")
+                .takes_value(true)
+                .validator(|color| run::parse_color(color).ok_or("expected a color as RRGGBB hex"))
+                .help("End the run successfully once the screen is filled with this color (RRGGBB)."))
+            .arg(Arg::new("checkpoint")
+                .long("checkpoint")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .validator(|checkpoint| run::parse_checkpoint(checkpoint).ok_or("expected a checkpoint as NAME=RRGGBB"))
+                .help("Require the screen to be filled with a color at some point during the run (NAME=RRGGBB). Use multiple --checkpoint args to specify multiple checkpoints.")))
         .next_line_help(true)
         .setting(AppSettings::DeriveDisplayOrder)
         .get_matches();
     
+    // Global args are propagated down into the subcommand's matches, but not back up
+    let globals = matches.subcommand().map(|(_, sub)| sub).unwrap_or(&matches);
+    
     // Setup program-wide logger format
-    let level = match std::env::var("RUST_LOG").unwrap_or(matches.value_of("verbose").unwrap_or("info").to_owned()).as_str() {
+    let level = match std::env::var("RUST_LOG").unwrap_or(globals.value_of("verbose").unwrap_or("info").to_owned()).as_str() {
         "error" => LevelFilter::Error,
         "warn" => LevelFilter::Warn,
         "info" => LevelFilter::Info,
@@ -65,120 +185,137 @@ fn main() {
     }
     
     // Collect features from cli arguments
-    let features: Vec<Feature> = matches.values_of("features").unwrap_or_default().map(|feat| Feature::from_str(feat).unwrap_or_default()).collect();
+    let features: Vec<Feature> = globals.values_of("features").unwrap_or_default().map(|feat| Feature::from_str(feat).unwrap_or_default()).collect();
+    
+    let domain = globals.value_of("domain").unwrap_or(DEFAULT_DOMAIN);
+    let addr = format!("{}:{}", domain, PORT);
+    let config_path = globals.value_of("config").unwrap_or(CONFIG_PATH);
+    let config = Config::load(config_path).and_then(|mut config| {
+        config.override_with(globals, &addr);
+        config.validate()?;
+        Ok(config)
+    });
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+    
+    let tls = match config.tls.enabled {
+        true => match TlsClient::new(domain, &config.tls.trust(&addr)) {
+            Ok(tls) => Some(tls),
+            Err(err) => {
+                error!("Failed to connect to the server: {}", err);
+                std::process::exit(1);
+            }
+        },
+        false => None,
+    };
     
     let mut intercom = BroadcastNetwork::<InterMessage>::new();
     
     // Initialize socket manager which handles the client's connection with the remote64 server
-    SocketManager::init(matches.value_of("domain"), features, intercom.endpoint());
-    
-    
-    let mut window_buf: Vec<u32> = vec![0; WIDTH * HEIGHT];
-    let mut window = Window::new("remote64-client", WIDTH, HEIGHT, WindowOptions {
-        borderless: false,
-        title: false,
-        resize: false,
-        scale: Scale::X1,
-        scale_mode: ScaleMode::AspectRatioStretch,
-        topmost: false,
-        transparency: false,
-        none: false
-    }).unwrap();
-    
-    window.limit_update_rate(Some(Duration::from_secs_f32(1.0/15.0)));
-    
-    let audio_queue = Arc::new(SegQueue::new());
-    let callback_audio_queue = audio_queue.clone();
-    
-    let audio_host = cpal::default_host();
-    let audio_device = audio_host.default_output_device().unwrap();
-    let config = StreamConfig {
-        channels: 2,
-        sample_rate: SampleRate(44100),
-        buffer_size: BufferSize::Fixed(256),
+    let fingerprint = match SocketManager::init(&addr, tls.as_ref(), config.auth.credentials(), features.clone(), intercom.endpoint()) {
+        Ok(fingerprint) => fingerprint,
+        Err(err) => {
+            error!("Failed to connect to the server: {}", err);
+            std::process::exit(1);
+        }
     };
-    let stream = audio_device.build_output_stream(
-        &config,
-        move |output_buffer: &mut [f32], _info: &cpal::OutputCallbackInfo| {
-            for output_sample in output_buffer.iter_mut() {
-                if let Some(sample) = callback_audio_queue.pop() {
-                    *output_sample = sample;
-                } else {
-                    *output_sample = 0.0;
-                }
+    
+    // Trust on first use: the certificate is pinned, so a different one is refused from now on
+    if let (TrustMode::Pin, Some(fingerprint)) = (config.tls.trust, &fingerprint) {
+        if !config.tls.pins.contains_key(&addr) {
+            warn!("Trusting {}'s certificate on first use. Fingerprint: {}", addr, fingerprint);
+            if let Err(err) = save_pin(config_path, &addr, fingerprint) {
+                warn!("Failed to pin the certificate: {}", err);
             }
-        },
-        move |_| {
-            //
         }
-    ).unwrap();
-    stream.play().unwrap();
+    }
     
-    let video_queue = Arc::new(SegQueue::<Vec<u8>>::new());
-    let output_video_queue = video_queue.clone();
     
-    let frame_endpoint = intercom.endpoint();
-    drop(frame_endpoint.send);
-    std::thread::spawn(move || {
-        while let Ok(msg) = frame_endpoint.recv.recv() {
-            match msg {
-                InterMessage::BulkFrames(frames) => {
-                    debug!("Bulk Received: {}", frames.len());
-                    for frame in frames {
-                        video_queue.push(frame.video);
-                        for sample in frame.audio {
-                            audio_queue.push(sample);
-                        }
-                    }
-                },
-                _ => ()
-            }
-        }
-    });
+    let screenshot_dir = PathBuf::from(globals.value_of("screenshot-dir").unwrap_or("."));
+    let mut playback = Playback::new("remote64-client", globals.is_present("headless"), screenshot_dir, &mut intercom);
+    
+    // Setup the consumers of received frames
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(Stats::default())];
+    if let Some(dir) = globals.value_of("record") {
+        let format = globals.value_of("record-format").and_then(|format| VideoFormat::from_str(format).ok()).unwrap_or(VideoFormat::Y4m);
+        sinks.push(Box::new(Recorder::new(dir, format)));
+    }
     
+    if let Some(("run", run_matches)) = matches.subcommand() {
+        let outcome = run::run(RunOptions::from_matches(run_matches, domain), features, playback, sinks, intercom);
+        std::thread::sleep(Duration::from_secs(1));
+        std::process::exit(outcome.exit_code());
+    }
     
     let video_endpoint = intercom.endpoint();
-    drop(video_endpoint.recv);
     
     std::thread::spawn(move || {
         intercom.start();
     });
     
-    let mut last_request = Instant::now();
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        let queue_len = output_video_queue.len();
-        if queue_len < 35 && last_request.elapsed() > Duration::from_millis(1000) {
-            let remaining = max(35 - queue_len, 20);
-            debug!("Framebuffer Health: Local: {} | Requested: {}", queue_len, remaining);
-            if queue_len == 0 {
-                warn!("Framebuffer is starving...");
-            }
-            
-            if remaining > 0 {
-                video_endpoint.send.try_send(InterMessage::SocketPacket(Packet::FrameRequest(remaining as u32))).unwrap();
-            }
-            
-            last_request = Instant::now();
+    // Without a window to close, a signal is how the session is usually ended, and the sinks and download
+    //   below still have to run afterwards
+    shutdown::install_handler();
+    let deadline = globals.value_of("duration").and_then(parse_duration).map(|duration| Instant::now() + duration);
+    // Sent once the session has ended, if the server ended it
+    let mut ready = None;
+    while playback.is_open() {
+        if shutdown::requested() {
+            info!("Interrupted, stopping.");
+            break;
         }
-        
-        let video = output_video_queue.pop();
-        if video.is_none() {
-            window.update_with_buffer(&window_buf, WIDTH, HEIGHT).unwrap();
-            continue;
+        if deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+            info!("Duration elapsed, stopping.");
+            break;
         }
-        let video = video.unwrap();
-        
         
-        for i in 0..window_buf.len() {
-            let r = video[(i * 3)];
-            let g = video[(i * 3) + 1];
-            let b = video[(i * 3) + 2];
-            window_buf[i] = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
+        if let Some(frame) = playback.update() {
+            for sink in sinks.iter_mut() {
+                sink.frame(&frame);
+            }
         }
         
-        window.update_with_buffer(&window_buf, WIDTH, HEIGHT).unwrap();
+        // Other received messages are only needed once downloading starts
+        let mut closed = false;
+        while let Ok(msg) = video_endpoint.recv.try_recv() {
+            match msg {
+                InterMessage::ReceivedPacket(Packet::Close) => closed = true,
+                InterMessage::ReceivedPacket(Packet::RecordingReady(info)) => ready = Some(info),
+                _ => (),
+            }
+        }
+        if closed {
+            info!("Server closed the connection, stopping.");
+            break;
+        }
+        if ready.is_some() {
+            info!("Server ended the session, stopping.");
+            break;
+        }
+    }
+    
+    for sink in sinks.iter_mut() {
+        sink.finish();
+    }
+    
+    if let Some(dir) = globals.value_of("download-recording") {
+        if let Err(err) = download_recording(&video_endpoint, Path::new(dir), ready) {
+            error!("Failed to download the server's recording: {}", err);
+        }
     }
     
     video_endpoint.send.try_send(InterMessage::Kill).unwrap_or_default();
     std::thread::sleep(Duration::from_secs(1));
+}
+
+fn validate_duration(text: &str) -> Result<(), String> {
+    match parse_duration(text) {
+        Some(_) => Ok(()),
+        None => Err(String::from("expected a duration such as 500ms, 30s or 2m")),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,252 @@
+use std::cmp::max;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use cpal::{BufferSize, SampleRate, Stream, StreamConfig};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crossbeam_channel::Sender;
+use crossbeam_queue::SegQueue;
+use image::RgbImage;
+use minifb::{Key, KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
+use remote64_common::intercom::{BroadcastNetwork, InterMessage};
+use remote64_common::{Frame, Packet};
+use crate::{HEIGHT, WIDTH};
+
+/// Rate at which frames are presented, with or without a window.
+const FRAME_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / 15);
+
+
+/// Presents frames received from the server in a window and on the default audio output.
+/// 
+/// Frames arrive in bulk through the intercom network and are buffered locally. Calling `update()`
+/// keeps that buffer topped up by requesting more frames from the server, and presents the next one.
+/// 
+/// Both the window and the audio output are optional. When headless, or if either device can't be
+/// opened, frames are still paced and returned from `update()` so they can be passed on to sinks.
+/// 
+/// Pressing F12 in the window saves a PNG screenshot of the current frame.
+pub struct Playback {
+    window: Option<Window>,
+    window_buf: Vec<u32>,
+    /// Size of the last presented frame, which the server may change (e.g. by cropping).
+    size: (usize, usize),
+    screenshot_dir: PathBuf,
+    _audio_stream: Option<Stream>,
+    frame_queue: Arc<SegQueue<Frame>>,
+    requests: Sender<InterMessage>,
+    last_request: Instant,
+    next_frame: Instant,
+}
+impl Playback {
+    pub fn new(title: &str, headless: bool, screenshot_dir: PathBuf, intercom: &mut BroadcastNetwork<InterMessage>) -> Self {
+        let window = if headless { None } else { open_window(title) };
+        
+        let audio_queue = Arc::new(SegQueue::new());
+        let audio_stream = if headless { None } else { open_audio(audio_queue.clone()) };
+        let play_audio = audio_stream.is_some();
+        
+        let frame_queue = Arc::new(SegQueue::<Frame>::new());
+        let input_frame_queue = frame_queue.clone();
+        
+        let frame_endpoint = intercom.endpoint();
+        drop(frame_endpoint.send);
+        std::thread::spawn(move || {
+            while let Ok(msg) = frame_endpoint.recv.recv() {
+                if let InterMessage::BulkFrames(frames) = msg {
+                    debug!("Bulk Received: {}", frames.len());
+                    for frame in frames {
+                        if play_audio {
+                            for sample in &frame.audio {
+                                audio_queue.push(*sample);
+                            }
+                        }
+                        input_frame_queue.push(frame);
+                    }
+                }
+            }
+        });
+        
+        let request_endpoint = intercom.endpoint();
+        drop(request_endpoint.recv);
+        
+        Self {
+            window,
+            window_buf: vec![0; WIDTH * HEIGHT],
+            size: (WIDTH, HEIGHT),
+            screenshot_dir,
+            _audio_stream: audio_stream,
+            frame_queue,
+            requests: request_endpoint.send,
+            last_request: Instant::now(),
+            next_frame: Instant::now(),
+        }
+    }
+    
+    /// Returns false once the window has been closed or escape was pressed. Always true when headless.
+    pub fn is_open(&self) -> bool {
+        match &self.window {
+            Some(window) => window.is_open() && !window.is_key_down(Key::Escape),
+            None => true,
+        }
+    }
+    
+    /// Requests more frames if the local buffer is running low, then presents the next buffered frame.
+    /// 
+    /// Returns the frame that was presented, if one was available.
+    pub fn update(&mut self) -> Option<Frame> {
+        let queue_len = self.frame_queue.len();
+        if queue_len < 35 && self.last_request.elapsed() > Duration::from_millis(1000) {
+            let remaining = max(35 - queue_len, 20);
+            debug!("Framebuffer Health: Local: {} | Requested: {}", queue_len, remaining);
+            if queue_len == 0 {
+                warn!("Framebuffer is starving...");
+            }
+            
+            if remaining > 0 {
+                self.requests.try_send(InterMessage::SocketPacket(Packet::FrameRequest(remaining as u32))).unwrap_or_default();
+            }
+            
+            self.last_request = Instant::now();
+        }
+        
+        let frame = match self.frame_queue.pop() {
+            Some(frame) => frame,
+            None => {
+                self.idle();
+                return None;
+            }
+        };
+        
+        let size = (frame.width as usize, frame.height as usize);
+        if size != self.size && size.0 > 0 && size.1 > 0 {
+            debug!("Frames are now {}x{}.", size.0, size.1);
+            self.size = size;
+            self.window_buf = vec![0; size.0 * size.1];
+        }
+        
+        if frame.video.len() >= self.window_buf.len() * 3 {
+            for i in 0..self.window_buf.len() {
+                let r = frame.video[i * 3];
+                let g = frame.video[(i * 3) + 1];
+                let b = frame.video[(i * 3) + 2];
+                self.window_buf[i] = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
+            }
+        }
+        
+        self.present();
+        
+        Some(frame)
+    }
+    
+    /// Redraws the last presented frame without requesting anything from the server.
+    pub fn idle(&mut self) {
+        self.present();
+    }
+    
+    /// Shows the window buffer, and waits until the next frame is due.
+    fn present(&mut self) {
+        if let Some(window) = &mut self.window {
+            if let Err(err) = window.update_with_buffer(&self.window_buf, self.size.0, self.size.1) {
+                warn!("Failed to update window: {}", err);
+            }
+            
+            if window.is_key_pressed(Key::F12, KeyRepeat::No) {
+                self.screenshot();
+            }
+        }
+        
+        // The window limits its own update rate, but that isn't available when headless
+        let now = Instant::now();
+        if self.window.is_none() && self.next_frame > now {
+            std::thread::sleep(self.next_frame - now);
+        }
+        self.next_frame = Instant::now() + FRAME_INTERVAL;
+    }
+}
+
+impl Playback {
+    /// Saves the currently shown frame into the screenshot directory.
+    fn screenshot(&self) {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let path = self.screenshot_dir.join(format!("screenshot-{}.png", millis));
+        
+        let mut img = RgbImage::new(self.size.0 as u32, self.size.1 as u32);
+        for (px, color) in img.pixels_mut().zip(self.window_buf.iter()) {
+            px.0 = [(color >> 16) as u8, (color >> 8) as u8, *color as u8];
+        }
+        
+        let result = std::fs::create_dir_all(&self.screenshot_dir).map_err(|err| err.to_string())
+            .and_then(|_| img.save(&path).map_err(|err| err.to_string()));
+        match result {
+            Ok(_) => info!("Saved screenshot to {}", path.display()),
+            Err(err) => warn!("Failed to save screenshot: {}", err),
+        }
+    }
+}
+
+fn open_window(title: &str) -> Option<Window> {
+    let mut window = match Window::new(title, WIDTH, HEIGHT, WindowOptions {
+        borderless: false,
+        title: false,
+        resize: false,
+        scale: Scale::X1,
+        scale_mode: ScaleMode::AspectRatioStretch,
+        topmost: false,
+        transparency: false,
+        none: false
+    }) {
+        Ok(window) => window,
+        Err(err) => {
+            warn!("Unable to open a window, continuing without video output: {}", err);
+            return None;
+        }
+    };
+    
+    window.limit_update_rate(Some(FRAME_INTERVAL));
+    
+    Some(window)
+}
+
+fn open_audio(audio_queue: Arc<SegQueue<f32>>) -> Option<Stream> {
+    let audio_host = cpal::default_host();
+    let audio_device = match audio_host.default_output_device() {
+        Some(device) => device,
+        None => {
+            warn!("No audio output device found, continuing without audio output.");
+            return None;
+        }
+    };
+    let config = StreamConfig {
+        channels: 2,
+        sample_rate: SampleRate(44100),
+        buffer_size: BufferSize::Fixed(256),
+    };
+    let stream = match audio_device.build_output_stream(
+        &config,
+        move |output_buffer: &mut [f32], _info: &cpal::OutputCallbackInfo| {
+            for output_sample in output_buffer.iter_mut() {
+                if let Some(sample) = audio_queue.pop() {
+                    *output_sample = sample;
+                } else {
+                    *output_sample = 0.0;
+                }
+            }
+        },
+        move |err| {
+            warn!("Audio output error: {}", err);
+        }
+    ) {
+        Ok(stream) => stream,
+        Err(err) => {
+            warn!("Unable to open audio output, continuing without it: {}", err);
+            return None;
+        }
+    };
+    
+    if let Err(err) = stream.play() {
+        warn!("Unable to start audio output, continuing without it: {}", err);
+        return None;
+    }
+    
+    Some(stream)
+}
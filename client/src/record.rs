@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+use hound::{WavSpec, WavWriter};
+use remote64_common::Frame;
+use crate::sink::Sink;
+use crate::{HEIGHT, WIDTH};
+
+/// Rate of the interleaved stereo audio sent by the server.
+const SAMPLE_RATE: u32 = 44100;
+const CHANNELS: u16 = 2;
+/// Frames buffered before the recording starts, used to estimate the capture frame rate.
+const ESTIMATE_FRAMES: usize = 30;
+/// Largest gap in frame sequence numbers that will be filled in, rather than treated as a restart.
+const MAX_GAP: u32 = 600;
+
+
+/// Container used for the video half of a local recording.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VideoFormat {
+    /// Uncompressed YUV4MPEG2, written directly.
+    Y4m,
+    /// H.264 encoded by piping raw frames into `ffmpeg`.
+    Mp4,
+}
+impl FromStr for VideoFormat {
+    type Err = String;
+    
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.to_ascii_lowercase().as_str() {
+            "y4m" => Ok(VideoFormat::Y4m),
+            "mp4" => Ok(VideoFormat::Mp4),
+            _ => Err(format!("unknown video format {}", text)),
+        }
+    }
+}
+
+enum VideoWriter {
+    Y4m(BufWriter<File>),
+    Ffmpeg(Child, BufWriter<ChildStdin>),
+}
+impl VideoWriter {
+    fn write_frame(&mut self, video: &[u8]) -> std::io::Result<()> {
+        match self {
+            VideoWriter::Y4m(writer) => {
+                writer.write_all(b"FRAME\n")?;
+                writer.write_all(&rgb_to_yuv444(video))
+            },
+            VideoWriter::Ffmpeg(_, stdin) => stdin.write_all(video),
+        }
+    }
+    
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            VideoWriter::Y4m(mut writer) => writer.flush(),
+            VideoWriter::Ffmpeg(mut child, mut stdin) => {
+                stdin.flush()?;
+                drop(stdin);
+                
+                let status = child.wait()?;
+                if !status.success() {
+                    warn!("ffmpeg exited with {}", status);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+
+/// Saves the received stream to disk, as a video file and a WAV file.
+/// 
+/// Both files are kept aligned using the frames' sequence numbers: when frames were dropped before
+/// reaching the client, the last video frame is repeated and silence is inserted in their place.
+/// 
+/// The capture frame rate isn't known ahead of time, so it is estimated from the amount of audio
+/// accompanying the first few frames, before the video file is started.
+pub struct Recorder {
+    dir: PathBuf,
+    format: VideoFormat,
+    video: Option<VideoWriter>,
+    wav: Option<WavWriter<BufWriter<File>>>,
+    pending: Vec<Frame>,
+    samples_per_frame: f64,
+    last_sequence: Option<u32>,
+    last_video: Vec<u8>,
+    failed: bool,
+}
+impl Recorder {
+    pub fn new<P: AsRef<Path>>(dir: P, format: VideoFormat) -> Self { Self {
+        dir: dir.as_ref().to_owned(),
+        format,
+        video: None,
+        wav: None,
+        pending: vec![],
+        samples_per_frame: 0.0,
+        last_sequence: None,
+        last_video: vec![],
+        failed: false,
+    }}
+    
+    pub fn video_path(&self) -> PathBuf {
+        match self.format {
+            VideoFormat::Y4m => self.dir.join("video.y4m"),
+            VideoFormat::Mp4 => self.dir.join("video.mp4"),
+        }
+    }
+    
+    pub fn audio_path(&self) -> PathBuf {
+        self.dir.join("audio.wav")
+    }
+    
+    /// Opens both output files, using the frames received so far to pick a frame rate and resolution.
+    fn start(&mut self) -> std::io::Result<()> {
+        let (width, height) = self.pending.iter()
+            .find(|frame| frame.width > 0 && frame.height > 0)
+            .map(|frame| (frame.width as usize, frame.height as usize))
+            .unwrap_or((WIDTH, HEIGHT));
+        self.last_video = vec![0; width * height * 3];
+        
+        let samples: usize = self.pending.iter().map(|frame| frame.audio.len()).sum();
+        let per_frame = samples as f64 / self.pending.len().max(1) as f64;
+        let fps = if per_frame > 0.0 {
+            ((SAMPLE_RATE * CHANNELS as u32) as f64 / per_frame).round().clamp(1.0, 120.0) as u32
+        } else {
+            60
+        };
+        self.samples_per_frame = per_frame;
+        info!("Recording {}x{} to {} at an estimated {} fps.", width, height, self.dir.display(), fps);
+        
+        std::fs::create_dir_all(&self.dir)?;
+        
+        let spec = WavSpec {
+            channels: CHANNELS,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        self.wav = Some(WavWriter::create(self.audio_path(), spec).map_err(to_io_error)?);
+        
+        self.video = Some(match self.format {
+            VideoFormat::Y4m => {
+                let mut writer = BufWriter::new(File::create(self.video_path())?);
+                writeln!(writer, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", width, height, fps)?;
+                VideoWriter::Y4m(writer)
+            },
+            VideoFormat::Mp4 => {
+                let mut child = Command::new("ffmpeg")
+                    .args(["-loglevel", "error", "-y", "-f", "rawvideo", "-pix_fmt", "rgb24"])
+                    .args(["-s", &format!("{}x{}", width, height), "-r", &fps.to_string(), "-i", "-"])
+                    .args(["-c:v", "libx264", "-pix_fmt", "yuv420p"])
+                    .arg(self.video_path())
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .spawn()?;
+                let stdin = child.stdin.take().ok_or_else(|| to_io_error("ffmpeg has no stdin"))?;
+                VideoWriter::Ffmpeg(child, BufWriter::new(stdin))
+            },
+        });
+        
+        for frame in std::mem::take(&mut self.pending) {
+            self.write(&frame)?;
+        }
+        
+        Ok(())
+    }
+    
+    fn write(&mut self, frame: &Frame) -> std::io::Result<()> {
+        let (video, wav) = match (&mut self.video, &mut self.wav) {
+            (Some(video), Some(wav)) => (video, wav),
+            _ => return Ok(()),
+        };
+        
+        if let Some(last) = self.last_sequence {
+            let gap = frame.sequence.wrapping_sub(last).wrapping_sub(1);
+            if gap > 0 && gap <= MAX_GAP {
+                debug!("Filling in {} dropped frames.", gap);
+                let silence = self.samples_per_frame.round() as usize;
+                for _ in 0..gap {
+                    video.write_frame(&self.last_video)?;
+                    for _ in 0..silence {
+                        wav.write_sample(0.0f32).map_err(to_io_error)?;
+                    }
+                }
+            }
+        }
+        self.last_sequence = Some(frame.sequence);
+        
+        // The resolution can't change mid-recording, so frames of any other size repeat the last one
+        if frame.video.len() == self.last_video.len() {
+            self.last_video.copy_from_slice(&frame.video);
+        }
+        video.write_frame(&self.last_video)?;
+        for sample in &frame.audio {
+            wav.write_sample(*sample).map_err(to_io_error)?;
+        }
+        
+        Ok(())
+    }
+    
+    fn fail(&mut self, err: std::io::Error) {
+        error!("Local recording stopped: {}", err);
+        self.failed = true;
+    }
+}
+impl Sink for Recorder {
+    fn frame(&mut self, frame: &Frame) {
+        if self.failed { return }
+        
+        let result = if self.video.is_none() {
+            self.pending.push(frame.clone());
+            if self.pending.len() < ESTIMATE_FRAMES { return }
+            
+            self.start()
+        } else {
+            self.write(frame)
+        };
+        
+        if let Err(err) = result {
+            self.fail(err);
+        }
+    }
+    
+    fn finish(&mut self) {
+        if !self.failed && self.video.is_none() && !self.pending.is_empty() {
+            if let Err(err) = self.start() {
+                self.fail(err);
+            }
+        }
+        
+        if let Some(wav) = self.wav.take() {
+            if let Err(err) = wav.finalize() {
+                error!("Failed to finish {}: {}", self.audio_path().display(), err);
+            }
+        }
+        if let Some(video) = self.video.take() {
+            if let Err(err) = video.finish() {
+                error!("Failed to finish {}: {}", self.video_path().display(), err);
+            }
+        }
+    }
+    
+    fn artifacts(&self) -> Vec<PathBuf> {
+        [self.video_path(), self.audio_path()].into_iter().filter(|path| path.is_file()).collect()
+    }
+}
+
+/// Converts packed RGB into planar full resolution Y'CbCr (BT.601, limited range), as expected by Y4M's C444.
+fn rgb_to_yuv444(rgb: &[u8]) -> Vec<u8> {
+    let pixels = rgb.len() / 3;
+    let mut yuv = vec![0u8; pixels * 3];
+    let (y_plane, chroma) = yuv.split_at_mut(pixels);
+    let (u_plane, v_plane) = chroma.split_at_mut(pixels);
+    
+    for (i, px) in rgb.chunks_exact(3).enumerate() {
+        let (r, g, b) = (px[0] as f32, px[1] as f32, px[2] as f32);
+        y_plane[i] = (16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8;
+        u_plane[i] = (128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8;
+        v_plane[i] = (128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8;
+    }
+    
+    yuv
+}
+
+fn to_io_error<E: ToString>(err: E) -> std::io::Error {
+    std::io::Error::other(err.to_string())
+}
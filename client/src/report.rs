@@ -0,0 +1,312 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use remote64_common::ServerInfo;
+use remote64_common::rom::{RomHeader, sha256_hex};
+
+
+/// Machine readable summary of a hardware run, written as JSON and as JUnit XML.
+/// 
+/// The report only holds plain data, filled in by the `run` subcommand as events happen.
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub rom: RomSummary,
+    pub server: Option<ServerSummary>,
+    pub outcome: Outcome,
+    pub exit_code: i32,
+    pub timings: Timings,
+    pub crashes: Vec<Crash>,
+    pub checkpoints: Vec<Checkpoint>,
+    pub artifacts: Vec<PathBuf>,
+    /// Human readable timeline of the run.
+    pub log: Vec<String>,
+}
+
+/// How a non-interactive run ended.
+/// 
+/// Each outcome maps to its own process exit code, so build pipelines can tell them apart.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub enum Outcome {
+    /// The ROM ran for the whole duration, or reached its end condition.
+    Success,
+    /// Something went wrong on the client's side (unreadable ROM, lost connection, etc).
+    Error,
+    /// The server refused the request, or doesn't support a requested feature.
+    Denied,
+    /// The server stopped responding, or the queue took too long.
+    Timeout,
+    /// The console appears to have crashed while running the ROM.
+    Crashed,
+    /// The ROM ran, but didn't reach every checkpoint.
+    Failed,
+}
+impl Outcome {
+    /// Exit code 2 is skipped, since clap already uses it for invalid arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::Error => 1,
+            Outcome::Denied => 3,
+            Outcome::Timeout => 4,
+            Outcome::Crashed => 5,
+            Outcome::Failed => 6,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RomSummary {
+    pub path: PathBuf,
+    pub size: usize,
+    pub sha256: String,
+    /// Byte order of the file, if it has a recognizable ROM header.
+    pub format: Option<String>,
+    pub title: Option<String>,
+    pub game_code: Option<String>,
+    pub version: Option<u8>,
+    pub crc1: Option<String>,
+    pub crc2: Option<String>,
+}
+impl RomSummary {
+    pub fn new(path: &Path, rom: &[u8]) -> Self {
+        let header = RomHeader::parse(rom);
+        
+        Self {
+            path: path.to_owned(),
+            size: rom.len(),
+            sha256: sha256_hex(rom),
+            format: header.as_ref().map(|header| format!("{:?}", header.order)),
+            title: header.as_ref().map(|header| header.title.clone()),
+            game_code: header.as_ref().map(|header| header.game_code.clone()),
+            version: header.as_ref().map(|header| header.version),
+            crc1: header.as_ref().map(|header| format!("{:08X}", header.crc1)),
+            crc2: header.as_ref().map(|header| format!("{:08X}", header.crc2)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerSummary {
+    pub address: String,
+    pub version: u16,
+    /// Components of the server that had failed when connecting.
+    pub degraded: Vec<String>,
+    pub features: Vec<String>,
+}
+impl ServerSummary {
+    pub fn new(address: &str, info: &ServerInfo) -> Self { Self {
+        address: address.to_owned(),
+        version: info.version,
+        degraded: info.degraded.iter().map(|component| format!("{:?}", component)).collect(),
+        features: info.features.iter().map(|feat| format!("{:?}", feat)).collect(),
+    }}
+}
+
+/// Durations of each phase of the run, in seconds.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Timings {
+    /// Unix time at which the run started.
+    pub started_at: u64,
+    /// Connecting and waiting in the server's queue.
+    pub queue_secs: f64,
+    /// Uploading the ROM, and the server loading it onto the console.
+    pub load_secs: f64,
+    /// Running the ROM.
+    pub run_secs: f64,
+    pub total_secs: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Crash {
+    /// Seconds since the ROM was started.
+    pub at_secs: f64,
+    pub reason: String,
+}
+
+/// A screen the ROM is expected to show at some point during the run.
+#[derive(Clone, Debug, Serialize)]
+pub struct Checkpoint {
+    pub name: String,
+    pub passed: bool,
+    /// Seconds since the ROM was started, if the checkpoint was reached.
+    pub at_secs: Option<f64>,
+}
+
+impl Report {
+    pub fn new(rom: RomSummary) -> Self { Self {
+        rom,
+        server: None,
+        outcome: Outcome::Error,
+        exit_code: Outcome::Error.exit_code(),
+        timings: Timings {
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            ..Default::default()
+        },
+        crashes: vec![],
+        checkpoints: vec![],
+        artifacts: vec![],
+        log: vec![],
+    }}
+    
+    pub fn set_outcome(&mut self, outcome: Outcome) {
+        self.outcome = outcome;
+        self.exit_code = outcome.exit_code();
+    }
+    
+    pub fn crash(&mut self, at: Duration, reason: String) {
+        self.crashes.push(Crash {
+            at_secs: at.as_secs_f64(),
+            reason,
+        });
+    }
+    
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        
+        Ok(())
+    }
+    
+    /// Writes the report as a JUnit XML test suite: one test case for the run itself, followed by
+    /// one for each checkpoint.
+    pub fn write_junit<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        
+        let name = self.rom.title.clone().filter(|title| !title.is_empty())
+            .unwrap_or_else(|| self.rom.path.display().to_string());
+        let failures = self.checkpoints.iter().filter(|checkpoint| !checkpoint.passed).count()
+            + matches!(self.outcome, Outcome::Crashed | Outcome::Failed) as usize;
+        let errors = matches!(self.outcome, Outcome::Error | Outcome::Denied | Outcome::Timeout) as usize;
+        
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(w, r#"<testsuites>"#)?;
+        writeln!(w, r#"  <testsuite name="remote64" tests="{}" failures="{}" errors="{}" time="{:.3}">"#,
+            1 + self.checkpoints.len(), failures, errors, self.timings.total_secs)?;
+        
+        writeln!(w, r#"    <properties>"#)?;
+        writeln!(w, r#"      <property name="rom.sha256" value="{}"/>"#, self.rom.sha256)?;
+        if let Some(server) = &self.server {
+            writeln!(w, r#"      <property name="server.address" value="{}"/>"#, escape(&server.address))?;
+            writeln!(w, r#"      <property name="server.version" value="{}"/>"#, server.version)?;
+        }
+        writeln!(w, r#"    </properties>"#)?;
+        
+        writeln!(w, r#"    <testcase classname="remote64.{}" name="run" time="{:.3}">"#, escape(&name), self.timings.run_secs)?;
+        match self.outcome {
+            Outcome::Success => (),
+            Outcome::Crashed | Outcome::Failed => {
+                let message = self.crashes.first().map(|crash| crash.reason.clone()).unwrap_or_else(|| format!("{:?}", self.outcome));
+                writeln!(w, r#"      <failure type="{:?}" message="{}"/>"#, self.outcome, escape(&message))?;
+            },
+            Outcome::Error | Outcome::Denied | Outcome::Timeout => {
+                writeln!(w, r#"      <error type="{:?}" message="Run ended with {:?}"/>"#, self.outcome, self.outcome)?;
+            },
+        }
+        writeln!(w, r#"      <system-out>{}</system-out>"#, escape(&self.log.join("\n")))?;
+        writeln!(w, r#"    </testcase>"#)?;
+        
+        for checkpoint in &self.checkpoints {
+            let time = checkpoint.at_secs.unwrap_or_default();
+            if checkpoint.passed {
+                writeln!(w, r#"    <testcase classname="remote64.{}" name="{}" time="{:.3}"/>"#, escape(&name), escape(&checkpoint.name), time)?;
+            } else {
+                writeln!(w, r#"    <testcase classname="remote64.{}" name="{}" time="{:.3}">"#, escape(&name), escape(&checkpoint.name), time)?;
+                writeln!(w, r#"      <failure type="CheckpointMissed" message="Checkpoint was never reached"/>"#)?;
+                writeln!(w, r#"    </testcase>"#)?;
+            }
+        }
+        
+        writeln!(w, r#"  </testsuite>"#)?;
+        writeln!(w, r#"</testsuites>"#)?;
+        
+        w.flush()
+    }
+}
+
+/// Escapes text for use in XML attributes and element content.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    
+    escaped
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Writes the report's JUnit XML to a temporary file, and reads it back.
+    fn junit(report: &Report, name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("remote64-{}-{}.xml", name, std::process::id()));
+        report.write_junit(&path).unwrap();
+        let xml = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap_or_default();
+        
+        xml
+    }
+    
+    fn report(outcome: Outcome) -> Report {
+        let mut report = Report::new(RomSummary::new(Path::new("test.z64"), &[0; 64]));
+        report.set_outcome(outcome);
+        report.checkpoints = vec![
+            Checkpoint { name: "title".to_owned(), passed: true, at_secs: Some(1.0) },
+            Checkpoint { name: "menu".to_owned(), passed: false, at_secs: None },
+        ];
+        
+        report
+    }
+    
+    #[test]
+    fn escape_replaces_xml_special_characters() {
+        assert_eq!(escape(r#"<a & "b" 'c'>"#), "&lt;a &amp; &quot;b&quot; &apos;c&apos;&gt;");
+        assert_eq!(escape("plain text"), "plain text");
+    }
+    
+    #[test]
+    fn junit_escapes_names_and_log() {
+        let mut report = report(Outcome::Crashed);
+        report.checkpoints[0].name = r#"<&"'>"#.to_owned();
+        report.crash(Duration::from_secs(2), "bad <frame> & worse".to_owned());
+        report.log.push(r#"saw "<&'>""#.to_owned());
+        let xml = junit(&report, "junit-escape");
+        
+        assert!(xml.contains(r#"name="&lt;&amp;&quot;&apos;&gt;""#));
+        assert!(xml.contains(r#"message="bad &lt;frame&gt; &amp; worse""#));
+        assert!(xml.contains("<system-out>saw &quot;&lt;&amp;&apos;&gt;&quot;</system-out>"));
+        assert!(!xml.contains(r#"<&"'>"#));
+    }
+    
+    #[test]
+    fn junit_counts_failures_and_errors_per_outcome() {
+        // The missed checkpoint always counts as one failure
+        let cases = [
+            (Outcome::Success, 1, 0),
+            (Outcome::Crashed, 2, 0),
+            (Outcome::Failed, 2, 0),
+            (Outcome::Error, 1, 1),
+            (Outcome::Denied, 1, 1),
+            (Outcome::Timeout, 1, 1),
+        ];
+        
+        for (outcome, failures, errors) in cases {
+            let xml = junit(&report(outcome), &format!("junit-{:?}", outcome));
+            let suite = format!(r#"tests="3" failures="{}" errors="{}""#, failures, errors);
+            
+            assert!(xml.contains(&suite), "{:?}: {}", outcome, xml);
+            assert_eq!(xml.matches("<failure ").count(), failures, "{:?}", outcome);
+            assert_eq!(xml.matches("<error ").count(), errors, "{:?}", outcome);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use clap::ArgMatches;
+use image::RgbImage;
+use remote64_common::intercom::{BroadcastNetwork, InterMessage};
+use remote64_common::{Feature, Frame, Packet, Packet::*};
+use remote64_common::util::parse_duration;
+use crate::check::{ColorCheck, FreezeCheck};
+use crate::download::download_recording;
+use crate::playback::Playback;
+use crate::report::{Checkpoint, Outcome, Report, RomSummary, ServerSummary};
+use crate::sink::Sink;
+
+/// How long the server may take to answer the initial info request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the server may take to load the uploaded ROM onto the console.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a running session may go without receiving any frames.
+const STARVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// Settings of the `run` subcommand.
+pub struct RunOptions {
+    pub rom: PathBuf,
+    pub duration: Duration,
+    pub out: PathBuf,
+    pub queue_timeout: Option<Duration>,
+    pub freeze_timeout: Option<Duration>,
+    pub end_color: Option<[u8; 3]>,
+    pub checkpoints: Vec<(String, [u8; 3])>,
+    /// Where to save the server's recording of the session, if it should be downloaded.
+    pub download: Option<PathBuf>,
+    /// Address of the server, as given on the command line. Only used for reporting.
+    pub server: String,
+}
+impl RunOptions {
+    pub fn from_matches(matches: &ArgMatches, server: &str) -> Self { Self {
+        rom: PathBuf::from(matches.value_of("rom").unwrap_or_default()),
+        duration: matches.value_of("duration").and_then(parse_duration).unwrap_or(Duration::from_secs(30)),
+        out: PathBuf::from(matches.value_of("out").unwrap_or("remote64-run")),
+        queue_timeout: matches.value_of("queue-timeout").and_then(parse_duration),
+        freeze_timeout: matches.value_of("crash-on-freeze").and_then(parse_duration),
+        end_color: matches.value_of("end-color").and_then(parse_color),
+        checkpoints: matches.values_of("checkpoint").unwrap_or_default().filter_map(parse_checkpoint).collect(),
+        download: matches.value_of("download-recording").map(PathBuf::from),
+        server: server.to_owned(),
+    }}
+}
+
+/// Parses a color given as six hex digits (`RRGGBB`), with or without a leading `#`.
+pub fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let text = text.trim_start_matches('#');
+    if text.len() != 6 { return None }
+    
+    let value = u32::from_str_radix(text, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// Parses a checkpoint given as `NAME=RRGGBB`.
+pub fn parse_checkpoint(text: &str) -> Option<(String, [u8; 3])> {
+    let (name, color) = text.rsplit_once('=')?;
+    if name.is_empty() { return None }
+    
+    Some((name.to_owned(), parse_color(color)?))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Connecting,
+    Queued,
+    Uploading,
+    Running,
+}
+
+/// Timeline of everything notable that happened during a run. Saved as `run.log` in the output directory.
+struct RunLog {
+    start: Instant,
+    lines: Vec<String>,
+}
+impl RunLog {
+    fn new() -> Self { Self {
+        start: Instant::now(),
+        lines: vec![],
+    }}
+    
+    fn event(&mut self, text: String) {
+        info!("{}", text);
+        self.lines.push(format!("[{:>9.3}s] {}", self.start.elapsed().as_secs_f64(), text));
+    }
+}
+
+
+/// Queues on the server, uploads the ROM, and watches it run until the duration elapses or an end
+/// condition is met. Artifacts and reports are written to the output directory afterwards.
+/// 
+/// Presented frames are passed on to the given sinks, and the files they write are listed in the report.
+/// 
+/// Takes ownership of the intercom network, and starts it once all of its endpoints are created.
+pub fn run(opts: RunOptions, features: Vec<Feature>, mut playback: Playback, mut sinks: Vec<Box<dyn Sink>>, mut intercom: BroadcastNetwork<InterMessage>) -> Outcome {
+    let mut log = RunLog::new();
+    
+    let rom = match std::fs::read(&opts.rom) {
+        Ok(rom) => rom,
+        Err(err) => {
+            error!("Failed to read ROM {}: {}", opts.rom.display(), err);
+            return Outcome::Error;
+        }
+    };
+    
+    let mut report = Report::new(RomSummary::new(&opts.rom, &rom));
+    
+    let endpoint = intercom.endpoint();
+    std::thread::spawn(move || {
+        intercom.start();
+    });
+    
+    let send = |packet: Packet| endpoint.send.try_send(InterMessage::SocketPacket(packet)).unwrap_or_default();
+    
+    send(InfoRequest);
+    let mut state = State::Connecting;
+    let mut state_since = Instant::now();
+    let mut last_queue_request = Instant::now();
+    let mut queue_position = None;
+    
+    // Sent once the session has ended, if the server ended it
+    let mut ready = None;
+    let mut last_frame: Option<Frame> = None;
+    let mut last_frame_at = Instant::now();
+    
+    let mut freeze_check = opts.freeze_timeout.map(FreezeCheck::new);
+    let mut color_check = opts.end_color.map(ColorCheck::new);
+    let mut checkpoints: Vec<(String, ColorCheck, Option<Duration>)> = opts.checkpoints.iter()
+        .map(|(name, color)| (name.clone(), ColorCheck::new(*color), None))
+        .collect();
+    
+    let outcome = 'running: loop {
+        if !playback.is_open() {
+            log.event("Window was closed before the run finished.".to_owned());
+            break Outcome::Error;
+        }
+        
+        while let Ok(msg) = endpoint.recv.try_recv() {
+            let packet = match msg {
+                InterMessage::ReceivedPacket(packet) => packet,
+                _ => continue
+            };
+            
+            match (state, packet) {
+                (State::Connecting, InfoResponse(info)) => {
+                    log.event(format!("Connected to server version {} with features {:?}.", info.version, info.features));
+                    report.server = Some(ServerSummary::new(&opts.server, &info));
+                    if !info.degraded.is_empty() {
+                        log.event(format!("Server is degraded ({}), the queue won't move until it recovers.", info.degraded.iter().map(|component| component.to_string()).collect::<Vec<_>>().join(", ")));
+                    }
+                    if let Some(missing) = features.iter().find(|feat| !info.features.contains(feat)) {
+                        log.event(format!("Server does not support requested feature {:?}.", missing));
+                        break 'running Outcome::Denied;
+                    }
+                    
+                    send(QueueRequest);
+                    state = State::Queued;
+                    state_since = Instant::now();
+                    last_queue_request = Instant::now();
+                },
+                (State::Queued, QueueResponse(0)) => {
+                    log.event(format!("Uploading {} ({:.2} KiB).", opts.rom.display(), rom.len() as f64 / 1024.0));
+                    send(RomUpload(rom.clone()));
+                    report.timings.queue_secs = log.start.elapsed().as_secs_f64();
+                    state = State::Uploading;
+                    state_since = Instant::now();
+                },
+                (State::Queued, QueueResponse(position)) if queue_position != Some(position) => {
+                    log.event(format!("Waiting in queue at position {}.", position));
+                    queue_position = Some(position);
+                },
+                (State::Uploading, RomLoaded) => {
+                    log.event(format!("ROM loaded. Running for {:.1}s.", opts.duration.as_secs_f64()));
+                    report.timings.load_secs = state_since.elapsed().as_secs_f64();
+                    state = State::Running;
+                    state_since = Instant::now();
+                    last_frame_at = Instant::now();
+                    if let Some(check) = &mut freeze_check {
+                        check.reset();
+                    }
+                },
+                (_, RecordingReady(info)) => ready = Some(info),
+                (_, RequestDenied) => {
+                    log.event(format!("Server denied the request while {:?}.", state));
+                    break 'running Outcome::Denied;
+                },
+                (_, Close) => {
+                    log.event("Server closed the connection.".to_owned());
+                    break 'running Outcome::Error;
+                },
+                _ => ()
+            }
+        }
+        
+        match state {
+            State::Connecting => if state_since.elapsed() > RESPONSE_TIMEOUT {
+                log.event("Server did not respond to info request.".to_owned());
+                break Outcome::Timeout;
+            },
+            State::Queued => {
+                if let Some(timeout) = opts.queue_timeout {
+                    if state_since.elapsed() > timeout {
+                        log.event("Gave up waiting in the queue.".to_owned());
+                        break Outcome::Timeout;
+                    }
+                }
+                if last_queue_request.elapsed() > Duration::from_secs(1) {
+                    send(QueueRequest);
+                    last_queue_request = Instant::now();
+                }
+            },
+            State::Uploading => if state_since.elapsed() > LOAD_TIMEOUT {
+                log.event("Server did not finish loading the ROM.".to_owned());
+                break Outcome::Timeout;
+            },
+            State::Running => {
+                if state_since.elapsed() >= opts.duration {
+                    log.event("Run duration elapsed.".to_owned());
+                    break Outcome::Success;
+                }
+                
+                match playback.update() {
+                    Some(frame) => {
+                        last_frame_at = Instant::now();
+                        
+                        for sink in sinks.iter_mut() {
+                            sink.frame(&frame);
+                        }
+                        
+                        for (name, check, reached_at) in checkpoints.iter_mut().filter(|(_, _, reached_at)| reached_at.is_none()) {
+                            check.frame(&frame);
+                            if check.reached() {
+                                log.event(format!("Checkpoint {} reached.", name));
+                                *reached_at = Some(state_since.elapsed());
+                            }
+                        }
+                        
+                        if let Some(check) = &mut color_check {
+                            check.frame(&frame);
+                            if check.reached() {
+                                let color = check.color();
+                                log.event(format!("End color {:02X}{:02X}{:02X} reached.", color[0], color[1], color[2]));
+                                last_frame = Some(frame);
+                                break Outcome::Success;
+                            }
+                        }
+                        
+                        if let Some(check) = &mut freeze_check {
+                            check.frame(&frame);
+                        }
+                        
+                        last_frame = Some(frame);
+                    },
+                    None => if last_frame_at.elapsed() > STARVE_TIMEOUT {
+                        log.event("Server stopped sending frames.".to_owned());
+                        break Outcome::Timeout;
+                    }
+                }
+                
+                // Checked with or without a new frame, as the video stopping altogether counts too
+                if let Some(check) = &freeze_check {
+                    if check.frozen() {
+                        let reason = match last_frame_at.elapsed() >= check.unchanged_for() {
+                            true => format!("No video has been received for {:.1}s.", last_frame_at.elapsed().as_secs_f64()),
+                            false => format!("Video has been frozen for {:.1}s.", check.unchanged_for().as_secs_f64()),
+                        };
+                        log.event(reason.clone());
+                        report.crash(state_since.elapsed().saturating_sub(check.unchanged_for()), reason);
+                        break Outcome::Crashed;
+                    }
+                }
+                
+                continue;
+            }
+        }
+        
+        playback.idle();
+    };
+    
+    if state == State::Running {
+        report.timings.run_secs = state_since.elapsed().as_secs_f64();
+    }
+    report.timings.total_secs = log.start.elapsed().as_secs_f64();
+    
+    let missed = checkpoints.iter().filter(|(_, _, reached_at)| reached_at.is_none()).count();
+    let outcome = if outcome == Outcome::Success && missed > 0 {
+        log.event(format!("{} of {} checkpoints were never reached.", missed, checkpoints.len()));
+        Outcome::Failed
+    } else {
+        outcome
+    };
+    report.checkpoints = checkpoints.into_iter().map(|(name, _, reached_at)| Checkpoint {
+        name,
+        passed: reached_at.is_some(),
+        at_secs: reached_at.map(|at| at.as_secs_f64()),
+    }).collect();
+    
+    log.event(format!("Run finished: {:?} (exit code {}).", outcome, outcome.exit_code()));
+    report.set_outcome(outcome);
+    
+    // Only a session that was being serviced has a recording to download
+    if let (Some(dir), State::Uploading | State::Running) = (&opts.download, state) {
+        match download_recording(&endpoint, dir, ready) {
+            Ok(paths) => {
+                log.event(format!("Downloaded {} recording files from the server.", paths.len()));
+                report.artifacts.extend(paths);
+            },
+            Err(err) => log.event(format!("Failed to download the server's recording: {}", err)),
+        }
+    }
+    
+    endpoint.send.try_send(InterMessage::Kill).unwrap_or_default();
+    
+    for sink in sinks.iter_mut() {
+        sink.finish();
+        report.artifacts.extend(sink.artifacts());
+    }
+    
+    if let Err(err) = save_artifacts(&opts, &log, last_frame, &mut report) {
+        error!("Failed to save run artifacts to {}: {}", opts.out.display(), err);
+        return Outcome::Error;
+    }
+    
+    outcome
+}
+
+/// Saves the run log and last frame, then the JSON and JUnit reports listing them.
+fn save_artifacts(opts: &RunOptions, log: &RunLog, last_frame: Option<Frame>, report: &mut Report) -> std::io::Result<()> {
+    std::fs::create_dir_all(&opts.out)?;
+    
+    let log_path = opts.out.join("run.log");
+    let mut file = File::create(&log_path)?;
+    for line in &log.lines {
+        writeln!(file, "{}", line)?;
+    }
+    report.artifacts.push(log_path);
+    report.log = log.lines.clone();
+    
+    if let Some(img) = last_frame.and_then(|frame| RgbImage::from_raw(frame.width as u32, frame.height as u32, frame.video)) {
+        let frame_path = opts.out.join("last-frame.png");
+        match img.save(&frame_path) {
+            Ok(_) => report.artifacts.push(frame_path),
+            Err(err) => warn!("Failed to save last frame: {}", err),
+        }
+    }
+    
+    report.write_json(opts.out.join("report.json"))?;
+    report.write_junit(opts.out.join("report.xml"))?;
+    
+    Ok(())
+}
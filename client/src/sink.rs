@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+use std::time::Instant;
+use remote64_common::Frame;
+
+
+/// Consumer of the frames received from the server, such as a file writer, checker, or statistics.
+/// 
+/// Sinks see every frame in the order it is presented, whether or not a window or speaker is attached.
+pub trait Sink {
+    /// Called with each frame as it is presented.
+    fn frame(&mut self, frame: &Frame);
+    
+    /// Called once playback has ended, to flush or report anything still pending.
+    fn finish(&mut self) {}
+    
+    /// Files written by this sink, once finished.
+    fn artifacts(&self) -> Vec<PathBuf> { vec![] }
+}
+
+
+/// Counts the received frames and audio samples, and logs a summary when finished.
+#[derive(Default)]
+pub struct Stats {
+    start: Option<Instant>,
+    frames: u64,
+    changed_frames: u64,
+    samples: u64,
+    last_video: Vec<u8>,
+}
+impl Sink for Stats {
+    fn frame(&mut self, frame: &Frame) {
+        self.start.get_or_insert_with(Instant::now);
+        
+        self.frames += 1;
+        self.samples += frame.audio.len() as u64;
+        if frame.video != self.last_video {
+            self.changed_frames += 1;
+            self.last_video.clone_from(&frame.video);
+        }
+    }
+    
+    fn finish(&mut self) {
+        let secs = self.start.map(|start| start.elapsed().as_secs_f64()).unwrap_or_default();
+        let fps = if secs > 0.0 { self.frames as f64 / secs } else { 0.0 };
+        
+        info!("Presented {} frames ({} distinct) over {:.1}s, {:.1} fps. Received {} audio samples.",
+            self.frames, self.changed_frames, secs, fps, self.samples);
+    }
+}
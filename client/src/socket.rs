@@ -1,62 +1,132 @@
 
-use std::time::Duration;
-use remote64_common::{Feature, Packet};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{RecvTimeoutError, select};
+use remote64_common::{Feature, INFO_HEADER, INFO_VERSION, Packet, PacketError};
+use remote64_common::auth;
 use remote64_common::intercom::{Endpoint, InterMessage};
-use remote64_common::network::Client;
+use remote64_common::network::{self, Client, Event, Limits};
+use remote64_common::network::tls::TlsClient;
 
+pub const DEFAULT_DOMAIN: &str = "bigbass1997.com";
+pub const PORT: u16 = 6400;
+/// Longest the server is waited on for each step of setting up the connection, such as authenticating.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
 
 
 pub struct SocketManager {
     pub socket: Client,
 }
 impl SocketManager {
-    pub fn init(domain: Option<&str>, _features: Vec<Feature>, endpoint: Endpoint) {
-        let socket = Client::new(&format!("{}:6400", domain.unwrap_or("bigbass1997.com")));
+    /// Connects to the server at `addr`, over TLS if given, checks that it speaks the same version of the
+    /// protocol, and authenticates with the user and secret in `credentials`, if given. Returns the fingerprint
+    /// of the server's certificate, over TLS.
+    pub fn init(addr: &str, tls: Option<&TlsClient>, credentials: Option<(&str, &str)>, _features: Vec<Feature>, endpoint: Endpoint) -> Result<Option<String>, network::Error> {
+        let socket = Client::new(addr, Limits::default(), tls)?;
+        let fingerprint = socket.fingerprint.clone();
+        check_version(&socket)?;
+        if let Some((user, secret)) = credentials {
+            authenticate(&socket, user, secret)?;
+            info!("Authenticated as {}.", user);
+        }
         
         let sm = SocketManager {
             socket,
         };
         
         std::thread::Builder::new().name("SocketManager".to_owned()).spawn(move || {
-            'running: loop {
-                // Handle any inbound messages from server
-                while let Ok(msg) = sm.socket.recv.try_recv() {
-                    match Packet::deserialize(&msg) {
-                        Ok(packet) => match packet {
-                            Packet::Ping => {
-                                debug!("Ping! {}", sm.socket.peer);
-                                sm.socket.send.try_send(Packet::Pong.serialize()).unwrap();
-                            },
-                            Packet::FrameResponse(frames) => {
-                                endpoint.send.try_send(InterMessage::BulkFrames(frames)).unwrap_or_default();
+            loop {
+                select! {
+                    // Handle any inbound messages from server
+                    recv(sm.socket.recv) -> event => match event {
+                        Ok(Event::Message(msg)) => match Packet::deserialize(&msg) {
+                            Ok(packet) => match packet {
+                                Packet::Ping => {
+                                    debug!("Ping! {}", sm.socket.peer);
+                                    sm.socket.send.try_send(Packet::Pong.serialize()).unwrap_or_default();
+                                },
+                                Packet::FrameResponse(frames) => {
+                                    endpoint.send.try_send(InterMessage::BulkFrames(frames)).unwrap_or_default();
+                                },
+                                packet => {
+                                    endpoint.send.try_send(InterMessage::ReceivedPacket(packet)).unwrap_or_default();
+                                }
                             },
                             _ => ()
                         },
-                        _ => ()
-                    }
-                }
-                
-                loop {
-                    match endpoint.recv.try_recv() {
-                        Ok(msg) => {
-                            match msg {
-                                InterMessage::SocketPacket(packet) => {
-                                    sm.socket.send.try_send(packet.serialize()).unwrap();
-                                }
-                                InterMessage::Kill => {
-                                    break 'running;
-                                },
-                                _ => ()
+                        // The rest of the client treats a lost connection as the server having closed it
+                        Ok(Event::Disconnected(err)) => {
+                            match err {
+                                Some(err) => warn!("Lost the connection to {}: {}", sm.socket.peer, err),
+                                None => warn!("{} closed the connection.", sm.socket.peer),
                             }
+                            endpoint.send.try_send(InterMessage::ReceivedPacket(Packet::Close)).unwrap_or_default();
+                            return;
+                        },
+                        Err(_) => return,
+                    },
+                    recv(endpoint.recv) -> msg => match msg {
+                        Ok(InterMessage::SocketPacket(packet)) => {
+                            sm.socket.send.try_send(packet.serialize()).unwrap_or_default();
                         },
-                        Err(_) => break
-                    }
+                        Ok(InterMessage::Kill) | Err(_) => break,
+                        Ok(_) => ()
+                    },
                 }
-                
-                std::thread::sleep(Duration::from_nanos(1));
             }
             
             sm.socket.send.send_timeout(Packet::Close.serialize(), Duration::from_secs(1)).unwrap_or_default();
         }).unwrap();
+        
+        Ok(fingerprint)
+    }
+}
+
+
+/// Asks for the server's info, failing unless it speaks the same version of the protocol as the client.
+fn check_version(socket: &Client) -> Result<(), network::Error> {
+    socket.send.try_send(Packet::InfoRequest.serialize()).unwrap_or_default();
+    match next_packet(socket).map_err(network::Error::Incompatible)? {
+        Packet::InfoResponse(info) if info.header == INFO_HEADER => Ok(()),
+        packet => Err(network::Error::Incompatible(format!("expected the server's info, got packet {:#04x}", packet.id()))),
+    }
+}
+
+/// Proves to the server that the client knows the user's secret, without sending the secret itself.
+fn authenticate(socket: &Client, user: &str, secret: &str) -> Result<(), network::Error> {
+    socket.send.try_send(Packet::AuthRequest(user.to_owned()).serialize()).unwrap_or_default();
+    let challenge = match next_packet(socket).map_err(network::Error::Auth)? {
+        Packet::AuthChallenge(challenge) => challenge,
+        Packet::RequestDenied => return Err(network::Error::Auth("the server refused to authenticate the client".to_owned())),
+        packet => return Err(network::Error::Auth(format!("expected a challenge, got packet {:#04x}", packet.id()))),
+    };
+    let proof = challenge.answer(user, secret).ok_or_else(|| {
+        network::Error::Auth(format!("the server's challenge takes {} iterations, outside of the 1 to {} allowed", challenge.iterations, auth::MAX_ITERATIONS))
+    })?;
+    socket.send.try_send(Packet::AuthResponse(proof).serialize()).unwrap_or_default();
+    match next_packet(socket).map_err(network::Error::Auth)? {
+        Packet::AuthAccepted => Ok(()),
+        Packet::RequestDenied => Err(network::Error::Auth(format!("wrong user or secret for {}", user))),
+        packet => Err(network::Error::Auth(format!("expected to be accepted, got packet {:#04x}", packet.id()))),
     }
-}
\ No newline at end of file
+}
+
+/// Waits for the server's next packet while setting up the connection, answering any pings meanwhile.
+/// Describes why, if none came.
+fn next_packet(socket: &Client) -> Result<Packet, String> {
+    let started = Instant::now();
+    loop {
+        let remaining = SETUP_TIMEOUT.saturating_sub(started.elapsed());
+        let msg = match socket.recv.recv_timeout(remaining) {
+            Ok(Event::Message(msg)) => msg,
+            Ok(Event::Disconnected(_)) | Err(RecvTimeoutError::Disconnected) => return Err("the server closed the connection".to_owned()),
+            Err(RecvTimeoutError::Timeout) => return Err("the server didn't answer in time".to_owned()),
+        };
+        
+        match Packet::deserialize(&msg) {
+            Ok(Packet::Ping) => socket.send.try_send(Packet::Pong.serialize()).unwrap_or_default(),
+            Ok(packet) => return Ok(packet),
+            Err(PacketError::UnsupportedVersion(version)) => return Err(format!("it speaks protocol version {}, while this client speaks {}", version, INFO_VERSION)),
+            Err(err) => warn!("Malformed packet: {:?}", err),
+        }
+    }
+}
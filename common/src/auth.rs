@@ -0,0 +1,254 @@
+use std::fmt;
+use std::str::FromStr;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// PBKDF2 rounds used when hashing new secrets. Makes guessing a secret from its hash slow.
+pub const ITERATIONS: u32 = 100_000;
+/// Most PBKDF2 rounds a client goes through to answer a challenge, so a server can't keep it busy indefinitely.
+pub const MAX_ITERATIONS: u32 = 10_000_000;
+pub const SALT_LEN: usize = 16;
+pub const NONCE_LEN: usize = 32;
+pub const KEY_LEN: usize = 32;
+
+
+/// Sent by the server in answer to `Packet::AuthRequest`. The client proves it knows the user's secret by
+/// deriving its key with the salt and iterations, and answering with `proof()` of the nonce.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Challenge {
+    /// Random for every challenge, so a proof can't be replayed.
+    pub nonce: [u8; NONCE_LEN],
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+}
+impl Challenge {
+    /// Challenges a client authenticating as `user`, whose secret is `secret` if it exists and may authenticate.
+    /// Otherwise, the challenge looks the same as a real user's, with a salt made up from `decoy_key` (see `decoy_salt()`).
+    pub fn new(user: &str, secret: Option<&StoredSecret>, nonce: [u8; NONCE_LEN], decoy_key: &[u8]) -> Self {
+        match secret {
+            Some(secret) => Self {
+                nonce,
+                salt: secret.salt.clone(),
+                iterations: secret.iterations,
+            },
+            None => Self {
+                nonce,
+                salt: decoy_salt(decoy_key, user),
+                iterations: ITERATIONS,
+            },
+        }
+    }
+    
+    /// Answers the challenge as `user`, with its secret. Gives nothing if the challenge takes more than
+    /// `MAX_ITERATIONS` (or no) rounds to answer.
+    pub fn answer(&self, user: &str, secret: &str) -> Option<[u8; KEY_LEN]> {
+        if !(1..=MAX_ITERATIONS).contains(&self.iterations) { return None }
+        
+        Some(proof(&derive_key(secret, &self.salt, self.iterations), user, &self.nonce))
+    }
+}
+
+/// A user's secret (a password or token), as stored by the server: a hash of the key derived from it.
+/// 
+/// Written as `ITERATIONS:SALT:KEY`, with the salt and the hashed key in hex. The server can check a
+/// client's proof of the key against the hash, but neither the key nor the secret can be recovered from
+/// it, so it can't be used to authenticate as the user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredSecret {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    /// `stored_key()` of the key derived from the secret.
+    pub key: [u8; KEY_LEN],
+}
+impl StoredSecret {
+    pub fn new(secret: &str, salt: [u8; SALT_LEN]) -> Self { Self {
+        iterations: ITERATIONS,
+        salt: salt.to_vec(),
+        key: stored_key(&derive_key(secret, &salt, ITERATIONS)),
+    }}
+    
+    /// Checks a client's answer to a challenge, without giving away through timing how much of it was right.
+    pub fn verify(&self, user: &str, nonce: &[u8; NONCE_LEN], answer: &[u8]) -> bool {
+        if answer.len() != KEY_LEN { return false }
+        
+        // The answer is the client's key, masked with a signature only the key's hash is needed for
+        let mut key = signature(&self.key, user, nonce);
+        key.iter_mut().zip(answer).for_each(|(byte, masked)| *byte ^= masked);
+        
+        stored_key(&key).iter().zip(&self.key).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+impl fmt::Display for StoredSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.iterations, to_hex(&self.salt), to_hex(&self.key))
+    }
+}
+impl FromStr for StoredSecret {
+    type Err = ();
+    
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut parts = text.trim().split(':');
+        let iterations = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let salt = from_hex(parts.next().ok_or(())?).ok_or(())?;
+        let key = from_hex(parts.next().ok_or(())?).ok_or(())?.try_into().map_err(|_| ())?;
+        // Clients refuse challenges outside of this range, so such a user could never authenticate
+        if parts.next().is_some() || !(1..=MAX_ITERATIONS).contains(&iterations) || salt.is_empty() || salt.len() > u8::MAX as usize {
+            return Err(());
+        }
+        
+        Ok(Self {
+            iterations,
+            salt,
+            key,
+        })
+    }
+}
+
+/// Derives the key a secret is proven with, which only the client knows.
+pub fn derive_key(secret: &str, salt: &[u8], iterations: u32) -> [u8; KEY_LEN] {
+    let salted = pbkdf2::pbkdf2_hmac_array::<Sha256, KEY_LEN>(secret.as_bytes(), salt, iterations);
+    
+    let mut mac = Hmac::<Sha256>::new_from_slice(&salted).expect("HMAC takes keys of any length");
+    mac.update(b"Client Key");
+    mac.finalize().into_bytes().into()
+}
+
+/// Hash of a key derived from a secret, which the server stores instead of the key itself.
+pub fn stored_key(key: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    Sha256::digest(key).into()
+}
+
+/// The answer to a challenge, proving knowledge of the key without revealing it: the key, masked with a
+/// signature of the nonce made with the key's hash.
+pub fn proof(key: &[u8; KEY_LEN], user: &str, nonce: &[u8; NONCE_LEN]) -> [u8; KEY_LEN] {
+    let mut proof = signature(&stored_key(key), user, nonce);
+    proof.iter_mut().zip(key).for_each(|(byte, key)| *byte ^= key);
+    
+    proof
+}
+
+/// A made up salt for a user that doesn't exist or isn't allowed, so its challenge looks like a real user's,
+/// without giving away which users exist. The same `key` and user always give the same salt.
+pub fn decoy_salt(key: &[u8], user: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(user.as_bytes());
+    
+    mac.finalize().into_bytes()[..SALT_LEN].to_vec()
+}
+
+fn signature(stored_key: &[u8; KEY_LEN], user: &str, nonce: &[u8; NONCE_LEN]) -> [u8; KEY_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(stored_key).expect("HMAC takes keys of any length");
+    mac.update(nonce);
+    mac.update(user.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() { return None }
+    
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..(i + 2)], 16).ok()).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Few rounds, so the tests run quickly.
+    const TEST_ITERATIONS: u32 = 16;
+    
+    fn stored(secret: &str) -> StoredSecret {
+        let salt = [7; SALT_LEN];
+        StoredSecret {
+            iterations: TEST_ITERATIONS,
+            salt: salt.to_vec(),
+            key: stored_key(&derive_key(secret, &salt, TEST_ITERATIONS)),
+        }
+    }
+    
+    #[test]
+    fn proof_of_the_right_secret_is_verified() {
+        let secret = stored("hunter2");
+        let nonce = [3; NONCE_LEN];
+        let key = derive_key("hunter2", &secret.salt, secret.iterations);
+        
+        assert!(secret.verify("alice", &nonce, &proof(&key, "alice", &nonce)));
+    }
+    
+    #[test]
+    fn challenge_answered_with_the_right_secret_is_verified() {
+        let secret = stored("hunter2");
+        let challenge = Challenge::new("alice", Some(&secret), [3; NONCE_LEN], b"decoy");
+        let answer = challenge.answer("alice", "hunter2").unwrap();
+        
+        assert!(secret.verify("alice", &challenge.nonce, &answer));
+    }
+    
+    #[test]
+    fn wrong_secret_is_rejected() {
+        let secret = stored("hunter2");
+        let nonce = [3; NONCE_LEN];
+        let key = derive_key("hunter3", &secret.salt, secret.iterations);
+        
+        assert!(!secret.verify("alice", &nonce, &proof(&key, "alice", &nonce)));
+    }
+    
+    #[test]
+    fn proof_is_bound_to_the_user_and_nonce() {
+        let secret = stored("hunter2");
+        let key = derive_key("hunter2", &secret.salt, secret.iterations);
+        let answer = proof(&key, "alice", &[3; NONCE_LEN]);
+        
+        assert!(!secret.verify("bob", &[3; NONCE_LEN], &answer));
+        assert!(!secret.verify("alice", &[4; NONCE_LEN], &answer));
+        assert!(!secret.verify("alice", &[3; NONCE_LEN], &answer[1..]));
+    }
+    
+    #[test]
+    fn stored_key_does_not_authenticate() {
+        let secret = stored("hunter2");
+        let nonce = [3; NONCE_LEN];
+        
+        assert!(!secret.verify("alice", &nonce, &proof(&secret.key, "alice", &nonce)));
+        assert!(!secret.verify("alice", &nonce, &secret.key));
+    }
+    
+    #[test]
+    fn unknown_user_gets_a_decoy_salt() {
+        let challenge = Challenge::new("mallory", None, [3; NONCE_LEN], b"decoy");
+        
+        assert_eq!(challenge.salt, decoy_salt(b"decoy", "mallory"));
+        assert_eq!(challenge.salt.len(), SALT_LEN);
+        assert_eq!(challenge.iterations, ITERATIONS);
+        assert_eq!(Challenge::new("mallory", None, [4; NONCE_LEN], b"decoy").salt, challenge.salt);
+        assert_ne!(decoy_salt(b"decoy", "eve"), challenge.salt);
+        assert_ne!(decoy_salt(b"other", "mallory"), challenge.salt);
+    }
+    
+    #[test]
+    fn out_of_range_iterations_are_rejected() {
+        let mut challenge = Challenge::new("alice", Some(&stored("hunter2")), [3; NONCE_LEN], b"decoy");
+        challenge.iterations = MAX_ITERATIONS + 1;
+        assert_eq!(challenge.answer("alice", "hunter2"), None);
+        challenge.iterations = 0;
+        assert_eq!(challenge.answer("alice", "hunter2"), None);
+        
+        let key = "00".repeat(KEY_LEN);
+        assert!(format!("{}:00:{}", MAX_ITERATIONS + 1, key).parse::<StoredSecret>().is_err());
+        assert!(format!("0:00:{}", key).parse::<StoredSecret>().is_err());
+        assert!(format!("{}:00:{}", MAX_ITERATIONS, key).parse::<StoredSecret>().is_ok());
+    }
+    
+    #[test]
+    fn stored_secret_round_trips_as_text() {
+        let secret = stored("hunter2");
+        
+        assert_eq!(secret.to_string().parse::<StoredSecret>(), Ok(secret));
+        assert!("16:zz:00".parse::<StoredSecret>().is_err());
+        assert!("16:00".parse::<StoredSecret>().is_err());
+    }
+}
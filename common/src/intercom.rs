@@ -1,6 +1,7 @@
 use std::time::Duration;
 use crossbeam_channel::{bounded, Receiver, Sender, unbounded};
-use crate::{Frame, Packet};
+use crate::{Component, Frame, Packet, RecordingInfo};
+use crate::rom::RomIdentity;
 
 /// A channel for sending and receiving messages with another BidirectionalChannel.
 /// 
@@ -120,12 +121,46 @@ impl<T: Clone> BroadcastNetwork<T> {
 #[derive(Clone, Debug)]
 pub enum InterMessage {
     SocketPacket(Packet),
+    ReceivedPacket(Packet),
     LatestFrame(Frame),
     BulkFrames(Vec<Frame>),
-    StartRecording,
-    StopRecording,
+    /// Starts recording a new session, given its ID and the client's address.
+    StartRecording(u64, String),
+    /// Something notable happened during the session being recorded.
+    SessionEvent(String),
+    /// The ROM that was uploaded for the session being recorded.
+    SessionRom(RomIdentity),
+    /// Ends the recording. If a session ID is given, the recording is kept for that session's client to download.
+    StopRecording(Option<u64>),
+    /// A stopped recording has been saved, and is ready to be downloaded.
+    RecordingReady(RecordingInfo),
+    /// The state of the client queue, published regularly.
+    QueueStatus(QueueStatus),
+    /// Ends the active client's session, as if it had ended the session itself, for the given reason.
+    EndSession(String),
+    /// Stops (or resumes) servicing the next client in the queue, once the active one is done.
+    PauseQueue(bool),
+    /// A component of the server failed (true), or recovered (false).
+    Degraded(Component, bool),
     
     Kill,
 }
 
-pub type Endpoint = BidirectionalChannel<InterMessage, InterMessage>;
\ No newline at end of file
+pub type Endpoint = BidirectionalChannel<InterMessage, InterMessage>;
+
+/// Snapshot of the server's client queue.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueStatus {
+    /// Address of the client being serviced.
+    pub active: Option<String>,
+    /// How long the active client has been serviced for.
+    pub session_elapsed: Duration,
+    /// How long a client may be serviced for in total, if limited.
+    pub session_limit: Option<Duration>,
+    /// Clients waiting behind the active one.
+    pub waiting: usize,
+    /// True while no further clients are being serviced.
+    pub paused: bool,
+    /// Bytes sent to clients per second, recently.
+    pub upload_rate: f64,
+}
\ No newline at end of file
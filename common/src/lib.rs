@@ -1,20 +1,31 @@
 use log::warn;
 use strum_macros::EnumString;
 use num_enum::{FromPrimitive, IntoPrimitive};
+use auth::Challenge;
 
+pub mod auth;
 pub mod network;
 pub mod intercom;
 pub mod logger;
+pub mod rom;
 pub mod util;
 
 
 pub const API_INFO: [u8; 6] = [b'R', b'M', b'6', b'4', 0x00, 0x00];
+pub const INFO_HEADER: [u8; 4] = [0x52, 0x4D, 0x36, 0x34]; // RM64
+/// Version of the protocol, sent in `ServerInfo`. Changes whenever a packet's layout does, since peers
+/// speaking different versions would misread each other.
+pub const INFO_VERSION: u16 = 0x0002;
 
 
 #[derive(Debug, PartialEq)]
 pub enum PacketError {
     Empty,
-    UnexpectedLength
+    UnexpectedLength,
+    /// The payload couldn't be decoded, e.g. compressed video that doesn't decompress.
+    Corrupt,
+    /// The server's info is for a different version of the protocol than `INFO_VERSION`, whose packets can't be read.
+    UnsupportedVersion(u16),
 }
 use PacketError::*;
 use crate::Packet::Unknown;
@@ -27,6 +38,17 @@ pub const ID_QUEUE_REQ: u8 = 0x05;
 pub const ID_QUEUE_RES: u8 = 0x06;
 pub const ID_FRAME_REQ: u8 = 0x07;
 pub const ID_FRAME_RES: u8 = 0x08;
+pub const ID_ROM_UPLOAD: u8 = 0x09;
+pub const ID_ROM_LOADED: u8 = 0x0A;
+pub const ID_SESSION_END: u8 = 0x0B;
+pub const ID_REC_READY: u8 = 0x0C;
+pub const ID_REC_REQ: u8 = 0x0D;
+pub const ID_REC_DATA: u8 = 0x0E;
+pub const ID_REC_RECEIVED: u8 = 0x0F;
+pub const ID_AUTH_REQ: u8 = 0x10;
+pub const ID_AUTH_CHALLENGE: u8 = 0x11;
+pub const ID_AUTH_RES: u8 = 0x12;
+pub const ID_AUTH_ACCEPTED: u8 = 0x13;
 pub const ID_REQ_DENIED: u8 = 0xFD;
 pub const ID_CLOSE: u8 = 0xFE;
 pub const ID_UNKNOWN: u8 = 0xFF;
@@ -48,11 +70,36 @@ impl Default for Feature {
     }
 }
 
+/// Parts of the server's hardware that can fail while it runs.
+#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum Component {
+    VideoCapture = 0x01,
+    AudioCapture = 0x02,
+    ControllerBoard = 0x03,
+    
+    #[num_enum(default)]
+    Invalid = 0x00,
+}
+impl std::fmt::Display for Component {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Component::VideoCapture => "video capture",
+            Component::AudioCapture => "audio capture",
+            Component::ControllerBoard => "controller board",
+            Component::Invalid => "unknown component",
+        })
+    }
+}
+
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct ServerInfo {
     pub header: [u8; 4],
     pub version: u16,
+    /// Components that have failed and are being recovered. While any have, the server is degraded, and
+    /// its queue doesn't move.
+    pub degraded: Vec<Component>,
     pub features: Vec<Feature>,
 }
 impl ServerInfo {
@@ -60,6 +107,10 @@ impl ServerInfo {
         let mut raw = vec![];
         raw.extend_from_slice(&self.header);
         raw.extend_from_slice(&self.version.to_be_bytes());
+        raw.push(self.degraded.len() as u8);
+        for component in &self.degraded {
+            raw.push((*component).into());
+        }
         for feat in &self.features {
             raw.push((*feat).into());
         }
@@ -70,17 +121,28 @@ impl ServerInfo {
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Frame {
+    /// Incremented by the server for every captured frame. Gaps mean frames were dropped before being sent.
+    pub sequence: u32,
+    /// Size of the video in pixels, which is packed RGB.
+    pub width: u16,
+    pub height: u16,
     pub video: Vec<u8>,
     pub audio: Vec<f32>,
 }
 impl Frame {
-    pub fn new(uncompressed_video: Vec<u8>, audio: Vec<f32>) -> Self { Self {
+    pub fn new(sequence: u32, width: u16, height: u16, uncompressed_video: Vec<u8>, audio: Vec<f32>) -> Self { Self {
+        sequence,
+        width,
+        height,
         video: uncompressed_video,
         audio,
     }}
     
     pub fn serialize(&self) -> Vec<u8> {
         let mut raw = vec![];
+        raw.extend_from_slice(&self.sequence.to_be_bytes());
+        raw.extend_from_slice(&self.width.to_be_bytes());
+        raw.extend_from_slice(&self.height.to_be_bytes());
         
         let video = match zstd::encode_all(self.video.as_slice(), 3) {
             Ok(video) => video,
@@ -99,20 +161,158 @@ impl Frame {
         raw
     }
     
-    pub fn deserialize(data: Vec<u8>) -> Frame {
-        if data.len() <= 4 {
-            return Frame::default();
-        }
+    pub fn deserialize(data: &[u8]) -> Result<Frame, PacketError> {
+        let mut reader = PayloadReader::new(data);
+        let sequence = reader.u32()?;
+        let width = reader.u16()?;
+        let height = reader.u16()?;
+        let video_len = reader.u32()? as usize;
+        let video = zstd::decode_all(reader.bytes(video_len)?).map_err(|_| Corrupt)?;
+        
+        let audio = reader.rest().chunks_exact(4)
+            .map(|sample| f32::from_be_bytes([sample[0], sample[1], sample[2], sample[3]]))
+            .collect();
         
-        let video_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
-        let video = zstd::decode_all(&data[4..(video_len + 4)]).unwrap();
+        Ok(Frame::new(sequence, width, height, video, audio))
+    }
+}
+
+/// A file saved by the server while recording a session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingFile {
+    pub name: String,
+    pub size: u64,
+    /// SHA-256 of the file's contents, as lowercase hex.
+    pub sha256: String,
+}
+
+/// Describes a finished session recording that is waiting to be downloaded.
+/// 
+/// The session ID is only ever sent to the client the session belonged to, and is required to
+/// request the recording's files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingInfo {
+    pub session: u64,
+    pub files: Vec<RecordingFile>,
+}
+impl RecordingInfo {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut raw = vec![];
+        raw.extend_from_slice(&self.session.to_be_bytes());
+        raw.push(self.files.len() as u8);
+        for file in &self.files {
+            write_str(&mut raw, &file.name);
+            raw.extend_from_slice(&file.size.to_be_bytes());
+            write_str(&mut raw, &file.sha256);
+        }
         
-        let mut audio = vec![];
-        for i in ((video_len + 4)..data.len()).step_by(4) {
-            audio.push(f32::from_be_bytes([data[i + 0], data[i + 1], data[i + 2], data[i + 3]]));
+        raw
+    }
+    
+    fn deserialize(reader: &mut PayloadReader) -> Result<Self, PacketError> {
+        let session = reader.u64()?;
+        let count = reader.u8()?;
+        let mut files = vec![];
+        for _ in 0..count {
+            files.push(RecordingFile {
+                name: reader.string()?,
+                size: reader.u64()?,
+                sha256: reader.string()?,
+            });
         }
         
-        Frame::new(video, audio)
+        Ok(Self {
+            session,
+            files,
+        })
+    }
+}
+
+/// Part of a recording file, starting at `offset` bytes into the file at index `file` of the `RecordingInfo`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingChunk {
+    pub session: u64,
+    pub file: u8,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+impl RecordingChunk {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut raw = vec![];
+        raw.extend_from_slice(&self.session.to_be_bytes());
+        raw.push(self.file);
+        raw.extend_from_slice(&self.offset.to_be_bytes());
+        raw.extend_from_slice(&self.data);
+        
+        raw
+    }
+    
+    fn deserialize(reader: &mut PayloadReader) -> Result<Self, PacketError> {
+        Ok(Self {
+            session: reader.u64()?,
+            file: reader.u8()?,
+            offset: reader.u64()?,
+            data: reader.rest().to_vec(),
+        })
+    }
+}
+
+fn write_str(raw: &mut Vec<u8>, text: &str) {
+    let bytes = &text.as_bytes()[..text.len().min(u8::MAX as usize)];
+    raw.push(bytes.len() as u8);
+    raw.extend_from_slice(bytes);
+}
+
+/// Reads values from a packet's payload, failing with `UnexpectedLength` if it runs out of data.
+struct PayloadReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> PayloadReader<'a> {
+    fn new(data: &'a [u8]) -> Self { Self {
+        data,
+        pos: 0,
+    }}
+    
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PacketError> {
+        let bytes = self.data.get(self.pos..(self.pos + len)).ok_or(UnexpectedLength)?;
+        self.pos += len;
+        
+        Ok(bytes)
+    }
+    
+    fn u8(&mut self) -> Result<u8, PacketError> {
+        Ok(self.bytes(1)?[0])
+    }
+    
+    fn u16(&mut self) -> Result<u16, PacketError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    
+    fn u32(&mut self) -> Result<u32, PacketError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    
+    fn u64(&mut self) -> Result<u64, PacketError> {
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(self.bytes(8)?);
+        
+        Ok(u64::from_be_bytes(raw))
+    }
+    
+    fn string(&mut self) -> Result<String, PacketError> {
+        let len = self.u8()? as usize;
+        
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+    
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        
+        rest
     }
 }
 
@@ -126,6 +326,22 @@ pub enum Packet {
     QueueResponse(u32),
     FrameRequest(u32), //TODO: Add image formatting and options to request (allow client to specify lower resolutions or lossy quality)
     FrameResponse(Vec<Frame>), //TODO: Add image datastructure to convey format of image (necessary once resolution/lossy options are implemented)
+    RomUpload(Vec<u8>),
+    RomLoaded,
+    /// Sent by the active client when it's done, to end its session without disconnecting.
+    SessionEnd,
+    RecordingReady(RecordingInfo),
+    RecordingRequest(u64),
+    RecordingData(RecordingChunk),
+    /// Sent once every file of a recording was received, letting the server delete its copy.
+    RecordingReceived(u64),
+    /// Sent by a client to authenticate as the named user, which the server answers with a challenge.
+    AuthRequest(String),
+    AuthChallenge(Challenge),
+    /// The client's proof of the user's secret, answering the challenge (see `auth::proof()`). The server
+    /// answers with `AuthAccepted`, or `RequestDenied` before disconnecting.
+    AuthResponse([u8; auth::KEY_LEN]),
+    AuthAccepted,
     RequestDenied,
     Close,
     Unknown(Vec<u8>),
@@ -141,17 +357,19 @@ impl Packet {
             ID_INFO_REQ => Ok(InfoRequest),
             ID_INFO_RES => {
                 if data.len() < 7 { return Err(UnexpectedLength) }
+                let version = u16::from_be_bytes([data[5], data[6]]);
+                if version != INFO_VERSION { return Err(UnsupportedVersion(version)) }
+                if data.len() < 8 { return Err(UnexpectedLength) }
                 
-                let mut features = vec![];
-                if data.len() > 7 {
-                    for i in 7..data.len() {
-                        features.push(Feature::from(data[i]));
-                    }
-                }
+                let degraded_end = 8 + data[7] as usize;
+                if data.len() < degraded_end { return Err(UnexpectedLength) }
+                let degraded = data[8..degraded_end].iter().map(|id| Component::from(*id)).collect();
+                let features = data[degraded_end..].iter().map(|id| Feature::from(*id)).collect();
                 
                 Ok(InfoResponse(ServerInfo {
                     header: [data[1], data[2], data[3], data[4]],
-                    version: u16::from_be_bytes([data[5], data[6]]),
+                    version,
+                    degraded,
                     features,
                 }))
             },
@@ -167,21 +385,48 @@ impl Packet {
                 Ok(FrameRequest(u32::from_be_bytes([data[1], data[2], data[3], data[4]])))
             },
             ID_FRAME_RES => {
-                if data.len() < 5 { return Err(UnexpectedLength) }
-                
-                let count = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+                let mut reader = PayloadReader::new(&data[1..]);
+                let count = reader.u32()?;
                 let mut frames = vec![];
-                let mut i = 5;
                 for _ in 0..count {
-                    let frame_len = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
-                    i += 4;
-                    
-                    frames.push(Frame::deserialize(data[i..(i + frame_len)].to_vec()));
-                    i += frame_len;
+                    let frame_len = reader.u32()? as usize;
+                    frames.push(Frame::deserialize(reader.bytes(frame_len)?)?);
                 }
                 
                 Ok(FrameResponse(frames))
             },
+            ID_ROM_UPLOAD => {
+                if data.len() < 2 { return Err(UnexpectedLength) }
+                
+                Ok(RomUpload(data[1..].to_vec()))
+            },
+            ID_ROM_LOADED => Ok(RomLoaded),
+            ID_SESSION_END => Ok(SessionEnd),
+            ID_REC_READY => Ok(RecordingReady(RecordingInfo::deserialize(&mut PayloadReader::new(&data[1..]))?)),
+            ID_REC_REQ => Ok(RecordingRequest(PayloadReader::new(&data[1..]).u64()?)),
+            ID_REC_DATA => Ok(RecordingData(RecordingChunk::deserialize(&mut PayloadReader::new(&data[1..]))?)),
+            ID_REC_RECEIVED => Ok(RecordingReceived(PayloadReader::new(&data[1..]).u64()?)),
+            ID_AUTH_REQ => Ok(AuthRequest(PayloadReader::new(&data[1..]).string()?)),
+            ID_AUTH_CHALLENGE => {
+                let mut reader = PayloadReader::new(&data[1..]);
+                let mut nonce = [0; auth::NONCE_LEN];
+                nonce.copy_from_slice(reader.bytes(auth::NONCE_LEN)?);
+                let iterations = reader.u32()?;
+                let salt_len = reader.u8()? as usize;
+                
+                Ok(AuthChallenge(Challenge {
+                    nonce,
+                    salt: reader.bytes(salt_len)?.to_vec(),
+                    iterations,
+                }))
+            },
+            ID_AUTH_RES => {
+                let mut proof = [0; auth::KEY_LEN];
+                proof.copy_from_slice(PayloadReader::new(&data[1..]).bytes(auth::KEY_LEN)?);
+                
+                Ok(AuthResponse(proof))
+            },
+            ID_AUTH_ACCEPTED => Ok(AuthAccepted),
             
             ID_REQ_DENIED => Ok(RequestDenied),
             ID_CLOSE => Ok(Close),
@@ -199,6 +444,17 @@ impl Packet {
             QueueResponse(_) => ID_QUEUE_RES,
             FrameRequest(_) => ID_FRAME_REQ,
             FrameResponse(_) => ID_FRAME_RES,
+            RomUpload(_) => ID_ROM_UPLOAD,
+            RomLoaded => ID_ROM_LOADED,
+            SessionEnd => ID_SESSION_END,
+            RecordingReady(_) => ID_REC_READY,
+            RecordingRequest(_) => ID_REC_REQ,
+            RecordingData(_) => ID_REC_DATA,
+            RecordingReceived(_) => ID_REC_RECEIVED,
+            AuthRequest(_) => ID_AUTH_REQ,
+            AuthChallenge(_) => ID_AUTH_CHALLENGE,
+            AuthResponse(_) => ID_AUTH_RES,
+            AuthAccepted => ID_AUTH_ACCEPTED,
             
             RequestDenied => ID_REQ_DENIED,
             Close => ID_CLOSE,
@@ -227,6 +483,22 @@ impl Packet {
                     raw.extend_from_slice(&serialized);
                 }
             },
+            RomUpload(rom) => raw.extend_from_slice(rom),
+            RomLoaded => (),
+            SessionEnd => (),
+            RecordingReady(info) => raw.extend_from_slice(&info.serialize()),
+            RecordingRequest(session) => raw.extend_from_slice(&session.to_be_bytes()),
+            RecordingData(chunk) => raw.extend_from_slice(&chunk.serialize()),
+            RecordingReceived(session) => raw.extend_from_slice(&session.to_be_bytes()),
+            AuthRequest(user) => write_str(&mut raw, user),
+            AuthChallenge(challenge) => {
+                raw.extend_from_slice(&challenge.nonce);
+                raw.extend_from_slice(&challenge.iterations.to_be_bytes());
+                raw.push(challenge.salt.len() as u8);
+                raw.extend_from_slice(&challenge.salt);
+            },
+            AuthResponse(proof) => raw.extend_from_slice(proof),
+            AuthAccepted => (),
             
             RequestDenied => (),
             Close => (),
@@ -236,3 +508,32 @@ impl Packet {
         raw
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn info(version: u16) -> ServerInfo {
+        ServerInfo {
+            header: INFO_HEADER,
+            version,
+            degraded: vec![Component::AudioCapture],
+            features: vec![Feature::LivePlayback],
+        }
+    }
+    
+    #[test]
+    fn info_of_the_current_version_round_trips() {
+        let raw = InfoResponse(info(INFO_VERSION)).serialize();
+        
+        assert_eq!(Packet::deserialize(&raw), Ok(InfoResponse(info(INFO_VERSION))));
+    }
+    
+    #[test]
+    fn info_of_another_version_is_rejected() {
+        let raw = InfoResponse(info(INFO_VERSION - 1)).serialize();
+        
+        assert_eq!(Packet::deserialize(&raw), Err(UnsupportedVersion(INFO_VERSION - 1)));
+    }
+}
@@ -1,53 +1,132 @@
-use std::cmp::min;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::ops::{Deref, DerefMut};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use crossbeam_channel::{Receiver, Sender};
-use crossbeam_queue::SegQueue;
-use log::trace;
-use crate::intercom::BidirectionalChannel;
-use crate::util::InfCell;
+use crossbeam_channel::{Receiver, Sender, bounded, never, select, unbounded};
+use log::{trace, warn};
+
+#[cfg(feature = "tokio")]
+pub mod framed;
+pub mod tls;
+
+use tls::{TlsClient, TlsServer};
 
 pub type Message = Vec<u8>;
 
+/// Messages smaller than this are gathered up with their lengths, and any queued behind them, before being written.
+const WRITE_BUFFER: usize = 64 * 1024;
+/// Messages that may wait in a connection's bulk queue.
+const BULK_QUEUE: usize = 8;
+/// Wait before accepting again, after accepting failed (e.g. because the process ran out of file descriptors).
+/// Doubles with every failure in a row, up to the maximum.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Unable to listen on the address.
+    Bind(io::Error),
+    /// Unable to connect to the server.
+    Connect(io::Error),
+    /// A connection was made, but couldn't be set up.
+    Setup(io::Error),
+    /// TLS couldn't be set up, e.g. because of an unreadable certificate.
+    Tls(String),
+    /// The TLS handshake failed, e.g. because the peer's certificate isn't trusted.
+    Handshake(io::Error),
+    /// The server didn't accept the client's credentials, or didn't answer as expected while authenticating.
+    Auth(String),
+    /// The server speaks a different version of the protocol, or doesn't seem to be a server at all.
+    Incompatible(String),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind(err) => write!(f, "unable to listen: {}", err),
+            Error::Connect(err) => write!(f, "unable to connect: {}", err),
+            Error::Setup(err) => write!(f, "unable to set up the connection: {}", err),
+            Error::Tls(err) => write!(f, "unable to set up TLS: {}", err),
+            Error::Handshake(err) => write!(f, "TLS handshake failed: {}", err),
+            Error::Auth(err) => write!(f, "authentication failed: {}", err),
+            Error::Incompatible(err) => write!(f, "incompatible server: {}", err),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// Accepts connections from clients, over TLS if given.
 pub struct Server {
-    new_connections: Arc<SegQueue<SocketConnection>>,
+    new_connections: Receiver<SocketConnection>,
 }
 impl Server {
-    pub fn new<A: ToSocketAddrs>(addr: A) -> Self {
-        let listener = TcpListener::bind(addr).unwrap();
-        listener.set_nonblocking(false).unwrap();
+    pub fn new<A: ToSocketAddrs>(addr: A, limits: Limits, tls: Option<TlsServer>) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr).map_err(Error::Bind)?;
+        listener.set_nonblocking(false).map_err(Error::Bind)?;
         
-        let new_connections = Arc::new(SegQueue::new());
-        let connections = new_connections.clone();
+        // Each connection is set up on its own thread, so a slow handshake doesn't hold up any others. A
+        //   connection that can't be set up is dropped, without affecting any others.
+        let (connections, new_connections) = unbounded();
         std::thread::spawn(move || {
+            let mut backoff = ACCEPT_BACKOFF;
             loop {
-                while let Ok((stream, _)) = listener.accept() {
-                    connections.push(SocketConnection::new(stream));
+                let (stream, peer) = match listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Failed to accept a connection, retrying in {:?}: {}", backoff, err);
+                        std::thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        continue;
+                    },
+                };
+                backoff = ACCEPT_BACKOFF;
+                
+                let connections = connections.clone();
+                let tls = tls.clone();
+                let spawned = std::thread::Builder::new().name(format!("SocketSetup {}", peer)).spawn(move || {
+                    let connection = match &tls {
+                        Some(tls) => tls.connection().and_then(|conn| SocketConnection::new(stream, limits, Some(conn))),
+                        None => SocketConnection::new(stream, limits, None),
+                    };
+                    match connection {
+                        Ok(connection) => connections.send(connection).unwrap_or_default(),
+                        Err(err) => warn!("Dropped a new connection from {}: {}", peer, err),
+                    }
+                });
+                if let Err(err) = spawned {
+                    warn!("Dropped a new connection from {}: {}", peer, err);
                 }
             }
         });
         
-        Self {
+        Ok(Self {
             new_connections,
-        }
+        })
     }
     
     pub fn accept(&self) -> Option<SocketConnection> {
-        self.new_connections.pop()
+        self.new_connections.try_recv().ok()
+    }
+    
+    /// Connections that are ready to be accepted, for waiting on alongside other channels (e.g. with `crossbeam_channel::Select`).
+    pub fn incoming(&self) -> &Receiver<SocketConnection> {
+        &self.new_connections
     }
 }
 
 
+/// A connection to a server, over TLS if given.
 pub struct Client(SocketConnection);
 impl Client {
-    pub fn new<A: ToSocketAddrs>(addr: A) -> Self {
-        let stream = TcpStream::connect(addr).unwrap();
-        let connection = SocketConnection::new(stream);
+    pub fn new<A: ToSocketAddrs>(addr: A, limits: Limits, tls: Option<&TlsClient>) -> Result<Self, Error> {
+        let stream = TcpStream::connect(addr).map_err(Error::Connect)?;
+        let connection = SocketConnection::new(stream, limits, tls.map(|tls| tls.connection()).transpose()?)?;
+        if let Some(tls) = tls {
+            tls.check(connection.fingerprint.as_deref())?;
+        }
         
-        Self(connection)
+        Ok(Self(connection))
     }
 }
 impl Deref for Client {
@@ -65,104 +144,174 @@ impl DerefMut for Client {
 
 
 
-#[derive(Default, Debug)]
-struct WorkingMessage {
-    msg_len: u32,
-    payload: Vec<u8>,
+/// Something that happened on a connection.
+#[derive(Debug)]
+pub enum Event {
+    /// A whole message was received.
+    Message(Message),
+    /// The connection was closed by the peer (`None`), or failed. Nothing else follows this event.
+    Disconnected(Option<io::Error>),
+}
+
+/// Limits on what a connection exchanges, and how long it may go quiet for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// Largest message accepted from the peer. A longer one disconnects the peer before any of it is read.
+    pub max_incoming: usize,
+    /// Largest message sent to the peer. Trying to send a longer one disconnects the peer instead.
+    pub max_outgoing: usize,
+    /// Longest the peer may go without sending anything, if limited.
+    pub read_timeout: Option<Duration>,
+    /// Longest a write may stall for, if limited.
+    pub write_timeout: Option<Duration>,
+}
+impl Default for Limits {
+    fn default() -> Self { Self {
+        max_incoming: 256 << 20,
+        max_outgoing: 256 << 20,
+        read_timeout: Some(Duration::from_secs(60)),
+        write_timeout: Some(Duration::from_secs(30)),
+    }}
 }
 
+/// A connection exchanging length-prefixed messages.
+/// 
+/// The stream is split into two blocking halves, each with a thread of its own. The writer sends
+/// queued messages in order, and the reader passes on each message once it has been received whole.
+/// Once the connection closes or fails, in either direction, both halves stop and `Event::Disconnected`
+/// is received, with the reason for any failure. Dropping every sender for the connection closes it.
+/// 
+/// Large transfers, such as recordings, go through `bulk` instead of `send`. It only holds a few messages,
+/// so a sender waits for the connection to catch up rather than queueing the whole transfer in memory.
+/// 
+/// Over TLS, the handshake is completed before the connection is returned.
 pub struct SocketConnection {
     pub send: Sender<Message>,
-    pub recv: Receiver<Message>,
+    pub bulk: Sender<Message>,
+    pub recv: Receiver<Event>,
     pub peer: SocketAddr,
+    /// SHA-256 fingerprint of the peer's TLS certificate, if it presented one (see `tls::fingerprint()`).
+    pub fingerprint: Option<String>,
 }
 impl SocketConnection {
-    pub fn new(stream: TcpStream) -> Self {
-        let (chan_pub, chan_owned) = BidirectionalChannel::<Message, Message>::new(None);
+    pub fn new(mut stream: TcpStream, limits: Limits, tls: Option<rustls::Connection>) -> Result<Self, Error> {
+        stream.set_nodelay(true).map_err(Error::Setup)?;
+        stream.set_read_timeout(limits.read_timeout).map_err(Error::Setup)?;
+        stream.set_write_timeout(limits.write_timeout).map_err(Error::Setup)?;
+        let peer = stream.peer_addr().map_err(Error::Setup)?;
         
-        stream.set_nonblocking(true).unwrap();
-        stream.set_nodelay(true).unwrap();
-        let peer = stream.peer_addr().unwrap();
+        let (reader, writer, fingerprint): (Box<dyn Read + Send>, Box<dyn Write + Send>, _) = match tls {
+            Some(mut conn) => {
+                let fingerprint = tls::handshake(&mut conn, &mut stream).map_err(Error::Handshake)?;
+                let (reader, writer) = tls::split(conn, &stream).map_err(Error::Setup)?;
+                (Box::new(reader), Box::new(writer), fingerprint)
+            },
+            None => (Box::new(stream.try_clone().map_err(Error::Setup)?), Box::new(stream.try_clone().map_err(Error::Setup)?), None),
+        };
         
-        let stream = Arc::new(Mutex::new(InfCell::new(stream)));
-        let sw = stream.clone();
-        let sr = stream.clone();
+        let (send, outgoing) = unbounded::<Message>();
+        let (bulk, mut bulk_outgoing) = bounded::<Message>(BULK_QUEUE);
+        let (incoming, recv) = unbounded::<Event>();
         
-        let mut workmsg_opt: Option<WorkingMessage> = None;
-        let (send, recv) = chan_owned.split();
-        std::thread::spawn(move || {
+        // A failed write shuts the stream down, which stops the reader too. The reader then reports the
+        //   writer's error in place of its own.
+        let write_error = Arc::new(Mutex::new(None));
+        let reader_write_error = write_error.clone();
+        std::thread::Builder::new().name(format!("SocketWriter {}", peer)).spawn(move || {
+            let mut writer = BufWriter::with_capacity(WRITE_BUFFER, writer);
             loop {
-                while let Ok(msg) = recv.recv_timeout(Duration::from_secs(1)) {
-                    let mut buf = vec![];
-                    buf.extend_from_slice(&(msg.len() as u32).to_be_bytes());
-                    buf.extend_from_slice(&msg);
-                    
-                    trace!("Sending message. msg_len: {}, buf_len: {}", msg.len(), buf.len());
-                    let mut i = 0;
-                    while i < buf.len() {
-                        let send_len = min(buf.len() - i, 1024 * 1024);
-                        
-                        let len = match sw.lock().unwrap().get_mut().write(&buf[i..(i + send_len)]) {
-                            Ok(len) => len,
-                            Err(_) => 0,
-                        };
-                        if len > 0 {
-                            trace!("Wrote {} bytes", len);
-                        }
-                        i += len;
-                    }
-                    trace!("Message sent.");
+                let msg = select! {
+                    recv(outgoing) -> msg => match msg {
+                        Ok(msg) => msg,
+                        Err(_) => break,
+                    },
+                    recv(bulk_outgoing) -> msg => match msg {
+                        Ok(msg) => msg,
+                        // Bulk senders alone don't keep the connection open, so this just stops watching them
+                        Err(_) => {
+                            bulk_outgoing = never();
+                            continue;
+                        },
+                    },
+                };
+                trace!("Sending message. msg_len: {}", msg.len());
+                // Messages queued up behind this one go out together
+                let result = write_message(&mut writer, &msg, limits.max_outgoing)
+                    .and_then(|_| if outgoing.is_empty() && bulk_outgoing.is_empty() { writer.flush() } else { Ok(()) });
+                if let Err(err) = result {
+                    *write_error.lock().unwrap() = Some(timed_out(err, "a write stalled", limits.write_timeout));
+                    break;
                 }
             }
-        });
-        std::thread::spawn(move || {
-            //sr.lock().unwrap().get_mut().set_read_timeout(None).unwrap();
+            // Everything was flushed unless writing failed, in which case it isn't retried
+            drop(writer.into_parts());
+            stream.shutdown(Shutdown::Both).unwrap_or_default();
+        }).map_err(Error::Setup)?;
+        
+        std::thread::Builder::new().name(format!("SocketReader {}", peer)).spawn(move || {
+            let mut reader = BufReader::new(reader);
             loop {
-                if workmsg_opt.is_none() {
-                    let mut msg = WorkingMessage::default();
-                    let mut len_buf = [0u8; 4];
-                    match sr.lock().unwrap().get_mut().read_exact(&mut len_buf) {
-                        Ok(_) => (),
-                        Err(err) => {
-                            trace!("Unable to read message len. {:?}", err);
-                            std::thread::sleep(Duration::from_nanos(1));
-                            continue;
-                        }
-                    }
-                    msg.msg_len = u32::from_be_bytes(len_buf);
-                    trace!("Reading new message with len {} bytes", msg.msg_len);
-                    
-                    workmsg_opt = Some(msg);
-                }
-                
-                let workmsg = workmsg_opt.as_mut().unwrap();
-                
-                let mut buf = vec![0u8; min(workmsg.msg_len as usize - workmsg.payload.len(), 1024 * 1024)];
-                let len = match sr.lock().unwrap().get_mut().read(&mut buf) {
-                    Ok(len) => len,
-                    Err(_) => 0
+                let event = match read_message(&mut reader, limits.max_incoming) {
+                    Ok(msg) => {
+                        trace!("Finished reading new message. len: {}", msg.len());
+                        Event::Message(msg)
+                    },
+                    Err(err) => match reader_write_error.lock().unwrap().take() {
+                        Some(err) => Event::Disconnected(Some(err)),
+                        None if err.kind() == ErrorKind::UnexpectedEof => Event::Disconnected(None),
+                        None => Event::Disconnected(Some(timed_out(err, "nothing was received", limits.read_timeout))),
+                    },
                 };
-                if len > 0 {
-                    trace!("Read {} bytes", len);
-                }
+                let disconnected = matches!(event, Event::Disconnected(_));
                 
-                if len > 0 {
-                    workmsg.payload.extend_from_slice(&buf[0..len]);
-                }
-                
-                if workmsg.payload.len() as u32 == workmsg.msg_len {
-                    trace!("Finished reading new message. len: {}", workmsg.payload.len());
-                    send.try_send(workmsg_opt.unwrap().payload).unwrap_or_default();
-                    workmsg_opt = None;
-                }
+                if incoming.send(event).is_err() || disconnected { break }
             }
-        });
+        }).map_err(Error::Setup)?;
         
-        let (send, recv) = chan_pub.split();
-        Self {
+        Ok(Self {
             send,
+            bulk,
             recv,
             peer,
-        }
+            fingerprint,
+        })
     }
-}
\ No newline at end of file
+}
+
+/// Writes a message, prefixed with its length. Nothing is flushed.
+fn write_message<W: Write>(writer: &mut W, msg: &[u8], max_len: usize) -> io::Result<()> {
+    if msg.len() > max_len {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("a {} byte message is over the {} byte limit", msg.len(), max_len)));
+    }
+    
+    writer.write_all(&(msg.len() as u32).to_be_bytes())?;
+    writer.write_all(msg)
+}
+
+/// Reads a whole length-prefixed message. The message grows as it arrives, rather than being allocated
+/// up front at whatever length the peer claims.
+fn read_message<R: Read>(reader: &mut R, max_len: usize) -> io::Result<Message> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as u64;
+    trace!("Reading new message with len {} bytes", len);
+    if len > max_len as u64 {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("the peer sent a {} byte message, over the {} byte limit", len, max_len)));
+    }
+    
+    let mut msg = vec![];
+    reader.take(len).read_to_end(&mut msg)?;
+    if (msg.len() as u64) < len {
+        return Err(io::Error::new(ErrorKind::ConnectionAborted, "the connection closed mid-message"));
+    }
+    
+    Ok(msg)
+}
+
+/// Describes a socket timeout, which is reported as `WouldBlock` on some platforms and `TimedOut` on others.
+fn timed_out(err: io::Error, what: &str, timeout: Option<Duration>) -> io::Error {
+    match (err.kind(), timeout) {
+        (ErrorKind::WouldBlock | ErrorKind::TimedOut, Some(timeout)) => io::Error::new(ErrorKind::TimedOut, format!("{} for {:?}", what, timeout)),
+        _ => err,
+    }
+}
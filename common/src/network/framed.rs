@@ -0,0 +1,164 @@
+use std::io;
+use std::net::SocketAddr;
+use bytes::{Bytes, BytesMut};
+use log::warn;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_util::codec::{Decoder, Encoder, Framed, LengthDelimitedCodec};
+use crate::Packet;
+use super::{Error, Limits};
+
+/// A connection for async code, as a `Stream` of packets received and a `Sink` of packets to send. It
+/// exchanges the same length-prefixed messages as `SocketConnection`, without any threads of its own.
+/// 
+/// Only the size limits in `Limits` apply. Idle timeouts are left to the caller, e.g. with `tokio::time::timeout()`.
+pub type PacketStream = Framed<TcpStream, PacketCodec>;
+
+
+/// Splits received messages into packets, and sends packets as messages.
+/// 
+/// A message over the size limit is an error, which ends the stream. A malformed packet is skipped,
+/// as it would be by the server.
+pub struct PacketCodec {
+    decoder: LengthDelimitedCodec,
+    encoder: LengthDelimitedCodec,
+}
+impl PacketCodec {
+    pub fn new(limits: Limits) -> Self { Self {
+        decoder: LengthDelimitedCodec::builder().max_frame_length(limits.max_incoming).new_codec(),
+        encoder: LengthDelimitedCodec::builder().max_frame_length(limits.max_outgoing).new_codec(),
+    }}
+}
+impl Decoder for PacketCodec {
+    type Item = Packet;
+    type Error = io::Error;
+    
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
+        while let Some(msg) = self.decoder.decode(src)? {
+            match Packet::deserialize(&msg) {
+                Ok(packet) => return Ok(Some(packet)),
+                Err(err) => warn!("Malformed packet: {:?}", err),
+            }
+        }
+        
+        Ok(None)
+    }
+}
+impl Encoder<Packet> for PacketCodec {
+    type Error = io::Error;
+    
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> io::Result<()> {
+        self.encoder.encode(Bytes::from(packet.serialize()), dst)
+    }
+}
+
+
+/// Connects to a server.
+pub async fn connect<A: ToSocketAddrs>(addr: A, limits: Limits) -> Result<PacketStream, Error> {
+    let stream = TcpStream::connect(addr).await.map_err(Error::Connect)?;
+    stream.set_nodelay(true).map_err(Error::Setup)?;
+    
+    Ok(Framed::new(stream, PacketCodec::new(limits)))
+}
+
+/// Accepts connections from clients.
+pub struct Listener {
+    listener: TcpListener,
+    limits: Limits,
+}
+impl Listener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A, limits: Limits) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr).await.map_err(Error::Bind)?;
+        
+        Ok(Self {
+            listener,
+            limits,
+        })
+    }
+    
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    
+    /// Waits for the next client to connect. A failed connection only affects that client, so the
+    /// caller may carry on accepting others.
+    pub async fn accept(&self) -> Result<(PacketStream, SocketAddr), Error> {
+        let (stream, peer) = self.listener.accept().await.map_err(Error::Setup)?;
+        stream.set_nodelay(true).map_err(Error::Setup)?;
+        
+        Ok((Framed::new(stream, PacketCodec::new(self.limits)), peer))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::write_message;
+    
+    fn limits(max_len: usize) -> Limits {
+        Limits {
+            max_incoming: max_len,
+            max_outgoing: max_len,
+            ..Default::default()
+        }
+    }
+    
+    #[test]
+    fn frames_packets_like_socket_connection() {
+        let packets = [Packet::QueueResponse(7), Packet::RomUpload(vec![0x80, 0x37, 0x12, 0x40]), Packet::Ping];
+        let mut codec = PacketCodec::new(Limits::default());
+        
+        let mut encoded = BytesMut::new();
+        let mut expected = vec![];
+        for packet in &packets {
+            codec.encode(packet.clone(), &mut encoded).unwrap();
+            write_message(&mut expected, &packet.serialize(), usize::MAX).unwrap();
+        }
+        assert_eq!(&encoded[..], &expected[..]);
+        // Each message starts with its length, as 4 big-endian bytes
+        assert_eq!(&encoded[..4], &(packets[0].serialize().len() as u32).to_be_bytes());
+        
+        for packet in packets {
+            assert_eq!(codec.decode(&mut encoded).unwrap(), Some(packet));
+        }
+        assert_eq!(codec.decode(&mut encoded).unwrap(), None);
+    }
+    
+    #[test]
+    fn partial_message_waits_for_the_rest() {
+        let mut codec = PacketCodec::new(Limits::default());
+        let mut message = vec![];
+        write_message(&mut message, &Packet::RomUpload(vec![1; 64]).serialize(), usize::MAX).unwrap();
+        
+        let mut src = BytesMut::from(&message[..10]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&message[10..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Packet::RomUpload(vec![1; 64])));
+    }
+    
+    #[test]
+    fn malformed_packet_is_skipped() {
+        let mut codec = PacketCodec::new(Limits::default());
+        let mut src = vec![];
+        write_message(&mut src, &[], usize::MAX).unwrap();
+        write_message(&mut src, &Packet::Pong.serialize(), usize::MAX).unwrap();
+        
+        assert_eq!(codec.decode(&mut BytesMut::from(&src[..])).unwrap(), Some(Packet::Pong));
+    }
+    
+    #[test]
+    fn oversized_message_is_rejected() {
+        let packet = Packet::RomUpload(vec![0; 32]);
+        let len = packet.serialize().len();
+        let mut message = vec![];
+        write_message(&mut message, &packet.serialize(), usize::MAX).unwrap();
+        
+        let mut codec = PacketCodec::new(limits(len));
+        assert_eq!(codec.decode(&mut BytesMut::from(&message[..])).unwrap(), Some(packet.clone()));
+        
+        // Only the length has to arrive for the message to be rejected
+        let mut codec = PacketCodec::new(limits(len - 1));
+        assert!(codec.decode(&mut BytesMut::from(&message[..4])).is_err());
+        assert!(codec.encode(packet, &mut BytesMut::new()).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use rustls::{ClientConfig, ClientConnection, Connection, DigitallySignedStruct, RootCertStore, ServerConfig, ServerConnection, SignatureScheme};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::pki_types::pem::PemObject;
+use log::warn;
+use sha2::{Digest, Sha256};
+use super::Error;
+
+/// Most plaintext encrypted at a time, so a large message isn't held in memory twice over.
+const WRITE_CHUNK: usize = 64 * 1024;
+/// Encrypted bytes read from the socket at a time.
+const READ_BUFFER: usize = 32 * 1024;
+
+
+/// How the client decides whether to trust the server's certificate.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trust {
+    /// Certificates issued by any of the system's root authorities, for the server's name.
+    System,
+    /// Certificates issued by the authority in this PEM file, for the server's name.
+    Ca(PathBuf),
+    /// Only the certificate with this SHA-256 fingerprint, whoever issued it (e.g. a self-signed one). Without a
+    /// fingerprint, whichever certificate the server presents is trusted, and can be pinned from then on.
+    Pinned(Option<String>),
+}
+
+/// The server's side of TLS, with its certificate.
+#[derive(Clone)]
+pub struct TlsServer {
+    config: Arc<ServerConfig>,
+    fingerprint: String,
+}
+impl TlsServer {
+    /// Loads the certificate chain and private key from PEM files.
+    pub fn new(cert: &Path, key: &Path) -> Result<Self, Error> {
+        let chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| Error::Tls(format!("failed to read the certificate {}: {}", cert.display(), err)))?;
+        if chain.is_empty() {
+            return Err(Error::Tls(format!("{} holds no certificates", cert.display())));
+        }
+        let fingerprint = fingerprint(&chain[0]);
+        let key_der = PrivateKeyDer::from_pem_file(key)
+            .map_err(|err| Error::Tls(format!("failed to read the private key {}: {}", key.display(), err)))?;
+        
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key_der))
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        
+        Ok(Self {
+            config: Arc::new(config),
+            fingerprint,
+        })
+    }
+    
+    /// Fingerprint of the server's certificate, for clients to pin.
+    pub fn fingerprint(&self) -> &str { &self.fingerprint }
+    
+    pub(super) fn connection(&self) -> Result<Connection, Error> {
+        ServerConnection::new(self.config.clone())
+            .map(Connection::from)
+            .map_err(|err| Error::Tls(err.to_string()))
+    }
+}
+
+/// The client's side of TLS, for connecting to a particular server.
+#[derive(Clone)]
+pub struct TlsClient {
+    config: Arc<ClientConfig>,
+    name: ServerName<'static>,
+    pin: Option<String>,
+}
+impl TlsClient {
+    /// `host` is the server's domain name or IP address, which its certificate must be for, unless pinned.
+    pub fn new(host: &str, trust: &Trust) -> Result<Self, Error> {
+        let name = ServerName::try_from(host.to_owned()).map_err(|_| Error::Tls(format!("`{}` is not a valid server name", host)))?;
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        
+        let config = match trust {
+            Trust::System => {
+                let mut roots = RootCertStore::empty();
+                let found = rustls_native_certs::load_native_certs();
+                for err in found.errors {
+                    warn!("Failed to load some of the system's root certificates: {}", err);
+                }
+                let (added, _) = roots.add_parsable_certificates(found.certs);
+                if added == 0 {
+                    return Err(Error::Tls("no root certificates were found on the system".to_owned()));
+                }
+                
+                builder.with_root_certificates(roots).with_no_client_auth()
+            },
+            Trust::Ca(path) => {
+                let mut roots = RootCertStore::empty();
+                let certs = CertificateDer::pem_file_iter(path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|err| Error::Tls(format!("failed to read the CA certificate {}: {}", path.display(), err)))?;
+                for cert in certs {
+                    roots.add(cert).map_err(|err| Error::Tls(format!("invalid CA certificate in {}: {}", path.display(), err)))?;
+                }
+                if roots.is_empty() {
+                    return Err(Error::Tls(format!("{} holds no certificates", path.display())));
+                }
+                
+                builder.with_root_certificates(roots).with_no_client_auth()
+            },
+            Trust::Pinned(_) => builder.dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinVerifier {
+                    provider: provider(),
+                }))
+                .with_no_client_auth(),
+        };
+        
+        Ok(Self {
+            config: Arc::new(config),
+            name,
+            pin: match trust {
+                Trust::Pinned(pin) => pin.clone(),
+                _ => None,
+            },
+        })
+    }
+    
+    /// Checks the fingerprint of the server's certificate against the pin, if any, once the handshake is done.
+    pub(super) fn check(&self, fingerprint: Option<&str>) -> Result<(), Error> {
+        match (&self.pin, fingerprint) {
+            (Some(pin), Some(fingerprint)) if same_fingerprint(pin, fingerprint) => Ok(()),
+            (Some(pin), fingerprint) => Err(Error::Handshake(io::Error::new(ErrorKind::InvalidData,
+                format!("the server's certificate {} doesn't match the pinned {}", fingerprint.unwrap_or("(none)"), pin)))),
+            (None, _) => Ok(()),
+        }
+    }
+    
+    pub(super) fn connection(&self) -> Result<Connection, Error> {
+        ClientConnection::new(self.config.clone(), self.name.clone())
+            .map(Connection::from)
+            .map_err(|err| Error::Tls(err.to_string()))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// SHA-256 fingerprint of a certificate, as colon separated hex (e.g. `AB:12:...`), the same as `openssl x509 -fingerprint -sha256`.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert).iter().fold(String::new(), |mut text, byte| {
+        if !text.is_empty() { text.push(':') }
+        write!(text, "{:02X}", byte).unwrap_or_default();
+        text
+    })
+}
+
+/// Compares fingerprints, ignoring case and any separators.
+pub fn same_fingerprint(a: &str, b: &str) -> bool {
+    let normalize = |text: &str| text.chars().filter(|c| c.is_ascii_hexdigit()).map(|c| c.to_ascii_uppercase()).collect::<String>();
+    normalize(a) == normalize(b)
+}
+
+
+/// Accepts whichever certificate the server presents, which is then checked against the pin once the
+/// handshake is done (see `TlsClient::check()`), before anything is sent. The handshake's signatures are
+/// still checked, so the server must hold the certificate's private key.
+#[derive(Debug)]
+struct PinVerifier {
+    provider: Arc<CryptoProvider>,
+}
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+    
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+    
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+
+/// Completes the handshake, returning the fingerprint of the peer's certificate, if it sent one.
+pub(super) fn handshake(conn: &mut Connection, stream: &mut TcpStream) -> io::Result<Option<String>> {
+    while conn.is_handshaking() {
+        conn.complete_io(stream)?;
+    }
+    
+    Ok(conn.peer_certificates().and_then(|certs| certs.first()).map(|cert| fingerprint(cert)))
+}
+
+/// Splits a connection into halves like a `TcpStream`'s, which share its TLS session. Only the writing
+/// half writes to the stream, so any records produced while reading (e.g. key updates) go out with the next write.
+pub(super) fn split(conn: Connection, stream: &TcpStream) -> io::Result<(TlsReader, TlsWriter)> {
+    let conn = Arc::new(Mutex::new(conn));
+    
+    Ok((
+        TlsReader {
+            stream: stream.try_clone()?,
+            conn: conn.clone(),
+            buf: vec![0; READ_BUFFER],
+            start: 0,
+            end: 0,
+        },
+        TlsWriter {
+            stream: stream.try_clone()?,
+            conn,
+            failed: false,
+        },
+    ))
+}
+
+pub(super) struct TlsReader {
+    stream: TcpStream,
+    conn: Arc<Mutex<Connection>>,
+    /// Encrypted bytes read from the stream, of which `start..end` haven't been passed to the session yet.
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+impl Read for TlsReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut conn = self.conn.lock().unwrap();
+            match conn.reader().read(out) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+            
+            // The stream is only read once everything before has been decrypted, and without holding the session
+            if self.start == self.end {
+                drop(conn);
+                self.end = self.stream.read(&mut self.buf)?;
+                self.start = 0;
+                conn = self.conn.lock().unwrap();
+            }
+            
+            let mut pending = &self.buf[self.start..self.end];
+            self.start += conn.read_tls(&mut pending)?;
+            conn.process_new_packets().map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        }
+    }
+}
+
+pub(super) struct TlsWriter {
+    stream: TcpStream,
+    conn: Arc<Mutex<Connection>>,
+    failed: bool,
+}
+impl TlsWriter {
+    /// Writes out every record the session has waiting, without holding the session while writing.
+    fn send_pending(&mut self) -> io::Result<()> {
+        let mut records = vec![];
+        {
+            let mut conn = self.conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+        }
+        
+        let result = self.stream.write_all(&records);
+        self.failed |= result.is_err();
+        result
+    }
+}
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(WRITE_CHUNK);
+        self.conn.lock().unwrap().writer().write_all(&buf[..len])?;
+        self.send_pending()?;
+        
+        Ok(len)
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending()?;
+        self.stream.flush()
+    }
+}
+impl Drop for TlsWriter {
+    /// Tells the peer the connection is being closed, unless it already failed.
+    fn drop(&mut self) {
+        if self.failed { return }
+        
+        self.conn.lock().unwrap().send_close_notify();
+        self.send_pending().unwrap_or_default();
+    }
+}
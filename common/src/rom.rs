@@ -0,0 +1,95 @@
+use sha2::{Digest, Sha256};
+
+/// Length of the header at the start of every N64 ROM image.
+pub const HEADER_LEN: usize = 0x40;
+
+
+/// Byte order a ROM image was dumped in, identified by the first word of its header.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ByteOrder {
+    /// `.z64`, the console's native order.
+    BigEndian,
+    /// `.v64`, every pair of bytes swapped.
+    ByteSwapped,
+    /// `.n64`, every 32-bit word reversed.
+    LittleEndian,
+}
+impl ByteOrder {
+    pub fn detect(rom: &[u8]) -> Option<Self> {
+        match rom.get(0..4)? {
+            [0x80, 0x37, 0x12, 0x40] => Some(ByteOrder::BigEndian),
+            [0x37, 0x80, 0x40, 0x12] => Some(ByteOrder::ByteSwapped),
+            [0x40, 0x12, 0x37, 0x80] => Some(ByteOrder::LittleEndian),
+            _ => None
+        }
+    }
+    
+    /// Converts data in this byte order into big endian (`.z64`) order.
+    pub fn to_big_endian(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ByteOrder::BigEndian => data.to_vec(),
+            ByteOrder::ByteSwapped => data.chunks(2).flat_map(|chunk| chunk.iter().rev().copied()).collect(),
+            ByteOrder::LittleEndian => data.chunks(4).flat_map(|chunk| chunk.iter().rev().copied()).collect(),
+        }
+    }
+}
+
+
+/// Identifying information from a ROM's header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomHeader {
+    pub order: ByteOrder,
+    pub crc1: u32,
+    pub crc2: u32,
+    /// Internal name, with padding removed.
+    pub title: String,
+    /// Four character game code (category, two character ID, region), e.g. `NSME`.
+    pub game_code: String,
+    pub version: u8,
+}
+impl RomHeader {
+    /// Parses the header of a ROM image in any byte order. Returns `None` if the data isn't a ROM.
+    pub fn parse(rom: &[u8]) -> Option<Self> {
+        if rom.len() < HEADER_LEN { return None }
+        
+        let order = ByteOrder::detect(rom)?;
+        let header = order.to_big_endian(&rom[..HEADER_LEN]);
+        
+        let text = |range: std::ops::Range<usize>| -> String {
+            header[range].iter()
+                .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { ' ' })
+                .collect::<String>()
+                .trim()
+                .to_owned()
+        };
+        
+        Some(Self {
+            order,
+            crc1: u32::from_be_bytes([header[0x10], header[0x11], header[0x12], header[0x13]]),
+            crc2: u32::from_be_bytes([header[0x14], header[0x15], header[0x16], header[0x17]]),
+            title: text(0x20..0x34),
+            game_code: text(0x3B..0x3F),
+            version: header[0x3F],
+        })
+    }
+}
+
+/// Identifies a ROM image, without having to keep its contents around.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomIdentity {
+    pub size: usize,
+    pub sha256: String,
+    pub header: Option<RomHeader>,
+}
+impl RomIdentity {
+    pub fn new(rom: &[u8]) -> Self { Self {
+        size: rom.len(),
+        sha256: sha256_hex(rom),
+        header: RomHeader::parse(rom),
+    }}
+}
+
+/// SHA-256 hash of the data, as a lowercase hex string.
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
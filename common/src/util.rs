@@ -1,5 +1,10 @@
 
 use std::cell::UnsafeCell;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use sha2::{Digest, Sha256};
 
 /// Infinite access unsafe cell. Multiple mutable references of this data can exist
 /// across threads. No locking or any kind of safety checks are performed.
@@ -38,4 +43,61 @@ impl<T> InfCell<T> {
 }
 
 unsafe impl<T> Send for InfCell<T> {}
-unsafe impl<T> Sync for InfCell<T> {}
\ No newline at end of file
+unsafe impl<T> Sync for InfCell<T> {}
+
+/// Parses a human readable duration such as `30s`, `500ms`, `2m`, `1h` or `7d`.
+/// 
+/// A number without a unit is interpreted as seconds. Fractional values (e.g. `1.5s`) are allowed.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let (value, unit) = text.split_at(split);
+    let value: f64 = value.parse().ok()?;
+    
+    let secs = match unit.trim() {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        "d" => value * 86400.0,
+        _ => return None,
+    };
+    
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Parses a size in bytes such as `512K`, `1.5G` or `1048576`. Units are binary (1K = 1024 bytes),
+/// and may optionally be followed by `B` or `iB`.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let (value, unit) = text.split_at(split);
+    let value: f64 = value.parse().ok()?;
+    
+    let unit = unit.trim().to_ascii_uppercase();
+    let multiplier = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1u64,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    
+    Some((value * multiplier as f64) as u64)
+}
+
+/// SHA-256 hash of a file's contents, as a lowercase hex string. The file is read in pieces, so it
+/// may be larger than available memory.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 { break }
+        hasher.update(&buf[..len]);
+    }
+    
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
@@ -0,0 +1,423 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use cpal::{BufferSize, Sample, SampleFormat, SampleRate, Stream, StreamConfig};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crossbeam_queue::SegQueue;
+use hound::WavReader;
+use serde::Deserialize;
+use crate::config::AudioConfig;
+use crate::sync::Clock;
+
+/// Sample rate audio is handled in everywhere in the server, and sent to clients in.
+pub const SAMPLE_RATE: u32 = 44100;
+/// Channels audio is handled in everywhere in the server, interleaved.
+pub const CHANNELS: usize = 2;
+/// Length of the chunks generated and replayed audio is delivered in.
+const CHUNK_SECS: f64 = 0.01;
+/// Frequency and level of the synthetic tone.
+const TONE_HZ: f64 = 440.0;
+const TONE_LEVEL: f32 = 0.25;
+/// Most audio that may wait to be monitored. Anything beyond that is dropped, so the speakers never lag far behind.
+const MONITOR_MAX_SECS: f64 = 0.25;
+
+
+/// Where captured audio comes from.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// An audio input device.
+    Device,
+    /// A generated sine tone, for running without any hardware.
+    Tone,
+    /// Audio replayed from a WAV file, over and over.
+    File,
+}
+impl FromStr for Source {
+    type Err = ();
+    
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "device" => Ok(Self::Device),
+            "tone" => Ok(Self::Tone),
+            "file" => Ok(Self::File),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Anything the server can capture audio from.
+pub trait AudioSource {
+    /// Starts capturing into `sink`.
+    fn start(&mut self, sink: AudioSink) -> Result<(), String>;
+    
+    /// Stops capturing. Nothing more is delivered to the sink afterwards.
+    fn stop(&mut self);
+}
+
+/// Receives captured audio, as chunks of interleaved samples in the server's format (see `SAMPLE_RATE`
+/// and `CHANNELS`), along with the time (see `Clock`) the first sample of each was captured.
+/// 
+/// Chunks are pushed onto the queue read by the main loop, and onto the monitor's queue if monitoring.
+#[derive(Clone)]
+pub struct AudioSink {
+    samples: Arc<SegQueue<(f64, Vec<f32>)>>,
+    monitor: Option<Arc<SegQueue<f32>>>,
+}
+impl AudioSink {
+    pub fn push(&self, captured_at: f64, chunk: Vec<f32>) {
+        if let Some(monitor) = &self.monitor {
+            let max = (MONITOR_MAX_SECS * SAMPLE_RATE as f64) as usize * CHANNELS;
+            if monitor.len() < max {
+                for sample in &chunk {
+                    monitor.push(*sample);
+                }
+            }
+        }
+        self.samples.push((captured_at, chunk));
+    }
+}
+
+/// The running audio source, along with the monitor output. Capturing stops when this is dropped.
+pub struct AudioCapture {
+    source: Box<dyn AudioSource>,
+    _monitor: Option<Stream>,
+}
+impl AudioCapture {
+    pub fn stop(&mut self) {
+        self.source.stop();
+    }
+}
+impl Drop for AudioCapture {
+    fn drop(&mut self) {
+        self.source.stop();
+    }
+}
+
+/// Opens and starts the configured audio source, monitoring it on the default output device if
+/// configured to. Captured audio is pushed onto `samples` (see `AudioSink`).
+pub fn start(config: &AudioConfig, clock: Clock, samples: Arc<SegQueue<(f64, Vec<f32>)>>) -> Result<AudioCapture, String> {
+    let mut source: Box<dyn AudioSource> = match config.source {
+        Source::Device => Box::new(DeviceSource::open(config, clock)?),
+        Source::Tone => {
+            info!("Generating a {}Hz tone.", TONE_HZ);
+            Box::new(ToneSource::new(clock))
+        },
+        Source::File => {
+            let path = config.file.as_ref().ok_or("audio.file must be set to capture from a file")?;
+            Box::new(WavSource::open(path, clock).map_err(|err| format!("failed to open {}: {}", path.display(), err))?)
+        },
+    };
+    
+    let (monitor_stream, monitor_queue) = match config.monitor.then(open_monitor) {
+        Some(Ok((stream, queue))) => (Some(stream), Some(queue)),
+        Some(Err(err)) => {
+            warn!("Unable to monitor audio on the default output, continuing without it: {}", err);
+            (None, None)
+        },
+        None => (None, None),
+    };
+    
+    source.start(AudioSink {
+        samples,
+        monitor: monitor_queue,
+    })?;
+    
+    Ok(AudioCapture {
+        source,
+        _monitor: monitor_stream,
+    })
+}
+
+/// Opens an output stream on the default output device, playing whatever is pushed onto the returned queue.
+fn open_monitor() -> Result<(Stream, Arc<SegQueue<f32>>), String> {
+    let device = cpal::default_host().default_output_device().ok_or("no audio output device found")?;
+    let config = StreamConfig {
+        channels: CHANNELS as u16,
+        sample_rate: SampleRate(SAMPLE_RATE),
+        buffer_size: BufferSize::Default,
+    };
+    
+    let queue = Arc::new(SegQueue::new());
+    let output_queue = queue.clone();
+    let stream = device.build_output_stream(
+        &config,
+        move |output_buffer: &mut [f32], _info: &cpal::OutputCallbackInfo| {
+            for output_sample in output_buffer.iter_mut() {
+                *output_sample = output_queue.pop().unwrap_or(0.0);
+            }
+        },
+        move |err| warn!("Audio monitor error: {}", err),
+    ).map_err(|err| err.to_string())?;
+    stream.play().map_err(|err| err.to_string())?;
+    
+    Ok((stream, queue))
+}
+
+
+/// Captures from an audio input device.
+pub struct DeviceSource {
+    device: cpal::Device,
+    config: StreamConfig,
+    format: SampleFormat,
+    stream: Option<Stream>,
+    clock: Clock,
+}
+impl DeviceSource {
+    /// Opens the input device whose name contains `config.input` (or the default input, if empty),
+    /// to capture at the configured sample rate and channel count.
+    pub fn open(config: &AudioConfig, clock: Clock) -> Result<Self, String> {
+        let host = cpal::default_host();
+        let default = || host.default_input_device().ok_or_else(|| "no audio input device found".to_owned());
+        
+        let device = if config.input.is_empty() {
+            default()?
+        } else {
+            let found = host.input_devices().map_err(|err| err.to_string())?
+                .find(|device| device.name().map(|name| name.contains(&config.input)).unwrap_or(false));
+            match found {
+                Some(device) => device,
+                None => {
+                    warn!("No audio input named {:?} found, using the default input.", config.input);
+                    default()?
+                }
+            }
+        };
+        
+        let format = device.default_input_config().map_err(|err| err.to_string())?.sample_format();
+        info!("Capturing audio from {} at {}Hz with {} channels.", device.name().unwrap_or_default(), config.sample_rate, config.channels);
+        
+        Ok(Self {
+            device,
+            config: StreamConfig {
+                channels: config.channels,
+                sample_rate: SampleRate(config.sample_rate),
+                buffer_size: BufferSize::Default,
+            },
+            format,
+            stream: None,
+            clock,
+        })
+    }
+    
+    fn build<T: Sample>(&self, sink: AudioSink) -> Result<Stream, cpal::BuildStreamError> {
+        let clock = self.clock;
+        let sample_rate = self.config.sample_rate.0;
+        let channels = self.config.channels as usize;
+        let mut resampler = Resampler::new(sample_rate, channels);
+        
+        self.device.build_input_stream(
+            &self.config,
+            move |in_buffer: &[T], info: &cpal::InputCallbackInfo| {
+                // How long ago the first sample was captured. Not every host reports this, in which case
+                //   the buffer's own length is the best guess.
+                let timestamp = info.timestamp();
+                let age = timestamp.callback.duration_since(&timestamp.capture)
+                    .unwrap_or_else(|| Duration::from_secs_f64((in_buffer.len() / channels) as f64 / sample_rate as f64));
+                
+                let samples: Vec<f32> = in_buffer.iter().map(|sample| sample.to_f32()).collect();
+                sink.push(clock.now() - age.as_secs_f64(), resampler.process(&samples));
+            },
+            move |err| warn!("Audio input error: {}", err),
+        )
+    }
+}
+impl AudioSource for DeviceSource {
+    fn start(&mut self, sink: AudioSink) -> Result<(), String> {
+        let stream = match self.format {
+            SampleFormat::F32 => self.build::<f32>(sink),
+            SampleFormat::I16 => self.build::<i16>(sink),
+            SampleFormat::U16 => self.build::<u16>(sink),
+        }.map_err(|err| format!("failed to open the audio input: {}", err))?;
+        stream.play().map_err(|err| format!("failed to start the audio input: {}", err))?;
+        
+        self.stream = Some(stream);
+        Ok(())
+    }
+    
+    fn stop(&mut self) {
+        self.stream = None;
+    }
+}
+
+
+/// Generates a continuous sine tone, in the server's format, in place of captured audio.
+pub struct ToneSource {
+    clock: Clock,
+    player: Option<Player>,
+}
+impl ToneSource {
+    pub fn new(clock: Clock) -> Self { Self {
+        clock,
+        player: None,
+    }}
+}
+impl AudioSource for ToneSource {
+    fn start(&mut self, sink: AudioSink) -> Result<(), String> {
+        let mut phase = 0.0f64;
+        self.player = Some(Player::spawn(self.clock, sink, move |chunk| {
+            for frame in chunk.chunks_exact_mut(CHANNELS) {
+                frame.fill((phase * std::f64::consts::TAU).sin() as f32 * TONE_LEVEL);
+                phase = (phase + TONE_HZ / SAMPLE_RATE as f64).fract();
+            }
+        }));
+        
+        Ok(())
+    }
+    
+    fn stop(&mut self) {
+        if let Some(player) = self.player.take() {
+            player.stop();
+        }
+    }
+}
+
+
+/// Replays a WAV file, looping back to the start after the end. The whole file is converted into the
+/// server's format when opened.
+pub struct WavSource {
+    audio: Arc<Vec<f32>>,
+    clock: Clock,
+    player: Option<Player>,
+}
+impl WavSource {
+    pub fn open(path: &Path, clock: Clock) -> Result<Self, String> {
+        let mut reader = WavReader::open(path).map_err(|err| err.to_string())?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+            hound::SampleFormat::Int => {
+                let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>().map(|sample| sample.map(|sample| sample as f32 / scale)).collect::<Result<_, _>>()
+            },
+        }.map_err(|err| err.to_string())?;
+        
+        let audio = Resampler::new(spec.sample_rate, spec.channels as usize).process(&samples);
+        if audio.is_empty() {
+            return Err("the file holds no audio".to_owned());
+        }
+        info!("Replaying {:.1}s of audio from {}.", audio.len() as f64 / (SAMPLE_RATE as usize * CHANNELS) as f64, path.display());
+        
+        Ok(Self {
+            audio: Arc::new(audio),
+            clock,
+            player: None,
+        })
+    }
+}
+impl AudioSource for WavSource {
+    fn start(&mut self, sink: AudioSink) -> Result<(), String> {
+        let audio = self.audio.clone();
+        let mut pos = 0;
+        self.player = Some(Player::spawn(self.clock, sink, move |chunk| {
+            for sample in chunk.iter_mut() {
+                *sample = audio[pos];
+                pos = (pos + 1) % audio.len();
+            }
+        }));
+        
+        Ok(())
+    }
+    
+    fn stop(&mut self) {
+        if let Some(player) = self.player.take() {
+            player.stop();
+        }
+    }
+}
+
+
+/// Delivers generated audio in real time, from a thread of its own, for sources that aren't paced by a device.
+struct Player {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+impl Player {
+    /// Calls `fill` for every chunk, to fill it with interleaved samples in the server's format.
+    fn spawn<F: FnMut(&mut [f32]) + Send + 'static>(clock: Clock, sink: AudioSink, mut fill: F) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        
+        let thread = std::thread::spawn(move || {
+            let chunk_frames = (CHUNK_SECS * SAMPLE_RATE as f64) as usize;
+            let start = clock.now();
+            let mut frames = 0u64;
+            
+            while !stopped.load(Ordering::Relaxed) {
+                // Chunks are timed by the samples delivered so far, so the rate never drifts
+                let captured_at = start + frames as f64 / SAMPLE_RATE as f64;
+                let wait = captured_at - clock.now();
+                if wait > 0.0 {
+                    std::thread::sleep(Duration::from_secs_f64(wait));
+                }
+                
+                let mut chunk = vec![0.0; chunk_frames * CHANNELS];
+                fill(&mut chunk);
+                sink.push(captured_at, chunk);
+                frames += chunk_frames as u64;
+            }
+        });
+        
+        Self {
+            stop,
+            thread,
+        }
+    }
+    
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap_or_default();
+    }
+}
+
+
+/// Converts interleaved audio of any sample rate and channel count into the server's format.
+/// 
+/// Mono audio is copied onto both channels, and any channels past the first two are dropped. Sample
+/// rates are converted by linear interpolation, carrying over between calls so chunks join up seamlessly.
+pub struct Resampler {
+    channels: usize,
+    /// Input frames per output frame.
+    step: f64,
+    /// Position of the next output frame, in input frames, where 0 is the last frame of the previous call.
+    pos: f64,
+    last: [f32; 2],
+}
+impl Resampler {
+    pub fn new(sample_rate: u32, channels: usize) -> Self { Self {
+        channels: channels.max(1),
+        step: sample_rate as f64 / SAMPLE_RATE as f64,
+        pos: 1.0,
+        last: [0.0; 2],
+    }}
+    
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.step == 1.0 && self.channels == CHANNELS {
+            return input.to_vec();
+        }
+        
+        let frames: Vec<[f32; 2]> = input.chunks_exact(self.channels)
+            .map(|frame| if self.channels == 1 { [frame[0], frame[0]] } else { [frame[0], frame[1]] })
+            .collect();
+        let frame = |i: usize| if i == 0 { self.last } else { frames[i - 1] };
+        
+        let mut output = Vec::with_capacity(((frames.len() as f64 / self.step) as usize + 1) * CHANNELS);
+        while self.pos < frames.len() as f64 {
+            let i = self.pos as usize;
+            let weight = (self.pos - i as f64) as f32;
+            let (a, b) = (frame(i), frame(i + 1));
+            output.extend([a[0] + (b[0] - a[0]) * weight, a[1] + (b[1] - a[1]) * weight]);
+            self.pos += self.step;
+        }
+        
+        if let Some(last) = frames.last() {
+            self.last = *last;
+        }
+        self.pos -= frames.len() as f64;
+        
+        output
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Commands understood by the board's firmware. Each is answered with the command's byte, OR'd with 0xE0.
+const CMD_PING: u8 = 0x01;
+const CMD_ON: u8 = 0x02;
+const CMD_OFF: u8 = 0x03;
+
+/// How long the console is left off for, when power-cycled.
+pub const POWER_CYCLE_OFF: Duration = Duration::from_secs(1);
+
+
+/// Whether the console is powered, as far as the server knows.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Power {
+    /// The board hasn't been told to switch the power since it was opened.
+    Unknown,
+    On,
+    Off,
+}
+
+/// The controller board, connected over its USB/UART bridge, which switches the console's power with its relays.
+/// 
+/// The board starts out disconnected. Once connected, it's disconnected again by any command failing,
+/// until reconnected (see `watchdog::watch_board()`).
+pub struct Board {
+    path: PathBuf,
+    port: Mutex<Option<File>>,
+    power: Mutex<Power>,
+}
+impl Board {
+    pub fn new(path: &Path) -> Self { Self {
+        path: path.to_owned(),
+        port: Mutex::new(None),
+        power: Mutex::new(Power::Unknown),
+    }}
+    
+    /// Opens the board's serial port, and checks that the board answers.
+    pub fn connect(&self) -> io::Result<()> {
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&self.path)?;
+        configure(&port)?;
+        
+        *self.port.lock().unwrap() = Some(port);
+        self.ping()?;
+        info!("Controller board connected on {}.", self.path.display());
+        
+        Ok(())
+    }
+    
+    pub fn connected(&self) -> bool { self.port.lock().unwrap().is_some() }
+    
+    pub fn power(&self) -> Power { *self.power.lock().unwrap() }
+    
+    pub fn ping(&self) -> io::Result<()> {
+        self.command(CMD_PING)
+    }
+    
+    /// Switches the console on or off.
+    pub fn set_power(&self, on: bool) -> io::Result<()> {
+        self.command(if on { CMD_ON } else { CMD_OFF })?;
+        *self.power.lock().unwrap() = if on { Power::On } else { Power::Off };
+        
+        Ok(())
+    }
+    
+    /// Switches the console off, then back on after `off_for`.
+    pub fn power_cycle(&self, off_for: Duration) -> io::Result<()> {
+        self.set_power(false)?;
+        std::thread::sleep(off_for);
+        self.set_power(true)
+    }
+    
+    /// Sends a command, and waits for the board to acknowledge it. Disconnects from the board if it doesn't.
+    fn command(&self, cmd: u8) -> io::Result<()> {
+        let mut port = self.port.lock().unwrap();
+        let result = match port.as_mut() {
+            Some(port) => exchange(port, cmd),
+            None => Err(io::Error::new(ErrorKind::NotConnected, "the board isn't connected")),
+        };
+        if result.is_err() {
+            *port = None;
+        }
+        
+        result
+    }
+}
+
+fn exchange(port: &mut File, cmd: u8) -> io::Result<()> {
+    port.write_all(&[cmd])?;
+    
+    let mut reply = [0];
+    if port.read(&mut reply)? == 0 {
+        return Err(io::Error::new(ErrorKind::TimedOut, "the board didn't answer"));
+    }
+    if reply[0] != cmd | 0xE0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("unexpected reply {:#04x} to command {:#04x}", reply[0], cmd)));
+    }
+    
+    Ok(())
+}
+
+/// Puts the port into raw mode at the firmware's 500000 baud, with reads giving up after half a second.
+fn configure(port: &File) -> io::Result<()> {
+    let fd = port.as_raw_fd();
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 5;
+        if libc::cfsetispeed(&mut termios, libc::B500000) != 0 || libc::cfsetospeed(&mut termios, libc::B500000) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::tcflush(fd, libc::TCIOFLUSH);
+    }
+    
+    Ok(())
+}
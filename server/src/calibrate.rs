@@ -0,0 +1,293 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crossbeam_queue::SegQueue;
+use crate::audio::{CHANNELS, SAMPLE_RATE};
+use crate::capture::CaptureSource;
+use crate::convert::Converter;
+
+/// Mean luminance (0-255) a frame must rise above to count as a flash, and fall below before the next one.
+const FLASH_ON: f32 = 160.0;
+const FLASH_OFF: f32 = 96.0;
+/// RMS level (0.0-1.0) the audio must rise above to count as a beep, and fall below before the next one.
+const BEEP_ON: f32 = 0.05;
+const BEEP_OFF: f32 = 0.02;
+/// Length of the blocks audio levels are measured over.
+const BLOCK_SECS: f64 = 0.002;
+/// Onsets closer together than this are treated as one, to ignore flicker and ringing.
+const DEBOUNCE_SECS: f64 = 0.1;
+/// Largest offset that will be searched for. Flashes should repeat less often than twice this.
+const MAX_OFFSET_SECS: f64 = 0.5;
+/// How close a beep must be to where it's expected, to count as matching a flash.
+const MATCH_SECS: f64 = 0.015;
+/// Fewest matched flash/beep pairs needed for a trustworthy estimate.
+pub const MIN_MATCHES: usize = 5;
+
+
+/// Result of a calibration.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Estimate {
+    /// Seconds that audio is captured later than the video it belongs to. Suitable for `AvAligner`.
+    pub offset: f64,
+    /// Number of flashes that were matched with a beep.
+    pub matches: usize,
+    /// Median distance of each matched pair from the estimated offset, in seconds.
+    pub jitter: f64,
+}
+
+
+/// Measures the offset between the video and audio capture paths, by watching for a pattern of
+/// simultaneous screen flashes and beeps, such as those produced by a simple test ROM.
+/// 
+/// Frames and audio are fed in along with their capture times (see `Clock`), the same way they are
+/// fed into `AvAligner`, so synthetic streams with a known offset can be used just as well as live ones.
+pub struct Calibrator {
+    sample_rate: f64,
+    channels: usize,
+    
+    flash_on: bool,
+    flashes: Vec<f64>,
+    
+    beep_on: bool,
+    beeps: Vec<f64>,
+    block: Vec<f32>,
+    block_start: f64,
+}
+impl Calibrator {
+    pub fn new(sample_rate: u32, channels: usize) -> Self { Self {
+        sample_rate: sample_rate as f64,
+        channels,
+        flash_on: false,
+        flashes: vec![],
+        beep_on: false,
+        beeps: vec![],
+        block: vec![],
+        block_start: 0.0,
+    }}
+    
+    /// Checks a packed RGB frame, captured at `captured_at`, for the start of a flash.
+    pub fn push_frame(&mut self, captured_at: f64, rgb: &[u8]) {
+        let pixels = (rgb.len() / 3).max(1);
+        let luma = rgb.chunks_exact(3)
+            .map(|px| 0.299 * px[0] as f32 + 0.587 * px[1] as f32 + 0.114 * px[2] as f32)
+            .sum::<f32>() / pixels as f32;
+        
+        if !self.flash_on && luma > FLASH_ON {
+            self.flash_on = true;
+            onset(&mut self.flashes, captured_at);
+        } else if self.flash_on && luma < FLASH_OFF {
+            self.flash_on = false;
+        }
+    }
+    
+    /// Checks interleaved audio samples, the first of which was captured at `captured_at`, for the start of a beep.
+    pub fn push_audio(&mut self, captured_at: f64, samples: &[f32]) {
+        let block_len = ((BLOCK_SECS * self.sample_rate) as usize).max(1) * self.channels;
+        for (i, sample) in samples.iter().enumerate() {
+            if self.block.is_empty() {
+                self.block_start = captured_at + (i / self.channels) as f64 / self.sample_rate;
+            }
+            self.block.push(*sample);
+            if self.block.len() < block_len { continue }
+            
+            let rms = (self.block.iter().map(|sample| sample * sample).sum::<f32>() / self.block.len() as f32).sqrt();
+            if !self.beep_on && rms > BEEP_ON {
+                self.beep_on = true;
+                onset(&mut self.beeps, self.block_start);
+            } else if self.beep_on && rms < BEEP_OFF {
+                self.beep_on = false;
+            }
+            self.block.clear();
+        }
+    }
+    
+    pub fn flashes(&self) -> &[f64] { &self.flashes }
+    
+    pub fn beeps(&self) -> &[f64] { &self.beeps }
+    
+    /// Estimates the offset by cross-correlating the flash and beep onset times.
+    /// 
+    /// Every flash/beep pair within `MAX_OFFSET_SECS` of each other proposes an offset, which is scored
+    /// by how many flashes have a beep at that offset. The best offset is then refined to the median of
+    /// its matched pairs. Returns `None` if fewer than `MIN_MATCHES` pairs agree.
+    pub fn estimate(&self) -> Option<Estimate> {
+        let matched = |offset: f64| -> Vec<f64> {
+            self.flashes.iter().filter_map(|flash| {
+                self.beeps.iter()
+                    .map(|beep| beep - flash)
+                    .filter(|diff| (diff - offset).abs() <= MATCH_SECS)
+                    .min_by(|a, b| (a - offset).abs().total_cmp(&(b - offset).abs()))
+            }).collect()
+        };
+        
+        let mut best: Option<(usize, f64)> = None;
+        for flash in &self.flashes {
+            for beep in &self.beeps {
+                let offset = beep - flash;
+                if offset.abs() > MAX_OFFSET_SECS { continue }
+                
+                let score = matched(offset).len();
+                let better = match best {
+                    Some((best_score, best_offset)) => score > best_score || (score == best_score && offset.abs() < best_offset.abs()),
+                    None => true,
+                };
+                if better {
+                    best = Some((score, offset));
+                }
+            }
+        }
+        
+        let (_, offset) = best?;
+        let diffs = matched(offset);
+        if diffs.len() < MIN_MATCHES { return None }
+        
+        let offset = median(diffs.clone());
+        let jitter = median(diffs.iter().map(|diff| (diff - offset).abs()).collect());
+        
+        Some(Estimate {
+            offset,
+            matches: diffs.len(),
+            jitter,
+        })
+    }
+}
+
+fn onset(onsets: &mut Vec<f64>, at: f64) {
+    if onsets.last().map(|last| at - last >= DEBOUNCE_SECS).unwrap_or(true) {
+        onsets.push(at);
+    }
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+
+/// Watches the live capture for `duration`, logging progress along the way, and returns the estimated offset.
+pub fn run(source: &mut dyn CaptureSource, converter: &Converter, samples: Arc<SegQueue<(f64, Vec<f32>)>>, duration: Duration) -> Option<Estimate> {
+    info!("Calibrating for {:.0}s. Start a ROM that repeatedly flashes the screen white while beeping, about once per second.", duration.as_secs_f64());
+    
+    let mut calibrator = Calibrator::new(SAMPLE_RATE, CHANNELS);
+    // Fields are checked individually rather than deinterlaced, for finer timing
+    let mut rgb_buf = vec![0u8; converter.frame_len()];
+    
+    let start = Instant::now();
+    let mut last_progress = Instant::now();
+    while start.elapsed() < duration {
+        let frame = match source.next_frame() {
+            Ok(frame) => frame,
+            Err(err) => {
+                error!("Failed to capture frame: {}", err);
+                return None;
+            }
+        };
+        
+        if let Err(err) = converter.convert(frame.data, &mut rgb_buf) {
+            warn!("Dropped a captured frame: {}", err);
+            continue;
+        }
+        calibrator.push_frame(frame.captured_at, &rgb_buf);
+        while let Some((captured_at, sample_buf)) = samples.pop() {
+            calibrator.push_audio(captured_at, &sample_buf);
+        }
+        
+        if last_progress.elapsed() > Duration::from_secs(5) {
+            info!("Seen {} flashes and {} beeps so far.", calibrator.flashes().len(), calibrator.beeps().len());
+            last_progress = Instant::now();
+        }
+    }
+    
+    let estimate = calibrator.estimate();
+    match estimate {
+        Some(estimate) => info!("Audio is captured {:.1}ms after video (matched {} of {} flashes, jitter {:.1}ms).",
+            estimate.offset * 1000.0, estimate.matches, calibrator.flashes().len(), estimate.jitter * 1000.0),
+        None => error!("Could not match at least {} flashes with beeps ({} flashes and {} beeps seen).",
+            MIN_MATCHES, calibrator.flashes().len(), calibrator.beeps().len()),
+    }
+    
+    estimate
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const FRAME_RATE: f64 = 60.0;
+    /// Seconds between flashes, and how long each flash and beep lasts.
+    const PERIOD_SECS: f64 = 1.0;
+    const PULSE_SECS: f64 = 0.1;
+    /// Audio is pushed in chunks of this many samples per channel, like the capture thread does.
+    const CHUNK_LEN: usize = 441;
+    
+    /// Feeds `secs` of synthetic capture into a calibrator: small frames that flash white once per
+    /// `PERIOD_SECS`, and a beep for each flash that starts `offset` seconds later.
+    fn calibrate(secs: f64, offset: f64) -> Calibrator {
+        let mut calibrator = Calibrator::new(SAMPLE_RATE, CHANNELS);
+        let pulse = |at: f64, delay: f64| {
+            let since = (at - delay).rem_euclid(PERIOD_SECS);
+            (0.0..secs).contains(&(at - delay)) && since < PULSE_SECS
+        };
+        
+        // Frames start late enough that the first beep of a negative offset isn't cut off
+        let start = MAX_OFFSET_SECS;
+        for frame in 0..((secs * FRAME_RATE) as usize) {
+            let at = start + frame as f64 / FRAME_RATE;
+            let level = if pulse(at, start) { 255 } else { 16 };
+            calibrator.push_frame(at, &[level; 8 * 8 * 3]);
+        }
+        
+        let sample_rate = SAMPLE_RATE as f64;
+        for chunk in 0..((secs + start) * sample_rate) as usize / CHUNK_LEN {
+            let chunk_at = (chunk * CHUNK_LEN) as f64 / sample_rate;
+            let samples: Vec<f32> = (0..CHUNK_LEN).flat_map(|i| {
+                let at = chunk_at + i as f64 / sample_rate;
+                let sample = if pulse(at, start + offset) { 0.5 * (at * 1000.0 * std::f64::consts::TAU).sin() as f32 } else { 0.0 };
+                [sample; CHANNELS]
+            }).collect();
+            calibrator.push_audio(chunk_at, &samples);
+        }
+        
+        calibrator
+    }
+    
+    #[test]
+    fn recovers_a_known_offset() {
+        for offset in [0.0, 0.035, 0.120, -0.045] {
+            let calibrator = calibrate(10.0, offset);
+            assert_eq!(calibrator.flashes().len(), 10);
+            assert_eq!(calibrator.beeps().len(), 10);
+            
+            let estimate = calibrator.estimate().unwrap();
+            assert!((estimate.offset - offset).abs() <= MATCH_SECS, "expected {}, estimated {}", offset, estimate.offset);
+            assert_eq!(estimate.matches, 10);
+            assert!(estimate.jitter <= MATCH_SECS);
+        }
+    }
+    
+    #[test]
+    fn too_few_flashes_give_no_estimate() {
+        let calibrator = calibrate((MIN_MATCHES - 1) as f64, 0.05);
+        
+        assert_eq!(calibrator.estimate(), None);
+    }
+    
+    #[test]
+    fn silence_gives_no_estimate() {
+        let mut calibrator = Calibrator::new(SAMPLE_RATE, CHANNELS);
+        for frame in 0..600 {
+            let level = if frame % 60 < 6 { 255 } else { 16 };
+            calibrator.push_frame(frame as f64 / FRAME_RATE, &[level; 8 * 8 * 3]);
+        }
+        calibrator.push_audio(0.0, &vec![0.0; SAMPLE_RATE as usize * 10 * CHANNELS]);
+        
+        assert_eq!(calibrator.flashes().len(), 10);
+        assert!(calibrator.beeps().is_empty());
+        assert_eq!(calibrator.estimate(), None);
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use v4l::io::traits::OutputStream;
+use crate::config::CaptureConfig;
+use crate::convert::{Converter, PixelFormat};
+use crate::deinterlace::Layout;
+use crate::pattern::PatternSource;
+use crate::sync::Clock;
+use crate::video::{self, VideoStream};
+use crate::watchdog::STALL_TIMEOUT;
+
+/// NTSC frame rate, used if the capture device doesn't report its own, and by sources without one.
+pub const DEFAULT_FRAME_RATE: f64 = 30000.0 / 1001.0;
+
+
+/// Where captured frames come from.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// A V4L capture device.
+    V4l,
+    /// A generated test pattern, for running without any hardware.
+    Pattern,
+    /// Frames replayed from a file or a directory of images, over and over.
+    File,
+}
+impl FromStr for Source {
+    type Err = ();
+    
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "v4l" => Ok(Self::V4l),
+            "pattern" => Ok(Self::Pattern),
+            "file" => Ok(Self::File),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A buffer as captured, before being converted into RGB.
+pub struct CapturedFrame<'a> {
+    /// Pixels in the source's format (see `CaptureSource::converter()`).
+    pub data: &'a [u8],
+    pub layout: Layout,
+    /// When the frame was captured, according to the `Clock` the source was opened with.
+    pub captured_at: f64,
+}
+
+/// Anything the server can capture video from.
+pub trait CaptureSource {
+    /// Blocks until the next frame has been captured. Errors once the source stops working, e.g. when
+    /// the device is unplugged, after which it should be reopened.
+    fn next_frame(&mut self) -> io::Result<CapturedFrame<'_>>;
+    
+    /// Converter for the pixel format and size of the captured frames.
+    fn converter(&self) -> Converter;
+    
+    /// Frames per second being captured, if known.
+    fn frame_rate(&self) -> Option<f64>;
+}
+
+/// Opens the configured capture source. Frames are timestamped with `clock`.
+pub fn open(config: &CaptureConfig, clock: Clock) -> Result<Box<dyn CaptureSource>, String> {
+    let frame_rate = config.frame_rate.unwrap_or(DEFAULT_FRAME_RATE);
+    
+    Ok(match config.source {
+        Source::V4l => Box::new(V4lSource::open(config, clock).map_err(|err| format!("failed to open the capture device: {}", err))?),
+        Source::Pattern => {
+            info!("Generating a {}x{} test pattern.", config.width, config.height);
+            Box::new(PatternSource::new(config.width, config.height, frame_rate, clock))
+        },
+        Source::File => {
+            let path = config.file.as_ref().ok_or("capture.file must be set to capture from a file")?;
+            Box::new(FileSource::open(path, config, frame_rate, clock).map_err(|err| format!("failed to open {}: {}", path.display(), err))?)
+        },
+    })
+}
+
+
+/// Captures from a V4L device. Waiting for a frame times out once the device has stalled (see `STALL_TIMEOUT`).
+pub struct V4lSource {
+    video: VideoStream<'static>,
+    converter: Converter,
+    clock: Clock,
+}
+impl V4lSource {
+    pub fn open(config: &CaptureConfig, clock: Clock) -> Result<Self, video::Error> {
+        let mut video = VideoStream::open(config)?;
+        video.stream.set_timeout(STALL_TIMEOUT);
+        let converter = video.converter()?;
+        
+        Ok(Self {
+            video,
+            converter,
+            clock,
+        })
+    }
+}
+impl CaptureSource for V4lSource {
+    fn next_frame(&mut self) -> io::Result<CapturedFrame<'_>> {
+        let (buf, meta) = self.video.stream.next()?;
+        
+        Ok(CapturedFrame {
+            data: video::used(buf, meta),
+            layout: Layout::from_v4l(meta.field),
+            captured_at: self.clock.now(),
+        })
+    }
+    
+    fn converter(&self) -> Converter { self.converter.clone() }
+    
+    fn frame_rate(&self) -> Option<f64> { self.video.frame_rate() }
+}
+
+
+enum Replay {
+    /// Frames stored one after another, each taking up the same number of bytes.
+    Raw(File),
+    /// One image per frame.
+    Images(Vec<PathBuf>, usize),
+}
+
+/// Replays frames from disk, looping back to the first one after the last.
+/// 
+/// The path is either a directory of JPEG images, replayed in order of their names, or a raw video
+/// file holding whole frames one after another, in the configured pixel format (RGB3 if unset) and size.
+pub struct FileSource {
+    replay: Replay,
+    buf: Vec<u8>,
+    converter: Converter,
+    frame_rate: f64,
+    pacer: Pacer,
+    clock: Clock,
+}
+impl FileSource {
+    pub fn open(path: &Path, config: &CaptureConfig, frame_rate: f64, clock: Clock) -> io::Result<Self> {
+        let (replay, format) = if path.is_dir() {
+            let mut images: Vec<PathBuf> = std::fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg")).unwrap_or(false))
+                .collect();
+            if images.is_empty() {
+                return Err(io::Error::new(ErrorKind::NotFound, "the directory holds no JPEG images"));
+            }
+            images.sort();
+            info!("Replaying {} images from {}.", images.len(), path.display());
+            
+            (Replay::Images(images, 0), PixelFormat::Mjpg)
+        } else {
+            let format = match &config.format {
+                Some(format) => PixelFormat::from_fourcc(&video::fourcc_bytes(format))
+                    .filter(|format| *format != PixelFormat::Mjpg)
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("can't replay raw {} video, MJPEG must be replayed from a directory of images", format)))?,
+                None => PixelFormat::Rgb3,
+            };
+            let file = File::open(path)?;
+            info!("Replaying raw {}x{} {} video from {}.", config.width, config.height, String::from_utf8_lossy(&format.fourcc()), path.display());
+            
+            (Replay::Raw(file), format)
+        };
+        
+        let converter = Converter::new(format, config.width, config.height, 0);
+        let buf = match converter.buffer_len() {
+            Some(len) => vec![0; len],
+            None => vec![],
+        };
+        if let Replay::Raw(file) = &replay {
+            if file.metadata()?.len() < buf.len() as u64 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "the file is smaller than a single frame"));
+            }
+        }
+        
+        Ok(Self {
+            replay,
+            buf,
+            converter,
+            frame_rate,
+            pacer: Pacer::new(frame_rate),
+            clock,
+        })
+    }
+}
+impl CaptureSource for FileSource {
+    fn next_frame(&mut self) -> io::Result<CapturedFrame<'_>> {
+        self.pacer.wait();
+        
+        match &mut self.replay {
+            Replay::Raw(file) => if let Err(err) = file.read_exact(&mut self.buf) {
+                if err.kind() != ErrorKind::UnexpectedEof { return Err(err) }
+                
+                // Back to the start, dropping any partial frame at the end
+                file.seek(SeekFrom::Start(0))?;
+                file.read_exact(&mut self.buf)?;
+            },
+            Replay::Images(images, next) => {
+                self.buf = std::fs::read(&images[*next])?;
+                *next = (*next + 1) % images.len();
+            },
+        }
+        
+        Ok(CapturedFrame {
+            data: &self.buf,
+            layout: Layout::Progressive,
+            captured_at: self.clock.now(),
+        })
+    }
+    
+    fn converter(&self) -> Converter { self.converter.clone() }
+    
+    fn frame_rate(&self) -> Option<f64> { Some(self.frame_rate) }
+}
+
+
+/// Paces sources that aren't held back by hardware, so they produce frames at their frame rate.
+pub struct Pacer {
+    interval: Duration,
+    next: Instant,
+}
+impl Pacer {
+    pub fn new(frame_rate: f64) -> Self { Self {
+        interval: Duration::from_secs_f64(1.0 / frame_rate),
+        next: Instant::now(),
+    }}
+    
+    /// Sleeps until the next frame is due. After falling behind by more than a frame, the pace restarts
+    /// from now, rather than catching up with a burst of frames.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            std::thread::sleep(self.next - now);
+        } else if now - self.next > self.interval {
+            self.next = now;
+        }
+        self.next += self.interval;
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::BTreeMap;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use clap::ArgMatches;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error as _;
+use toml_edit::{DocumentMut, Item, Table, value};
+use remote64_common::Feature;
+use remote64_common::auth::StoredSecret;
+use remote64_common::network::Limits;
+use remote64_common::util::{parse_duration, parse_size};
+use crate::audio;
+use crate::capture::Source;
+use crate::deinterlace::Mode;
+use crate::recording::REC_PATH;
+use crate::transform::{self, Crop};
+
+/// Default location of the server's configuration file.
+pub const CONFIG_PATH: &str = "remote64-server.toml";
+
+
+/// Everything an operator can configure about the server.
+/// 
+/// Loaded from a TOML file, where every table and key is optional and falls back to the defaults
+/// below. Command line arguments override whatever the file says.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Features supported by this server.
+    #[serde(deserialize_with = "features")]
+    pub features: Vec<Feature>,
+    /// Command used to load and start an uploaded ROM on the console.
+    pub loader: Option<String>,
+    /// Runs without the preview window, until stopped by SIGINT or SIGTERM.
+    pub headless: bool,
+    pub network: NetworkConfig,
+    pub capture: CaptureConfig,
+    pub output: OutputConfig,
+    pub audio: AudioConfig,
+    pub recording: RecordingConfig,
+    pub queue: QueueConfig,
+    pub board: BoardConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Address and port clients connect to.
+    pub listen: String,
+    /// How often clients are pinged.
+    #[serde(deserialize_with = "duration")]
+    pub ping_interval: Duration,
+    /// Clients that haven't answered a ping for this long are disconnected.
+    #[serde(deserialize_with = "duration")]
+    pub ping_timeout: Duration,
+    /// Largest message accepted from a client, such as an uploaded ROM. Clients sending longer ones are disconnected.
+    #[serde(deserialize_with = "size")]
+    pub max_incoming: u64,
+    /// Largest message sent to a client, such as a batch of frames.
+    #[serde(deserialize_with = "size")]
+    pub max_outgoing: u64,
+    /// Clients that haven't sent anything for this long are disconnected, even if their connection seems open.
+    #[serde(deserialize_with = "duration")]
+    pub read_timeout: Duration,
+    /// Clients are disconnected once sending them anything stalls for this long.
+    #[serde(deserialize_with = "duration")]
+    pub write_timeout: Duration,
+}
+impl Default for NetworkConfig {
+    fn default() -> Self { Self {
+        listen: "0.0.0.0:6400".to_owned(),
+        ping_interval: Duration::from_secs(10),
+        ping_timeout: Duration::from_secs(22),
+        max_incoming: 80 << 20,
+        max_outgoing: 256 << 20,
+        read_timeout: Duration::from_secs(60),
+        write_timeout: Duration::from_secs(30),
+    }}
+}
+impl NetworkConfig {
+    pub fn limits(&self) -> Limits { Limits {
+        max_incoming: self.max_incoming as usize,
+        max_outgoing: self.max_outgoing as usize,
+        read_timeout: Some(self.read_timeout),
+        write_timeout: Some(self.write_timeout),
+    }}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// What to capture from: a V4L device, a generated test pattern, or a file.
+    pub source: Source,
+    /// File or directory of images to replay, with the file source.
+    pub file: Option<PathBuf>,
+    /// Device to capture from, by path, index or part of its name. The first capture device found is used if unset.
+    pub device: Option<String>,
+    /// FourCC of the pixel format to capture in. The first supported format the device offers is used if unset.
+    pub format: Option<String>,
+    pub width: usize,
+    pub height: usize,
+    /// Frames per second to request from the device. Also assumed if the device doesn't report its own.
+    pub frame_rate: Option<f64>,
+    /// How the fields of interlaced video are combined into frames.
+    pub deinterlace: Mode,
+}
+impl Default for CaptureConfig {
+    fn default() -> Self { Self {
+        source: Source::V4l,
+        file: None,
+        device: None,
+        format: None,
+        width: 720,
+        height: 480,
+        frame_rate: None,
+        deinterlace: Mode::Adaptive,
+    }}
+}
+
+/// How captured frames are cropped and scaled, before they are shown, streamed and recorded.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Part of the captured frame to keep: `none`, `auto` to detect and remove black borders, or `WIDTHxHEIGHT+X+Y`.
+    #[serde(deserialize_with = "crop")]
+    pub crop: Crop,
+    /// Resolution frames are scaled to. Derived from the crop and aspect ratio if unset.
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    /// Aspect ratio the picture is shown in, such as `4:3`. The captured pixels are assumed to be square if unset.
+    #[serde(deserialize_with = "opt_aspect")]
+    pub aspect: Option<f64>,
+}
+impl Default for OutputConfig {
+    fn default() -> Self { Self {
+        crop: Crop::None,
+        width: None,
+        height: None,
+        aspect: None,
+    }}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// What to capture audio from: an input device, a generated tone, or a WAV file.
+    pub source: audio::Source,
+    /// WAV file to replay, with the file source.
+    pub file: Option<PathBuf>,
+    /// Part of the name of the audio input to capture from. The default input is used if empty.
+    pub input: String,
+    /// Sample rate and channel count to capture from the input at. Audio is converted into 44.1kHz stereo regardless.
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Whether captured audio is also played on the default output device.
+    pub monitor: bool,
+    /// Milliseconds that audio is captured later than the video it belongs to.
+    pub av_offset_ms: f64,
+}
+impl Default for AudioConfig {
+    fn default() -> Self { Self {
+        source: audio::Source::Device,
+        file: None,
+        input: "pulse".to_owned(),
+        sample_rate: audio::SAMPLE_RATE,
+        channels: audio::CHANNELS as u16,
+        monitor: true,
+        av_offset_ms: 0.0,
+    }}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Directory every session is recorded into.
+    pub path: PathBuf,
+    /// How long a finished session's recording can be downloaded by its client.
+    #[serde(deserialize_with = "duration")]
+    pub expiry: Duration,
+    /// Session recordings older than this are deleted.
+    #[serde(deserialize_with = "opt_duration")]
+    pub max_age: Option<Duration>,
+    /// The oldest session recordings are deleted while all of them together take up more bytes than this.
+    #[serde(deserialize_with = "opt_size")]
+    pub max_size: Option<u64>,
+}
+impl Default for RecordingConfig {
+    fn default() -> Self { Self {
+        path: PathBuf::from(REC_PATH),
+        expiry: Duration::from_secs(3600),
+        max_age: None,
+        // Without any limit, the recordings would eventually fill the disk
+        max_size: Some(50 << 30),
+    }}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Most clients that may be connected and waiting at once. Further clients are turned away.
+    pub max_clients: Option<usize>,
+    /// Most frames kept for the active client to request. Older frames are dropped.
+    pub frame_buffer: usize,
+    /// Longest a client may be serviced for, after which its session is ended and the next client's begins.
+    #[serde(deserialize_with = "opt_duration")]
+    pub session_limit: Option<Duration>,
+}
+impl Default for QueueConfig {
+    fn default() -> Self { Self {
+        max_clients: None,
+        frame_buffer: 60,
+        session_limit: None,
+    }}
+}
+
+/// The controller board, which switches the console's power.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoardConfig {
+    /// Serial port the board is connected to, such as `/dev/ttyUSB0`. The console's power can't be controlled if unset.
+    pub path: Option<PathBuf>,
+}
+
+/// Serves clients over TLS, when a certificate and key are given. Otherwise, over plain TCP.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the server's certificate, followed by any intermediate certificates.
+    pub cert: Option<PathBuf>,
+    /// PEM file with the certificate's private key.
+    pub key: Option<PathBuf>,
+}
+
+/// Who may use the server. Clients authenticate as one of the users by proving they know its secret, which
+/// is never sent over the network (see `remote64_common::auth`).
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Clients must authenticate before joining the queue. Otherwise, authenticating is optional.
+    pub required: bool,
+    /// Users that may authenticate. Every configured user may if unset.
+    pub allow: Option<Vec<String>>,
+    /// Secrets of every user, by name, as printed by the hash-secret command.
+    #[serde(deserialize_with = "users")]
+    pub users: BTreeMap<String, StoredSecret>,
+}
+impl AuthConfig {
+    /// The secret of a user that may authenticate, if it's configured and allowed.
+    pub fn secret(&self, user: &str) -> Option<&StoredSecret> {
+        let allowed = self.allow.as_ref().map(|allow| allow.iter().any(|name| name == user)).unwrap_or(true);
+        if !allowed { return None }
+        
+        self.users.get(user)
+    }
+}
+
+impl Config {
+    /// Reads the configuration file at `path`. A missing file is not an error, and gives the defaults.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No configuration file at {}, using defaults.", path.display());
+                return Ok(Self::default());
+            },
+            Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+        };
+        
+        toml::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+    
+    /// Replaces any settings that were also given as command line arguments.
+    pub fn override_with(&mut self, matches: &ArgMatches) {
+        if let Some(features) = matches.values_of("features") {
+            self.features = features.map(|feat| Feature::from_str(feat).unwrap_or_default()).collect();
+        }
+        if let Some(loader) = matches.value_of("loader") {
+            self.loader = Some(loader.to_owned());
+        }
+        if matches.is_present("headless") {
+            self.headless = true;
+        }
+        if let Some(listen) = matches.value_of("listen") {
+            self.network.listen = listen.to_owned();
+        }
+        if let Some(source) = matches.value_of("capture-source").and_then(|source| Source::from_str(source).ok()) {
+            self.capture.source = source;
+        }
+        if let Some(file) = matches.value_of("capture-file") {
+            self.capture.file = Some(PathBuf::from(file));
+        }
+        if let Some(device) = matches.value_of("capture-device") {
+            self.capture.device = Some(device.to_owned());
+        }
+        if let Some(format) = matches.value_of("capture-format") {
+            self.capture.format = Some(format.to_owned());
+        }
+        if let Some(mode) = matches.value_of("deinterlace").and_then(|mode| Mode::from_str(mode).ok()) {
+            self.capture.deinterlace = mode;
+        }
+        if let Some(crop) = matches.value_of("crop").and_then(|crop| Crop::from_str(crop).ok()) {
+            self.output.crop = crop;
+        }
+        if let Some((width, height)) = matches.value_of("output-size").and_then(transform::parse_size) {
+            self.output.width = Some(width);
+            self.output.height = Some(height);
+        }
+        if let Some(aspect) = matches.value_of("aspect").and_then(transform::parse_aspect) {
+            self.output.aspect = Some(aspect);
+        }
+        if let Some(source) = matches.value_of("audio-source").and_then(|source| audio::Source::from_str(source).ok()) {
+            self.audio.source = source;
+        }
+        if let Some(file) = matches.value_of("audio-file") {
+            self.audio.file = Some(PathBuf::from(file));
+        }
+        if let Some(input) = matches.value_of("audio-input") {
+            self.audio.input = input.to_owned();
+        }
+        if matches.is_present("no-monitor") {
+            self.audio.monitor = false;
+        }
+        if let Some(offset) = matches.value_of("av-offset").and_then(|ms| ms.parse().ok()) {
+            self.audio.av_offset_ms = offset;
+        }
+        if let Some(path) = matches.value_of("recording-path") {
+            self.recording.path = PathBuf::from(path);
+        }
+        if let Some(expiry) = matches.value_of("recording-expiry").and_then(parse_duration) {
+            self.recording.expiry = expiry;
+        }
+        if let Some(max_age) = matches.value_of("retention-max-age").and_then(parse_duration) {
+            self.recording.max_age = Some(max_age);
+        }
+        if let Some(max_size) = matches.value_of("retention-max-size").and_then(parse_size) {
+            self.recording.max_size = Some(max_size);
+        }
+        if let Some(limit) = matches.value_of("session-limit").and_then(parse_duration) {
+            self.queue.session_limit = Some(limit);
+        }
+        if let Some(path) = matches.value_of("board") {
+            self.board.path = Some(PathBuf::from(path));
+        }
+    }
+    
+    /// Checks that the settings make sense together, describing the first problem found.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Err(err) = self.network.listen.to_socket_addrs() {
+            return Err(format!("network.listen `{}` is not a valid address: {}", self.network.listen, err));
+        }
+        if self.network.ping_interval.is_zero() {
+            return Err("network.ping_interval must be longer than 0s".to_owned());
+        }
+        if self.network.ping_timeout <= self.network.ping_interval {
+            return Err("network.ping_timeout must be longer than network.ping_interval".to_owned());
+        }
+        // Messages' lengths are sent as 32 bits
+        if !(1..=u32::MAX as u64).contains(&self.network.max_incoming) || !(1..=u32::MAX as u64).contains(&self.network.max_outgoing) {
+            return Err("network.max_incoming and network.max_outgoing must be between 1B and 4G".to_owned());
+        }
+        // Clients only send pongs while idle, so they'd be timed out between pings
+        if self.network.read_timeout <= self.network.ping_interval {
+            return Err("network.read_timeout must be longer than network.ping_interval".to_owned());
+        }
+        if self.network.write_timeout.is_zero() {
+            return Err("network.write_timeout must be longer than 0s".to_owned());
+        }
+        
+        if self.capture.source == Source::File && self.capture.file.is_none() {
+            return Err("capture.file must be set to capture from a file".to_owned());
+        }
+        if let Some(format) = &self.capture.format {
+            if format.is_empty() || format.len() > 4 || !format.is_ascii() {
+                return Err(format!("capture.format must be a FourCC code such as RGBP or YUYV, not {:?}", format));
+            }
+        }
+        // Encoded video must have even dimensions
+        if self.capture.width == 0 || self.capture.height == 0 || !self.capture.width.is_multiple_of(2) || !self.capture.height.is_multiple_of(2) {
+            return Err(format!("capture.width and capture.height must be even and above 0, not {}x{}", self.capture.width, self.capture.height));
+        }
+        if let Some(frame_rate) = self.capture.frame_rate {
+            if !(frame_rate > 0.0 && frame_rate.is_finite()) {
+                return Err(format!("capture.frame_rate must be above 0, not {}", frame_rate));
+            }
+        }
+        
+        if let Crop::Fixed(rect) = self.output.crop {
+            if rect.width == 0 || rect.height == 0 || !rect.width.is_multiple_of(2) || !rect.height.is_multiple_of(2) {
+                return Err(format!("output.crop must have an even size above 0, not {}x{}", rect.width, rect.height));
+            }
+            if rect.x + rect.width > self.capture.width || rect.y + rect.height > self.capture.height {
+                return Err(format!("output.crop {}x{}+{}+{} doesn't fit within the {}x{} capture", rect.width, rect.height, rect.x, rect.y, self.capture.width, self.capture.height));
+            }
+        }
+        match (self.output.width, self.output.height) {
+            (Some(width), Some(height)) => if width == 0 || height == 0 || !width.is_multiple_of(2) || !height.is_multiple_of(2) {
+                return Err(format!("output.width and output.height must be even and above 0, not {}x{}", width, height));
+            },
+            (None, None) => (),
+            _ => return Err("output.width and output.height must be set together".to_owned()),
+        }
+        
+        if self.audio.source == audio::Source::File && self.audio.file.is_none() {
+            return Err("audio.file must be set to capture from a file".to_owned());
+        }
+        if self.audio.sample_rate == 0 || self.audio.channels == 0 {
+            return Err(format!("audio.sample_rate and audio.channels must be above 0, not {} and {}", self.audio.sample_rate, self.audio.channels));
+        }
+        if !self.audio.av_offset_ms.is_finite() {
+            return Err("audio.av_offset_ms must be a number of milliseconds".to_owned());
+        }
+        
+        if self.recording.path.as_os_str().is_empty() {
+            return Err("recording.path must not be empty".to_owned());
+        }
+        
+        if self.queue.max_clients == Some(0) {
+            return Err("queue.max_clients must be at least 1".to_owned());
+        }
+        if self.queue.frame_buffer == 0 {
+            return Err("queue.frame_buffer must be at least 1".to_owned());
+        }
+        if self.queue.session_limit.map(|limit| limit.is_zero()).unwrap_or(false) {
+            return Err("queue.session_limit must be longer than 0s".to_owned());
+        }
+        
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return Err("tls.cert and tls.key must be set together".to_owned());
+        }
+        
+        if self.auth.required && self.auth.users.is_empty() {
+            return Err("auth.users must have at least one user to require authentication".to_owned());
+        }
+        // Names are sent with a single byte length
+        if let Some(user) = self.auth.users.keys().find(|user| user.is_empty() || user.len() > u8::MAX as usize) {
+            return Err(format!("auth.users has a name that isn't 1 to 255 bytes long: {:?}", user));
+        }
+        if let Some(user) = self.auth.allow.iter().flatten().find(|user| !self.auth.users.contains_key(*user)) {
+            return Err(format!("auth.allow has {:?}, which isn't in auth.users", user));
+        }
+        
+        if self.features.contains(&Feature::Invalid) {
+            return Err("features contains an unknown feature".to_owned());
+        }
+        if self.loader.as_deref().map(|loader| loader.trim().is_empty()).unwrap_or(false) {
+            return Err("loader must not be empty".to_owned());
+        }
+        
+        Ok(())
+    }
+}
+
+fn features<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Feature>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?.iter()
+        .map(|feat| Feature::from_str(feat).map_err(|_| D::Error::custom(format!("unknown feature `{}`, expected LivePlayback, AudioRecording or InputHandling", feat))))
+        .collect()
+}
+
+fn users<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, StoredSecret>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?.into_iter()
+        .map(|(user, secret)| match StoredSecret::from_str(&secret) {
+            Ok(secret) => Ok((user, secret)),
+            Err(()) => Err(D::Error::custom(format!("invalid secret for user `{}`, expected ITERATIONS:SALT:KEY as printed by the hash-secret command", user))),
+        })
+        .collect()
+}
+
+fn crop<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Crop, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    Crop::from_str(&text).map_err(|_| D::Error::custom(format!("invalid crop `{}`, expected none, auto or e.g. 704x480+8+0", text)))
+}
+
+fn opt_aspect<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    transform::parse_aspect(&text).map(Some).ok_or_else(|| D::Error::custom(format!("invalid aspect ratio `{}`, expected e.g. 4:3", text)))
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_duration(&text).ok_or_else(|| D::Error::custom(format!("invalid duration `{}`, expected e.g. 500ms, 30s or 1h", text)))
+}
+
+fn opt_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    duration(deserializer).map(Some)
+}
+
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_size(&text).ok_or_else(|| D::Error::custom(format!("invalid size `{}`, expected e.g. 500M or 20G", text)))
+}
+
+fn opt_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    size(deserializer).map(Some)
+}
+
+
+/// Writes the A/V offset (in milliseconds) into a configuration file, creating it if needed. Anything
+/// else in the file, including comments, is left as it was.
+pub fn save_av_offset<P: AsRef<Path>>(path: P, offset_ms: f64) -> Result<(), String> {
+    let path = path.as_ref();
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+    };
+    let mut doc = text.parse::<DocumentMut>().map_err(|err| format!("failed to parse {}: {}", path.display(), err))?;
+    
+    let audio = doc.entry("audio").or_insert(Item::Table(Table::new()));
+    let audio = audio.as_table_mut().ok_or(format!("`audio` in {} is not a table", path.display()))?;
+    audio["av_offset_ms"] = value((offset_ms * 10.0).round() / 10.0);
+    
+    std::fs::write(path, doc.to_string()).map_err(|err| format!("failed to write {}: {}", path.display(), err))
+}
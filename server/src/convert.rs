@@ -0,0 +1,288 @@
+use std::fmt;
+
+/// Pixel formats the server can capture in, named after their V4L FourCC codes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelFormat {
+    /// RGB565, 2 bytes per pixel, little-endian.
+    Rgbp,
+    /// Packed RGB, 3 bytes per pixel.
+    Rgb3,
+    /// Packed YUV 4:2:2, ordered Y0 U Y1 V.
+    Yuyv,
+    /// Packed YUV 4:2:2, ordered U Y0 V Y1.
+    Uyvy,
+    /// Planar YUV 4:2:0, a full Y plane followed by a half-size plane of interleaved U and V.
+    Nv12,
+    /// A JPEG image per frame.
+    Mjpg,
+}
+impl PixelFormat {
+    /// Every supported format, in order of preference. Uncompressed RGB needs the least work, and
+    /// MJPEG the most.
+    pub const ALL: [PixelFormat; 6] = [Self::Rgbp, Self::Rgb3, Self::Yuyv, Self::Uyvy, Self::Nv12, Self::Mjpg];
+    
+    pub fn from_fourcc(fourcc: &[u8; 4]) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| &format.fourcc() == fourcc)
+    }
+    
+    pub fn fourcc(&self) -> [u8; 4] {
+        match self {
+            Self::Rgbp => *b"RGBP",
+            Self::Rgb3 => *b"RGB3",
+            Self::Yuyv => *b"YUYV",
+            Self::Uyvy => *b"UYVY",
+            Self::Nv12 => *b"NV12",
+            Self::Mjpg => *b"MJPG",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConvertError {
+    /// The captured buffer is smaller than a frame in this format and size.
+    ShortBuffer(usize, usize),
+    Jpeg(String),
+    /// A JPEG frame didn't have the size the device was set up with.
+    WrongSize(u32, u32),
+}
+use ConvertError::*;
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShortBuffer(got, expected) => write!(f, "buffer holds {} bytes, expected at least {}", got, expected),
+            Jpeg(err) => write!(f, "failed to decode JPEG: {}", err),
+            WrongSize(width, height) => write!(f, "JPEG is {}x{}, not the configured size", width, height),
+        }
+    }
+}
+
+
+/// Converts captured frames into packed RGB (u8 u8 u8), the format frames are handled in everywhere
+/// else in the server.
+#[derive(Clone, Debug)]
+pub struct Converter {
+    format: PixelFormat,
+    width: usize,
+    height: usize,
+    /// Bytes per row of the captured buffer, which may include padding. For NV12 this is the row
+    /// length of both planes.
+    stride: usize,
+}
+impl Converter {
+    /// `stride` may be 0 if the device doesn't report it, in which case rows are assumed to be unpadded.
+    pub fn new(format: PixelFormat, width: usize, height: usize, stride: usize) -> Self {
+        let min_stride = match format {
+            PixelFormat::Rgbp | PixelFormat::Yuyv | PixelFormat::Uyvy => width * 2,
+            PixelFormat::Rgb3 => width * 3,
+            PixelFormat::Nv12 | PixelFormat::Mjpg => width,
+        };
+        
+        Self {
+            format,
+            width,
+            height,
+            stride: stride.max(min_stride),
+        }
+    }
+    
+    /// Size of a converted frame, in bytes.
+    pub fn frame_len(&self) -> usize {
+        self.width * self.height * 3
+    }
+    
+    /// Size of a captured buffer, in bytes, including any padding. Unknown for compressed formats.
+    pub fn buffer_len(&self) -> Option<usize> {
+        match self.format {
+            PixelFormat::Nv12 => Some(self.stride * self.height + self.stride * self.height.div_ceil(2)),
+            PixelFormat::Mjpg => None,
+            _ => Some(self.stride * self.height),
+        }
+    }
+    
+    /// Converts a captured buffer into `rgb`, which must hold `frame_len()` bytes.
+    pub fn convert(&self, src: &[u8], rgb: &mut [u8]) -> Result<(), ConvertError> {
+        let expected = match self.format {
+            PixelFormat::Nv12 => self.stride * self.height + self.stride * self.height.div_ceil(2) - (self.stride - self.width),
+            PixelFormat::Mjpg => 0,
+            _ => self.stride * (self.height - 1) + self.width * self.bytes_per_pixel(),
+        };
+        if src.len() < expected {
+            return Err(ShortBuffer(src.len(), expected));
+        }
+        
+        match self.format {
+            PixelFormat::Rgbp => self.rows(src, rgb, |row, out| {
+                for (px, out) in row.chunks_exact(2).zip(out.chunks_exact_mut(3)) {
+                    out[0] = px[1] & 0b11111000;
+                    out[1] = ((px[1] & 0b00000111) << 5) | ((px[0] & 0b11100000) >> 3);
+                    out[2] = (px[0] & 0b00011111) << 3;
+                }
+            }),
+            PixelFormat::Rgb3 => self.rows(src, rgb, |row, out| out.copy_from_slice(row)),
+            PixelFormat::Yuyv => self.rows(src, rgb, |row, out| {
+                for (px, out) in row.chunks_exact(4).zip(out.chunks_mut(6)) {
+                    yuv422(px[0], px[2], px[1], px[3], out);
+                }
+            }),
+            PixelFormat::Uyvy => self.rows(src, rgb, |row, out| {
+                for (px, out) in row.chunks_exact(4).zip(out.chunks_mut(6)) {
+                    yuv422(px[1], px[3], px[0], px[2], out);
+                }
+            }),
+            PixelFormat::Nv12 => {
+                let (luma, chroma) = src.split_at(self.stride * self.height);
+                for y in 0..self.height {
+                    let luma_row = &luma[(y * self.stride)..][..self.width];
+                    let chroma_row = &chroma[((y / 2) * self.stride)..];
+                    let out = &mut rgb[(y * self.width * 3)..][..(self.width * 3)];
+                    for (x, out) in out.chunks_exact_mut(3).enumerate() {
+                        let uv = (x / 2) * 2;
+                        yuv_to_rgb(luma_row[x], chroma_row[uv], chroma_row[uv + 1], out);
+                    }
+                }
+            },
+            PixelFormat::Mjpg => self.jpeg(src, rgb)?,
+        }
+        
+        Ok(())
+    }
+    
+    fn bytes_per_pixel(&self) -> usize {
+        match self.format {
+            PixelFormat::Rgb3 => 3,
+            PixelFormat::Rgbp | PixelFormat::Yuyv | PixelFormat::Uyvy => 2,
+            PixelFormat::Nv12 | PixelFormat::Mjpg => 1,
+        }
+    }
+    
+    /// Runs `convert_row` on each row of a packed format, skipping any padding at the end of the rows.
+    fn rows<F: Fn(&[u8], &mut [u8])>(&self, src: &[u8], rgb: &mut [u8], convert_row: F) {
+        let row_len = self.width * self.bytes_per_pixel();
+        for (y, out) in rgb.chunks_exact_mut(self.width * 3).take(self.height).enumerate() {
+            convert_row(&src[(y * self.stride)..][..row_len], out);
+        }
+    }
+    
+    fn jpeg(&self, src: &[u8], rgb: &mut [u8]) -> Result<(), ConvertError> {
+        let mut decoder = jpeg_decoder::Decoder::new(src);
+        let pixels = decoder.decode().map_err(|err| Jpeg(err.to_string()))?;
+        let info = decoder.info().ok_or_else(|| Jpeg("missing image info".to_owned()))?;
+        if info.width as usize != self.width || info.height as usize != self.height {
+            return Err(WrongSize(info.width as u32, info.height as u32));
+        }
+        
+        match info.pixel_format {
+            jpeg_decoder::PixelFormat::RGB24 => rgb.copy_from_slice(&pixels[..rgb.len()]),
+            jpeg_decoder::PixelFormat::L8 => for (out, luma) in rgb.chunks_exact_mut(3).zip(pixels) {
+                out.fill(luma);
+            },
+            format => return Err(Jpeg(format!("unsupported pixel format {:?}", format))),
+        }
+        
+        Ok(())
+    }
+}
+
+/// Converts a pair of pixels sharing their chroma.
+fn yuv422(y0: u8, y1: u8, u: u8, v: u8, out: &mut [u8]) {
+    yuv_to_rgb(y0, u, v, &mut out[0..3]);
+    if out.len() >= 6 {
+        yuv_to_rgb(y1, u, v, &mut out[3..6]);
+    }
+}
+
+/// Converts a limited range (16-235) BT.601 YUV pixel into RGB, using 8-bit fixed-point coefficients.
+fn yuv_to_rgb(y: u8, u: u8, v: u8, out: &mut [u8]) {
+    let c = y as i32 - 16;
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    
+    out[0] = ((298 * c + 409 * e + 128) >> 8).clamp(0, 255) as u8;
+    out[1] = ((298 * c - 100 * d - 208 * e + 128) >> 8).clamp(0, 255) as u8;
+    out[2] = ((298 * c + 516 * d + 128) >> 8).clamp(0, 255) as u8;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn convert(converter: &Converter, src: &[u8]) -> Vec<u8> {
+        let mut rgb = vec![0; converter.frame_len()];
+        converter.convert(src, &mut rgb).unwrap();
+        
+        rgb
+    }
+    
+    #[test]
+    fn limited_range_white_and_black() {
+        let mut out = [0; 3];
+        yuv_to_rgb(235, 128, 128, &mut out);
+        assert_eq!(out, [255, 255, 255]);
+        yuv_to_rgb(16, 128, 128, &mut out);
+        assert_eq!(out, [0, 0, 0]);
+        // Out of range values are clamped rather than wrapping around
+        yuv_to_rgb(0, 128, 128, &mut out);
+        assert_eq!(out, [0, 0, 0]);
+        yuv_to_rgb(255, 128, 128, &mut out);
+        assert_eq!(out, [255, 255, 255]);
+    }
+    
+    #[test]
+    fn uyvy_and_yuyv_orders_agree() {
+        // 4x2 pixels of Y0 U Y1 V
+        let yuyv = [
+            16, 128, 235, 128, 145, 54, 41, 240,
+            81, 90, 210, 146, 107, 202, 170, 166,
+        ];
+        let uyvy: Vec<u8> = yuyv.chunks_exact(2).flat_map(|pair| [pair[1], pair[0]]).collect();
+        
+        let from_yuyv = convert(&Converter::new(PixelFormat::Yuyv, 4, 2, 0), &yuyv);
+        let from_uyvy = convert(&Converter::new(PixelFormat::Uyvy, 4, 2, 0), &uyvy);
+        assert_eq!(from_yuyv, from_uyvy);
+        assert_eq!(from_yuyv[0..6], [0, 0, 0, 255, 255, 255]);
+    }
+    
+    #[test]
+    fn nv12_padding_is_skipped() {
+        let (width, height) = (4usize, 3usize);
+        let luma: Vec<u8> = (0..(width * height)).map(|i| 16 + 18 * i as u8).collect();
+        let chroma: Vec<u8> = (0..(width * height.div_ceil(2))).map(|i| 60 + 20 * i as u8).collect();
+        let packed = [luma.as_slice(), &chroma].concat();
+        
+        // The same planes, with every row padded out to 8 bytes
+        let stride = 8;
+        let pad = |plane: &[u8]| -> Vec<u8> {
+            plane.chunks_exact(width).flat_map(|row| [row, &[0xAA; 4]].concat()).collect()
+        };
+        let padded = [pad(&luma), pad(&chroma)].concat();
+        
+        let converter = Converter::new(PixelFormat::Nv12, width, height, stride);
+        assert_eq!(converter.buffer_len(), Some(padded.len()));
+        assert_eq!(convert(&Converter::new(PixelFormat::Nv12, width, height, 0), &packed), convert(&converter, &padded));
+        // The padding after the last row is optional
+        assert_eq!(convert(&converter, &padded[..(padded.len() - 4)]), convert(&converter, &padded));
+    }
+    
+    #[test]
+    fn rgbp_unpacks_565() {
+        // Red, green, blue and white, little-endian
+        let src = [0x00, 0xF8, 0xE0, 0x07, 0x1F, 0x00, 0xFF, 0xFF];
+        let rgb = convert(&Converter::new(PixelFormat::Rgbp, 4, 1, 0), &src);
+        
+        assert_eq!(rgb, [
+            248, 0, 0,
+            0, 252, 0,
+            0, 0, 248,
+            248, 252, 248,
+        ]);
+    }
+    
+    #[test]
+    fn short_buffer_is_rejected() {
+        let converter = Converter::new(PixelFormat::Yuyv, 4, 2, 0);
+        let mut rgb = vec![0; converter.frame_len()];
+        
+        assert!(matches!(converter.convert(&[0; 15], &mut rgb), Err(ShortBuffer(15, 16))));
+    }
+}
@@ -0,0 +1,326 @@
+use std::str::FromStr;
+use serde::Deserialize;
+
+/// Summed RGB difference above which a pixel is considered to be moving, by the adaptive mode.
+const MOTION_THRESHOLD: u32 = 48;
+/// Fields of the same parity in a row, after which the video is treated as progressive (e.g. 240p).
+const REPEATS_FOR_PROGRESSIVE: u32 = 4;
+
+
+/// How the two fields of interlaced video are combined into frames.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Interleaves the lines of both fields. Sharpest for still images, but moving edges are combed.
+    Weave,
+    /// Only uses the latest field, interpolating the missing lines. Never combs, but halves vertical detail.
+    Bob,
+    /// Weaves where the picture is still, and bobs where it is moving.
+    Adaptive,
+    /// Doubles every line of the latest field. For progressive (240p) video sent as fields, which
+    /// capture devices can't always tell apart from interlaced video.
+    LineDouble,
+}
+impl FromStr for Mode {
+    type Err = ();
+    
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "weave" => Ok(Self::Weave),
+            "bob" => Ok(Self::Bob),
+            "adaptive" => Ok(Self::Adaptive),
+            "line-double" => Ok(Self::LineDouble),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Field {
+    Top,
+    Bottom,
+}
+impl Field {
+    fn index(&self) -> usize {
+        match self {
+            Field::Top => 0,
+            Field::Bottom => 1,
+        }
+    }
+}
+
+/// What a captured buffer holds, according to its metadata.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Layout {
+    /// A whole progressive frame.
+    Progressive,
+    /// A single field, holding half of the frame's lines.
+    Field(Field),
+    /// Both fields, with their lines interleaved. The field captured first is given.
+    Interlaced(Field),
+    /// Both fields, one after the other. The field captured (and stored) first is given.
+    Sequential(Field),
+}
+impl Layout {
+    /// Reads a buffer's `v4l2_field` value.
+    pub fn from_v4l(field: u32) -> Self {
+        match field {
+            2 => Layout::Field(Field::Top),
+            3 => Layout::Field(Field::Bottom),
+            4 | 8 => Layout::Interlaced(Field::Top),
+            9 => Layout::Interlaced(Field::Bottom),
+            5 => Layout::Sequential(Field::Top),
+            6 => Layout::Sequential(Field::Bottom),
+            _ => Layout::Progressive,
+        }
+    }
+}
+
+
+/// Turns captured fields into whole frames, one for every two fields, so the frame rate stays the
+/// same as for progressive capture.
+/// 
+/// Fields that keep arriving with the same parity are progressive video sent as fields (the N64's
+/// 240p modes), and are line doubled regardless of the mode, rather than being combined.
+pub struct Deinterlacer {
+    mode: Mode,
+    width: usize,
+    height: usize,
+    
+    /// Latest top and bottom field.
+    fields: [Vec<u8>; 2],
+    /// The top and bottom field before those, to detect motion with.
+    previous: [Vec<u8>; 2],
+    last: Option<Field>,
+    repeats: u32,
+    pending: u32,
+}
+impl Deinterlacer {
+    /// `width` and `height` are of whole frames.
+    pub fn new(mode: Mode, width: usize, height: usize) -> Self {
+        let field_len = width * (height / 2) * 3;
+        Self {
+            mode,
+            width,
+            height,
+            fields: [vec![0; field_len], vec![0; field_len]],
+            previous: [vec![0; field_len], vec![0; field_len]],
+            last: None,
+            repeats: 0,
+            pending: 0,
+        }
+    }
+    
+    /// Takes a captured buffer, converted into packed RGB, and writes a frame into `out` once one is
+    /// complete. Returns true if it did.
+    pub fn push(&mut self, layout: Layout, rgb: &[u8], out: &mut [u8]) -> bool {
+        let row = self.width * 3;
+        let field_rows = self.height / 2;
+        
+        match layout {
+            Layout::Progressive => {
+                out.copy_from_slice(&rgb[..out.len()]);
+                return true;
+            },
+            Layout::Field(field) => return self.field(field, rgb, out),
+            Layout::Interlaced(first) => {
+                for field in [first, other(first)] {
+                    let mut lines = vec![0; row * field_rows];
+                    for (y, line) in lines.chunks_exact_mut(row).enumerate() {
+                        let src = (y * 2 + field.index()) * row;
+                        line.copy_from_slice(&rgb[src..(src + row)]);
+                    }
+                    self.store(field, &lines);
+                }
+            },
+            Layout::Sequential(first) => {
+                let (a, b) = rgb.split_at(row * field_rows);
+                self.store(first, a);
+                self.store(other(first), &b[..(row * field_rows)]);
+            },
+        }
+        
+        // Both fields of a whole buffer always make up a frame
+        self.pending = 0;
+        self.build(out);
+        true
+    }
+    
+    fn field(&mut self, field: Field, rgb: &[u8], out: &mut [u8]) -> bool {
+        let was_progressive = self.progressive();
+        if self.last == Some(field) {
+            self.repeats = (self.repeats + 1).min(REPEATS_FOR_PROGRESSIVE * 2);
+        } else {
+            self.repeats = self.repeats.saturating_sub(1);
+        }
+        if self.progressive() != was_progressive {
+            debug!("Captured video is now {}.", if self.progressive() { "progressive" } else { "interlaced" });
+        }
+        self.store(field, &rgb[..self.fields[0].len()]);
+        self.pending += 1;
+        
+        // Interlaced frames are complete once their bottom field arrives, progressive ones every other field
+        let complete = if self.progressive() { self.pending >= 2 } else { field == Field::Bottom };
+        if !complete { return false }
+        
+        self.pending = 0;
+        self.build(out);
+        true
+    }
+    
+    fn store(&mut self, field: Field, lines: &[u8]) {
+        let i = field.index();
+        std::mem::swap(&mut self.fields[i], &mut self.previous[i]);
+        self.fields[i].copy_from_slice(lines);
+        self.last = Some(field);
+    }
+    
+    fn progressive(&self) -> bool {
+        self.repeats >= REPEATS_FOR_PROGRESSIVE
+    }
+    
+    /// Combines the stored fields into a frame, according to the mode.
+    fn build(&self, out: &mut [u8]) {
+        let row = self.width * 3;
+        let field_rows = self.height / 2;
+        let latest = self.last.unwrap_or(Field::Top);
+        let mode = if self.progressive() { Mode::LineDouble } else { self.mode };
+        
+        let current = &self.fields[latest.index()];
+        let opposite = &self.fields[other(latest).index()];
+        let opposite_previous = &self.previous[other(latest).index()];
+        
+        for (y, line) in out.chunks_exact_mut(row).take(field_rows * 2).enumerate() {
+            let (k, parity) = (y / 2, if y % 2 == 0 { Field::Top } else { Field::Bottom });
+            let own = &current[(k * row)..][..row];
+            
+            if mode == Mode::LineDouble || parity == latest {
+                line.copy_from_slice(own);
+                continue;
+            }
+            
+            let woven = &opposite[(k * row)..][..row];
+            if mode == Mode::Weave {
+                line.copy_from_slice(woven);
+                continue;
+            }
+            
+            // The missing line sits between two lines of the latest field
+            let neighbour = match latest {
+                Field::Top => (k + 1).min(field_rows - 1),
+                Field::Bottom => k.saturating_sub(1),
+            };
+            let neighbour = &current[(neighbour * row)..][..row];
+            
+            if mode == Mode::Bob {
+                for ((out, a), b) in line.iter_mut().zip(own).zip(neighbour) {
+                    *out = ((*a as u16 + *b as u16) / 2) as u8;
+                }
+                continue;
+            }
+            
+            let before = &opposite_previous[(k * row)..][..row];
+            for x in 0..self.width {
+                let px = x * 3..x * 3 + 3;
+                let motion: u32 = woven[px.clone()].iter().zip(&before[px.clone()]).map(|(a, b)| a.abs_diff(*b) as u32).sum();
+                for c in px {
+                    line[c] = if motion > MOTION_THRESHOLD {
+                        ((own[c] as u16 + neighbour[c] as u16) / 2) as u8
+                    } else {
+                        woven[c]
+                    };
+                }
+            }
+        }
+    }
+}
+
+fn other(field: Field) -> Field {
+    match field {
+        Field::Top => Field::Bottom,
+        Field::Bottom => Field::Top,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const WIDTH: usize = 2;
+    const HEIGHT: usize = 6;
+    
+    /// Lines of `WIDTH` pixels, each filled with one value.
+    fn lines(values: &[u8]) -> Vec<u8> {
+        values.iter().flat_map(|value| [*value; WIDTH * 3]).collect()
+    }
+    
+    fn frame() -> Vec<u8> {
+        vec![0; WIDTH * HEIGHT * 3]
+    }
+    
+    #[test]
+    fn alternating_fields_weave() {
+        let mut deinterlacer = Deinterlacer::new(Mode::Weave, WIDTH, HEIGHT);
+        let mut out = frame();
+        
+        assert!(!deinterlacer.push(Layout::Field(Field::Top), &lines(&[10, 30, 50]), &mut out));
+        assert!(deinterlacer.push(Layout::Field(Field::Bottom), &lines(&[20, 40, 60]), &mut out));
+        assert_eq!(out, lines(&[10, 20, 30, 40, 50, 60]));
+        
+        assert!(!deinterlacer.push(Layout::Field(Field::Top), &lines(&[11, 31, 51]), &mut out));
+        assert!(deinterlacer.push(Layout::Field(Field::Bottom), &lines(&[21, 41, 61]), &mut out));
+        assert_eq!(out, lines(&[11, 21, 31, 41, 51, 61]));
+    }
+    
+    #[test]
+    fn repeated_parity_switches_to_line_double() {
+        let mut deinterlacer = Deinterlacer::new(Mode::Weave, WIDTH, HEIGHT);
+        let mut out = frame();
+        
+        // The first field, then three repeats of it, are still treated as interlaced
+        for _ in 0..REPEATS_FOR_PROGRESSIVE {
+            assert!(!deinterlacer.push(Layout::Field(Field::Top), &lines(&[10, 30, 50]), &mut out));
+        }
+        assert!(!deinterlacer.progressive());
+        
+        // The fourth repeat makes it progressive, completing a frame with the fields still pending
+        assert!(deinterlacer.push(Layout::Field(Field::Top), &lines(&[10, 30, 50]), &mut out));
+        assert!(deinterlacer.progressive());
+        assert_eq!(out, lines(&[10, 10, 30, 30, 50, 50]));
+        
+        // After which a frame is completed every other field, starting over from the last one
+        assert!(!deinterlacer.push(Layout::Field(Field::Top), &lines(&[12, 32, 52]), &mut out));
+        assert!(deinterlacer.push(Layout::Field(Field::Top), &lines(&[14, 34, 54]), &mut out));
+        assert_eq!(out, lines(&[14, 14, 34, 34, 54, 54]));
+        assert!(!deinterlacer.push(Layout::Field(Field::Top), &lines(&[16, 36, 56]), &mut out));
+    }
+    
+    #[test]
+    fn whole_buffers_reset_pending_fields() {
+        let mut deinterlacer = Deinterlacer::new(Mode::Weave, WIDTH, HEIGHT);
+        let mut out = frame();
+        for _ in 0..=REPEATS_FOR_PROGRESSIVE {
+            deinterlacer.push(Layout::Field(Field::Top), &lines(&[10, 30, 50]), &mut out);
+        }
+        assert!(!deinterlacer.push(Layout::Field(Field::Top), &lines(&[10, 30, 50]), &mut out));
+        
+        assert!(deinterlacer.push(Layout::Sequential(Field::Top), &lines(&[10, 30, 50, 20, 40, 60]), &mut out));
+        assert!(!deinterlacer.push(Layout::Field(Field::Top), &lines(&[10, 30, 50]), &mut out));
+    }
+    
+    #[test]
+    fn bob_averages_neighbouring_lines() {
+        let mut deinterlacer = Deinterlacer::new(Mode::Bob, WIDTH, HEIGHT);
+        let mut out = frame();
+        
+        // Missing top lines sit between the bottom line above and below, except for the first one
+        deinterlacer.push(Layout::Field(Field::Top), &lines(&[99, 99, 99]), &mut out);
+        assert!(deinterlacer.push(Layout::Field(Field::Bottom), &lines(&[20, 40, 60]), &mut out));
+        assert_eq!(out, lines(&[20, 20, 30, 40, 50, 60]));
+        
+        // Missing bottom lines sit between the top line above and below, except for the last one
+        assert!(deinterlacer.push(Layout::Interlaced(Field::Bottom), &lines(&[10, 99, 30, 99, 50, 99]), &mut out));
+        assert_eq!(out, lines(&[10, 20, 30, 40, 50, 50]));
+    }
+}
@@ -2,32 +2,52 @@
 extern crate env_logger;
 #[macro_use] extern crate log;
 
-use std::str::FromStr;
+use std::io::BufRead;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use clap::{AppSettings, Arg, Command};
+use crossbeam_channel::Sender;
 use crossbeam_queue::SegQueue;
 use log::LevelFilter;
-use minifb::{Key, Window, WindowOptions};
-use minifb::{Scale, ScaleMode};
-use portaudio::DeviceIndex;
-use v4l::io::traits::OutputStream;
-use remote64_common::{Feature, Frame};
-use remote64_common::util::InfCell;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use remote64_common::{Component, Frame};
+use remote64_common::auth::StoredSecret;
+use remote64_common::util::{parse_duration, parse_size};
 use remote64_common::intercom::{BroadcastNetwork, InterMessage};
+use crate::capture::{CaptureSource, DEFAULT_FRAME_RATE};
+use crate::audio::{AudioCapture, CHANNELS, SAMPLE_RATE};
+use crate::board::{Board, POWER_CYCLE_OFF};
 use crate::sockets::SocketManager;
-use crate::recording::Recording;
-use crate::video::VideoStream;
+use crate::recording::{Recorder, Recording, RetentionPolicy};
+use crate::config::{CONFIG_PATH, Config};
+use crate::convert::Converter;
+use crate::deinterlace::Deinterlacer;
+use crate::preview::{Hotkey, Preview, Status};
+use crate::transform::Transform;
+use crate::sync::{AvAligner, Clock};
+use crate::watchdog::{BAD_FRAME_LIMIT, IDLE_INTERVAL, STALL_TIMEOUT, Watchdog};
 
 
+mod audio;
+mod board;
+mod calibrate;
+mod capture;
+mod config;
+mod convert;
+mod deinterlace;
+mod overlay;
+mod pattern;
+mod preview;
 mod sockets;
 mod recording;
+mod shutdown;
+mod sync;
+mod transform;
 mod video;
+mod watchdog;
 
 
-const WIDTH: usize = 720;
-const HEIGHT: usize = 480;
-
 fn main() {
     // Run clap to parse cli arguments
     let matches = Command::new("remote64-server")
@@ -38,8 +58,136 @@ fn main() {
             .takes_value(true)
             .multiple_occurrences(true)
             .possible_values(["LivePlayback", "AudioRecording", "InputHandling"])
-            .help("Specify a feature supported by this server. Use multiple -f/--feature args to specify multiple features."))
+            .global(true)
+            .help("Specify a feature supported by this server. Use multiple -f/--feature args to specify multiple features. Replaces the features in the configuration file."))
+        .arg(Arg::new("loader")
+            .long("loader")
+            .takes_value(true)
+            .global(true)
+            .help("Command used to load and start an uploaded ROM on the console. The ROM's path is appended as the final argument."))
+        .arg(Arg::new("headless")
+            .long("headless")
+            .global(true)
+            .help("Run without the preview window, until stopped by SIGINT or SIGTERM."))
+        .arg(Arg::new("listen")
+            .long("listen")
+            .takes_value(true)
+            .global(true)
+            .help("Address and port that clients connect to (default 0.0.0.0:6400)."))
+        .arg(Arg::new("capture-source")
+            .long("capture-source")
+            .takes_value(true)
+            .possible_values(["v4l", "pattern", "file"])
+            .global(true)
+            .help("What to capture video from: a V4L device (default), a generated test pattern, or a file given by --capture-file."))
+        .arg(Arg::new("capture-file")
+            .long("capture-file")
+            .takes_value(true)
+            .global(true)
+            .help("Raw video file (in the capture format and size) or directory of JPEG images to replay, with the file capture source."))
+        .arg(Arg::new("capture-device")
+            .long("capture-device")
+            .takes_value(true)
+            .global(true)
+            .help("Device to capture video from, by path (e.g. /dev/video2), index (e.g. 2) or part of its name. The first capture device found is used by default."))
+        .arg(Arg::new("capture-format")
+            .long("capture-format")
+            .takes_value(true)
+            .global(true)
+            .help("FourCC of the pixel format to capture in (e.g. RGBP). The first supported format the device offers is used by default."))
+        .arg(Arg::new("deinterlace")
+            .long("deinterlace")
+            .takes_value(true)
+            .possible_values(["weave", "bob", "adaptive", "line-double"])
+            .global(true)
+            .help("How interlaced (480i) video is combined into frames (default adaptive). Progressive (240p) video is detected and line doubled regardless, unless the capture device can't tell them apart, in which case use line-double."))
+        .arg(Arg::new("crop")
+            .long("crop")
+            .takes_value(true)
+            .global(true)
+            .help("Part of the captured frame to keep: none (default), auto to detect and remove black borders, or WIDTHxHEIGHT+X+Y (e.g. 704x480+8+0)."))
+        .arg(Arg::new("output-size")
+            .long("output-size")
+            .takes_value(true)
+            .global(true)
+            .help("Resolution frames are scaled to, as WIDTHxHEIGHT (e.g. 640x480). Derived from the crop and aspect ratio by default."))
+        .arg(Arg::new("aspect")
+            .long("aspect")
+            .takes_value(true)
+            .global(true)
+            .help("Aspect ratio the picture is shown in (e.g. 4:3), letterboxed if the output size has a different shape. Captured pixels are assumed to be square by default."))
+        .arg(Arg::new("list-devices")
+            .long("list-devices")
+            .help("List every capture device, with the pixel formats, resolutions and frame rates it offers, then exit."))
+        .arg(Arg::new("audio-source")
+            .long("audio-source")
+            .takes_value(true)
+            .possible_values(["device", "tone", "file"])
+            .global(true)
+            .help("What to capture audio from: an input device (default), a generated tone, or a WAV file given by --audio-file."))
+        .arg(Arg::new("audio-file")
+            .long("audio-file")
+            .takes_value(true)
+            .global(true)
+            .help("WAV file to replay, with the file audio source."))
+        .arg(Arg::new("audio-input")
+            .long("audio-input")
+            .takes_value(true)
+            .global(true)
+            .help("Part of the name of the audio input to capture from (default \"pulse\"). An empty name selects the default input."))
+        .arg(Arg::new("no-monitor")
+            .long("no-monitor")
+            .global(true)
+            .help("Don't play captured audio on the default output device."))
+        .arg(Arg::new("recording-path")
+            .long("recording-path")
+            .takes_value(true)
+            .global(true)
+            .help("Directory every session is recorded into (default recording/)."))
+        .arg(Arg::new("recording-expiry")
+            .long("recording-expiry")
+            .takes_value(true)
+            .validator(|text| parse_duration(text).ok_or("expected a duration such as 30m or 1h"))
+            .global(true)
+            .help("How long a finished session's recording can be downloaded by its client (default 1h)."))
+        .arg(Arg::new("retention-max-age")
+            .long("retention-max-age")
+            .takes_value(true)
+            .validator(|text| parse_duration(text).ok_or("expected a duration such as 12h or 30d"))
+            .global(true)
+            .help("Delete session recordings older than this."))
+        .arg(Arg::new("retention-max-size")
+            .long("retention-max-size")
+            .takes_value(true)
+            .validator(|text| parse_size(text).ok_or("expected a size such as 500M or 20G"))
+            .global(true)
+            .help("Delete the oldest session recordings while all of them together take up more than this (default 50G)."))
+        .arg(Arg::new("av-offset")
+            .long("av-offset")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .validator(|ms| ms.parse::<f64>().map(|_| ()).map_err(|_| "expected a number of milliseconds"))
+            .global(true)
+            .help("Milliseconds that audio is captured later than the video it belongs to. Audio is shifted earlier by this much (or later, if negative) when paired with video. Overrides the offset saved by the calibrate command."))
+        .arg(Arg::new("session-limit")
+            .long("session-limit")
+            .takes_value(true)
+            .validator(|text| parse_duration(text).ok_or("expected a duration such as 10m or 1h"))
+            .global(true)
+            .help("Longest a client may be serviced for, before the next client in the queue is serviced. Sessions are unlimited by default."))
+        .arg(Arg::new("board")
+            .long("board")
+            .takes_value(true)
+            .global(true)
+            .help("Serial port of the controller board (e.g. /dev/ttyUSB0), which switches the console's power."))
+        .arg(Arg::new("config")
+            .long("config")
+            .takes_value(true)
+            .default_value(CONFIG_PATH)
+            .global(true)
+            .help("Path of the server's configuration file. Command line arguments override the settings in it."))
         .arg(Arg::new("verbose")
+            .global(true)
             .short('v')
             .long("verbose")
             .takes_value(true)
@@ -47,12 +195,33 @@ fn main() {
             .default_value("info")
             .possible_values(["error", "warn", "info", "debug", "trace"])
             .help("Specify the console log level. Environment variable 'RUST_LOG' will override this option."))
+        .subcommand(Command::new("calibrate")
+            .about("Measures the offset between the audio and video capture, and saves it into the configuration file.")
+            .after_help("Run a ROM on the console that flashes the screen white while beeping, about once per second, for the whole duration.")
+            .arg(Arg::new("duration")
+                .long("duration")
+                .takes_value(true)
+                .default_value("60s")
+                .validator(|text| parse_duration(text).ok_or("expected a duration such as 30s or 2m"))
+                .help("How long to watch the capture for. Longer calibrations are more accurate.")))
+        .subcommand(Command::new("hash-secret")
+            .about("Hashes a user's password or token, printing the line to add under [auth.users] in the configuration file.")
+            .after_help("The secret is read from the first line of standard input.")
+            .arg(Arg::new("user")
+                .required(true)
+                .help("Name of the user."))
+            .arg(Arg::new("generate")
+                .long("generate")
+                .help("Generate a random token for the user instead, and print it too.")))
         .next_line_help(true)
         .setting(AppSettings::DeriveDisplayOrder)
         .get_matches();
     
+    // Global args are propagated down into the subcommand's matches, but not back up
+    let globals = matches.subcommand().map(|(_, sub)| sub).unwrap_or(&matches);
+    
     // Setup program-wide logger format
-    let level = match std::env::var("RUST_LOG").unwrap_or(matches.value_of("verbose").unwrap_or("info").to_owned()).as_str() {
+    let level = match std::env::var("RUST_LOG").unwrap_or(globals.value_of("verbose").unwrap_or("info").to_owned()).as_str() {
         "error" => LevelFilter::Error,
         "warn" => LevelFilter::Warn,
         "info" => LevelFilter::Info,
@@ -66,170 +235,346 @@ fn main() {
         logbuilder.init();
     }
     
-    // Collect features from cli arguments
-    let features: Vec<Feature> = matches.values_of("features").unwrap_or_default().map(|feat| Feature::from_str(feat).unwrap_or_default()).collect();
-    
-    let mut intercom = BroadcastNetwork::<InterMessage>::new();
-    
-    // Initialize socket manager which handles the client connections and request queue
-    SocketManager::init(features, intercom.endpoint());
-    
-    
-    
-    
-    let mut window_buf: Vec<u32> = vec![0; WIDTH * HEIGHT];
-    let mut window = Window::new("remote64-server", WIDTH, HEIGHT, WindowOptions {
-        borderless: false,
-        title: false,
-        resize: false,
-        scale: Scale::X1,
-        scale_mode: ScaleMode::AspectRatioStretch,
-        topmost: false,
-        transparency: false,
-        none: false
-    }).unwrap();
-    
-    window.limit_update_rate(Some(Duration::from_secs_f32(1.0/60.0)));
+    if matches.is_present("list-devices") {
+        video::list_devices();
+        return;
+    }
     
+    if let Some(("hash-secret", hash_matches)) = matches.subcommand() {
+        let secret = if hash_matches.is_present("generate") {
+            let token = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect::<String>();
+            println!("Token: {}", token);
+            token
+        } else {
+            let mut line = String::new();
+            if let Err(err) = std::io::stdin().lock().read_line(&mut line) {
+                error!("Failed to read the secret: {}", err);
+                std::process::exit(1);
+            }
+            line.trim_end_matches(['\r', '\n']).to_owned()
+        };
+        if secret.is_empty() {
+            error!("The secret must not be empty.");
+            std::process::exit(1);
+        }
+        
+        let user = hash_matches.value_of("user").unwrap_or_default();
+        println!("{:?} = \"{}\"", user, StoredSecret::new(&secret, rand::random()));
+        return;
+    }
     
+    let config_path = globals.value_of("config").unwrap_or(CONFIG_PATH);
+    let config = Config::load(config_path).and_then(|mut config| {
+        config.override_with(globals);
+        config.validate()?;
+        Ok(config)
+    });
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let (capture_width, capture_height) = (config.capture.width, config.capture.height);
     
-    let pa = portaudio::PortAudio::new().unwrap();
+    // Cropping and scaling happen once, so the window, clients and recording all get the same frames
+    let output_size = config.output.width.zip(config.output.height);
+    let mut transform = Transform::new(config.output.crop, config.output.aspect, (capture_width, capture_height), output_size);
+    let (width, height) = transform.output_size();
+    if (width, height) != (capture_width, capture_height) {
+        info!("Scaling {}x{} captured frames to {}x{}.", capture_width, capture_height, width, height);
+    }
     
-    let mut input_device_id = DeviceIndex(0);
-    for device in pa.devices().unwrap() {
-        let (idx, info) = device.unwrap();
+    if let Some(("calibrate", calibrate_matches)) = matches.subcommand() {
+        let duration = calibrate_matches.value_of("duration").and_then(parse_duration).unwrap_or(Duration::from_secs(60));
         
-        if info.name.contains("pulse") {
-            input_device_id = idx;
+        let clock = Clock::new();
+        let samples = Arc::new(SegQueue::<(f64, Vec<f32>)>::new());
+        let mut audio = start_audio(&config, clock, samples.clone());
+        let (mut source, converter) = open_capture(&config, clock);
+        
+        let estimate = calibrate::run(source.as_mut(), &converter, samples, duration);
+        audio.stop();
+        
+        let saved = estimate.map(|estimate| config::save_av_offset(config_path, estimate.offset * 1000.0));
+        match saved {
+            Some(Ok(())) => info!("Saved the offset into {}.", config_path),
+            Some(Err(ref err)) => error!("Failed to save the offset: {}", err),
+            None => (),
         }
+        std::process::exit(if let Some(Ok(())) = saved { 0 } else { 1 });
     }
-    //input_device_id = pa.default_input_device().unwrap();
-    let input_device_info = pa.device_info(input_device_id).unwrap();
-    let latency = input_device_info.default_low_input_latency;
-    let input_params = portaudio::StreamParameters::<f32>::new(input_device_id, 2, true, latency);
     
+    let mut intercom = BroadcastNetwork::<InterMessage>::new();
     
-    let output_device_id = pa.default_output_device().unwrap();
-    let output_device_info = pa.device_info(output_device_id).unwrap();
-    let latency = output_device_info.default_low_output_latency;
-    let output_params = portaudio::StreamParameters::<f32>::new(output_device_id, 2, true, latency);
+    // Initialize socket manager which handles the client connections and request queue
+    let socket_manager = match SocketManager::init(&config, intercom.endpoint()) {
+        Ok(socket_manager) => socket_manager,
+        Err(err) => {
+            error!("Failed to start the server on {}: {}", config.network.listen, err);
+            std::process::exit(1);
+        }
+    };
     
-    pa.is_duplex_format_supported(input_params, output_params, 44100.0).unwrap();
+    // The preview window is optional, the server otherwise runs until it receives SIGINT or SIGTERM
+    shutdown::install_handler();
+    let mut preview = if config.headless {
+        info!("Running headless.");
+        None
+    } else {
+        match Preview::open(width, height) {
+            Ok(preview) => Some(preview),
+            Err(err) => {
+                warn!("Unable to open the preview window, running headless: {}", err);
+                None
+            }
+        }
+    };
     
-    let settings = portaudio::DuplexStreamSettings::new(input_params, output_params, 44100.0, 512);
     
-    let recording = InfCell::new(Recording::new(WIDTH as u32, HEIGHT as u32));
-    let audio_recording = recording.get_mut();
-    let video_recording = recording.get_mut();
-    for _ in 0..15 {
-        video_recording.frame(); // delay video by 15 frames to better sync with audio recording
-    }
     
-    let manage_recording = recording.get_mut();
-    let recording_endpoint = intercom.endpoint();
-    std::thread::spawn(move || {
-        while let Ok(msg) = recording_endpoint.recv.recv() {
-            match msg {
-                InterMessage::StartRecording => {
-                    info!("Recording started.");
-                    manage_recording.start();
-                },
-                InterMessage::StopRecording => {
-                    info!("Recording ended.");
-                    manage_recording.end();
-                },
-                _ => ()
-            }
-        }
-        info!("Recording endpoint died.");
+    
+    // Audio and video are paired up by capture time, before being recorded or sent to clients
+    let clock = Clock::new();
+    let (source, mut converter) = open_capture(&config, clock);
+    let frame_rate = source.frame_rate().or(config.capture.frame_rate).unwrap_or_else(|| {
+        warn!("Capture device did not report its frame rate, assuming {}.", DEFAULT_FRAME_RATE);
+        DEFAULT_FRAME_RATE
     });
+    info!("Capturing at {:.3} fps.", frame_rate);
+    
+    let mut aligner = AvAligner::new(SAMPLE_RATE, CHANNELS, frame_rate, config.audio.av_offset_ms / 1000.0);
+    
+    let retention = RetentionPolicy {
+        path: config.recording.path.clone(),
+        max_age: config.recording.max_age,
+        max_size: config.recording.max_size,
+        expiry: config.recording.expiry,
+    };
+    retention.enforce(&[]);
+    
+    let recording = Recording::new(config.recording.path.clone(), width as u32, height as u32, frame_rate);
+    let recorder = Recorder::spawn(recording, retention, intercom.endpoint());
     
     
     let audio_endpoint = intercom.endpoint();
     drop(audio_endpoint.recv);
-    let samples = Arc::new(SegQueue::<f32>::new());
-    let callback_samples = samples.clone();
-    let callback = move |portaudio::stream::DuplexCallbackArgs {
-                             in_buffer,
-                             out_buffer,
-                             frames: _,
-                             flags, 
-                             time: _,
-                         }| {
-        if !flags.is_empty() {
-            debug!("flags: {:?}", flags);
-        }
-        
-        for (output_sample, input_sample) in out_buffer.iter_mut().zip(in_buffer.iter()) {
-            *output_sample = *input_sample;
-            
-            callback_samples.push(*input_sample);
-            
-            if audio_recording.started() {
-                audio_recording.sample(*input_sample);
-            }
-        }
-        
-        portaudio::Continue
-    };
+    let samples = Arc::new(SegQueue::<(f64, Vec<f32>)>::new());
+    let audio = start_audio(&config, clock, samples.clone());
     
-    let mut audio_stream = pa.open_non_blocking_stream(settings, callback).unwrap();
-    audio_stream.start().unwrap();
     
     
     
+    // The console's power is only controlled if a board is configured, and the server runs without one
+    let board = config.board.path.as_ref().map(|path| Arc::new(Board::new(path)));
     
     let video_endpoint = intercom.endpoint();
-    drop(video_endpoint.recv);
     std::thread::spawn(move || {
         intercom.start();
     });
     
-    let mut video_capture = VideoStream::new().unwrap(); //TODO allow server user to specify which device to use
+    // Capture, audio and the board are recovered when they fail, while the server carries on degraded
+    if let Some(board) = &board {
+        watchdog::watch_board(board.clone(), video_endpoint.send.clone());
+    }
+    let mut source = Some(source);
+    let mut audio = Some(audio);
+    let mut capture_watchdog = Watchdog::new(Component::VideoCapture, video_endpoint.send.clone());
+    let mut audio_watchdog = Watchdog::new(Component::AudioCapture, video_endpoint.send.clone());
+    let mut last_audio = Instant::now();
+    let mut bad_frames = 0;
     
-    window_buf.fill(0);
-    let mut socket_buf = vec![0; window_buf.len() * 3];
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        let (stream_buf, _meta) = video_capture.stream.next().unwrap(); // blocks until next frame, thus may limit FPS
+    let mut capture_buf = vec![0; converter.frame_len()];
+    let mut frame_buf = vec![0; capture_width * capture_height * 3];
+    let mut socket_buf = vec![0; width * height * 3];
+    let mut deinterlacer = Deinterlacer::new(config.capture.deinterlace, capture_width, capture_height);
+    let mut sequence: u32 = 0;
+    let mut status = Status::default();
+    let (mut fps_frames, mut fps_since) = (0, Instant::now());
+    while !shutdown::requested() {
+        if preview.as_ref().map(|preview| !preview.is_open()).unwrap_or(false) {
+            info!("Preview window closed.");
+            break;
+        }
         
-        // decode stream buffer and distribute among other framebuffers
-        for i in (0..stream_buf.len()).step_by(2) { // assumes RGBP format, which uses 2 bytes per pixel
-            let r = stream_buf[i + 1] & 0b11111000;
-            let g = ((stream_buf[i + 1] & 0b00000111) << 5) | ((stream_buf[i] & 0b11100000) >> 3);
-            let b = (stream_buf[i] & 0b00011111) << 3;
-            let color = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-            
-            let i = i / 2;
-            
-            // update server's framebuffer (u32 0RGB)
-            window_buf[i] = color;
-            
-            // update client's framebuffer (u8 u8 u8 RGB)
-            socket_buf[(i * 3) + 0] = r;
-            socket_buf[(i * 3) + 1] = g;
-            socket_buf[(i * 3) + 2] = b;
+        while let Ok(msg) = video_endpoint.recv.try_recv() {
+            if let InterMessage::QueueStatus(queue) = msg {
+                status.queue = queue;
+            }
+        }
+        
+        // Audio that stops arriving is restarted, e.g. after the input device was unplugged
+        let mut audio_arrived = false;
+        while let Some((captured_at, sample_buf)) = samples.pop() {
+            if source.is_some() {
+                aligner.push_audio(captured_at, &sample_buf);
+            }
+            audio_arrived = true;
+        }
+        if audio_arrived {
+            last_audio = Instant::now();
+            audio_watchdog.ok();
+        } else if audio.is_some() && last_audio.elapsed() > STALL_TIMEOUT {
+            audio_watchdog.fail(&format!("no audio was captured for {}s", STALL_TIMEOUT.as_secs()));
+            audio = None;
+        } else if audio.is_none() && audio_watchdog.retry_due() {
+            match audio::start(&config.audio, clock, samples.clone()) {
+                Ok(restarted) => {
+                    audio = Some(restarted);
+                    last_audio = Instant::now();
+                },
+                Err(err) => audio_watchdog.fail(&err),
+            }
+        }
+        
+        // Capture that failed is reopened, while the window keeps showing the last frame
+        if source.is_none() && capture_watchdog.retry_due() {
+            match capture::open(&config.capture, clock) {
+                Ok(reopened) => {
+                    converter = reopened.converter();
+                    capture_buf.resize(converter.frame_len(), 0);
+                    source = Some(reopened);
+                },
+                Err(err) => capture_watchdog.fail(&err),
+            }
+        }
+        
+        status.degraded = [
+            (Component::VideoCapture, capture_watchdog.failed()),
+            (Component::AudioCapture, audio_watchdog.failed()),
+            (Component::ControllerBoard, board.as_ref().map(|board| !board.connected()).unwrap_or(false)),
+        ].into_iter().filter(|(_, failed)| *failed).map(|(component, _)| component).collect();
+        
+        // blocks until next frame, thus may limit FPS
+        let frame = match source.as_mut().map(|source| source.next_frame()) {
+            Some(Ok(frame)) => frame,
+            Some(Err(_)) if shutdown::requested() => break, // interrupted by the signal
+            Some(Err(err)) => {
+                capture_watchdog.fail(&format!("failed to capture a frame: {}", err));
+                source = None;
+                aligner.reset();
+                continue;
+            },
+            None => {
+                status.capture_fps = 0.0;
+                if let Some(preview) = &mut preview {
+                    update_preview(preview, &socket_buf, &mut status, board.as_ref(), &video_endpoint.send, &recorder);
+                }
+                std::thread::sleep(IDLE_INTERVAL);
+                continue;
+            },
+        };
+        let (captured_at, layout) = (frame.captured_at, frame.layout);
+        
+        fps_frames += 1;
+        if fps_since.elapsed() >= Duration::from_secs(1) {
+            status.capture_fps = fps_frames as f64 / fps_since.elapsed().as_secs_f64();
+            (fps_frames, fps_since) = (0, Instant::now());
+        }
+        
+        // convert captured frame into RGB, combine fields into frames, crop and scale them, and distribute among other framebuffers
+        if let Err(err) = converter.convert(frame.data, &mut capture_buf) {
+            warn!("Dropped a captured frame: {}", err);
             
-            // update video recording framebuffer ([u8; 3] RGB)
-            video_recording.set_pixel_i(i as u32, r, g, b);
+            // A device that only captures garbage has most likely lost its signal
+            bad_frames += 1;
+            if bad_frames >= BAD_FRAME_LIMIT {
+                capture_watchdog.fail(&format!("none of the last {} captured frames could be used", BAD_FRAME_LIMIT));
+                source = None;
+                aligner.reset();
+                bad_frames = 0;
+            }
+            continue;
         }
+        bad_frames = 0;
+        capture_watchdog.ok();
         
-        // update server window
-        window.update_with_buffer(&window_buf, WIDTH, HEIGHT).unwrap();
+        if !deinterlacer.push(layout, &capture_buf, &mut frame_buf) { continue }
+        transform.apply(&frame_buf, &mut socket_buf);
         
-        // attempt to save new video frame (if recording is running)
-        video_recording.frame();
+        // update server window, and act on the operator's hotkeys
+        if let Some(preview) = &mut preview {
+            update_preview(preview, &socket_buf, &mut status, board.as_ref(), &video_endpoint.send, &recorder);
+        }
         
+        // pair frames with their audio
+        aligner.push_frame(captured_at, socket_buf.clone());
         
-        // send latest frame
-        let len = samples.len();
-        let mut sample_buf = Vec::with_capacity(len);
-        for _ in 0..len {
-            sample_buf.push(samples.pop().unwrap());
+        while let Some((video, audio)) = aligner.pop(clock.now()) {
+            // attempt to save new video frame (if recording is running)
+            recorder.frame(&video, &audio);
+            
+            // send latest frame
+            video_endpoint.send.try_send(InterMessage::LatestFrame(Frame::new(sequence, width as u16, height as u16, video, audio))).unwrap_or_default();
+            sequence = sequence.wrapping_add(1);
         }
-        video_endpoint.send.try_send(InterMessage::LatestFrame(Frame::new(socket_buf.clone(), sample_buf))).unwrap_or_default();
     }
     
-    audio_stream.stop().unwrap();
+    // Shut down gracefully: stop capturing, tell clients the server is going away, and finish the recording
+    info!("Shutting down.");
+    drop(audio);
     
-    recording.get_mut().end();
-}
\ No newline at end of file
+    video_endpoint.send.try_send(InterMessage::Kill).unwrap_or_default();
+    recorder.shutdown();
+    socket_manager.join().unwrap_or_default();
+}
+
+/// Shows a frame in the preview window with the server's status over it, and acts on the operator's hotkeys.
+fn update_preview(preview: &mut Preview, frame: &[u8], status: &mut Status, board: Option<&Arc<Board>>, send: &Sender<InterMessage>, recorder: &Recorder) {
+    status.power = board.map(|board| board.power());
+    status.recording = recorder.started();
+    status.recording_paused = recorder.paused();
+    preview.show(frame, status);
+    
+    for hotkey in preview.hotkeys() {
+        match hotkey {
+            Hotkey::SkipClient => send.try_send(InterMessage::EndSession("Skipped by the operator.".to_owned())).unwrap_or_default(),
+            Hotkey::PauseQueue => send.try_send(InterMessage::PauseQueue(!status.queue.paused)).unwrap_or_default(),
+            Hotkey::PowerCycle => match board {
+                Some(board) => power_cycle(board.clone(), send.clone()),
+                None => warn!("No controller board is connected to power-cycle the console with."),
+            },
+            Hotkey::ToggleRecording => recorder.toggle_pause(),
+        }
+    }
+}
+
+/// Switches the console off and on again, on its own thread so capture carries on meanwhile.
+fn power_cycle(board: Arc<Board>, send: Sender<InterMessage>) {
+    std::thread::spawn(move || {
+        info!("Power-cycling the console.");
+        match board.power_cycle(POWER_CYCLE_OFF) {
+            Ok(()) => send.try_send(InterMessage::SessionEvent("Operator power-cycled the console.".to_owned())).unwrap_or_default(),
+            Err(err) => error!("Failed to power-cycle the console: {}", err),
+        }
+    });
+}
+
+/// Opens the configured capture source, along with a converter for the format it captures in,
+/// exiting with an error if it can't be used.
+fn open_capture(config: &Config, clock: Clock) -> (Box<dyn CaptureSource>, Converter) {
+    match capture::open(&config.capture, clock) {
+        Ok(source) => {
+            let converter = source.converter();
+            (source, converter)
+        },
+        Err(err) => {
+            error!("Failed to start capturing: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Starts capturing audio from the configured source, exiting with an error if it can't be used.
+/// Captured audio is pushed onto `samples` in chunks, along with the time (see `Clock`) its first
+/// sample was captured.
+fn start_audio(config: &Config, clock: Clock, samples: Arc<SegQueue<(f64, Vec<f32>)>>) -> AudioCapture {
+    match audio::start(&config.audio, clock, samples) {
+        Ok(audio) => audio,
+        Err(err) => {
+            error!("Failed to start capturing audio: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::io;
+use crate::capture::{CaptureSource, CapturedFrame, Pacer};
+use crate::convert::{Converter, PixelFormat};
+use crate::deinterlace::Layout;
+use crate::sync::Clock;
+
+/// 75% colour bars, as in the SMPTE pattern, from left to right.
+const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+/// 3x5 pixel digits, a row per byte with the leftmost pixel in bit 2.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+/// Pixels the box moves every frame, horizontally and vertically.
+const BOX_SPEED: (usize, usize) = (4, 3);
+
+const WHITE: [u8; 3] = [255, 255, 255];
+const BLACK: [u8; 3] = [0, 0, 0];
+
+
+/// Generates a test pattern in place of captured video: colour bars over a grey ramp, with a white
+/// box bouncing around and the frame number in the top left corner. Every frame is different, so
+/// dropped or repeated frames are easy to spot downstream.
+pub struct PatternSource {
+    width: usize,
+    height: usize,
+    /// The bars and ramp, which never change.
+    background: Vec<u8>,
+    buf: Vec<u8>,
+    frame: u32,
+    frame_rate: f64,
+    pacer: Pacer,
+    clock: Clock,
+}
+impl PatternSource {
+    pub fn new(width: usize, height: usize, frame_rate: f64, clock: Clock) -> Self {
+        let bars_height = height * 2 / 3;
+        let mut background = vec![0; width * height * 3];
+        for (y, line) in background.chunks_exact_mut(width * 3).enumerate() {
+            for (x, px) in line.chunks_exact_mut(3).enumerate() {
+                if y < bars_height {
+                    px.copy_from_slice(&BARS[x * BARS.len() / width]);
+                } else {
+                    px.fill((x * 256 / width) as u8);
+                }
+            }
+        }
+        
+        Self {
+            width,
+            height,
+            buf: background.clone(),
+            background,
+            frame: 0,
+            frame_rate,
+            pacer: Pacer::new(frame_rate),
+            clock,
+        }
+    }
+    
+    fn draw(&mut self) {
+        self.buf.copy_from_slice(&self.background);
+        
+        let size = (self.height / 8).max(2);
+        let x = bounce(self.frame as usize * BOX_SPEED.0, self.width.saturating_sub(size));
+        let y = bounce(self.frame as usize * BOX_SPEED.1, self.height.saturating_sub(size));
+        self.fill(x, y, size, size, WHITE);
+        
+        // Each digit is 3 blocks wide with a block of space after it, on a black plate with a block of margin
+        let scale = (self.height / 60).max(1);
+        let digits = self.frame.to_string();
+        self.fill(0, 0, (digits.len() * 4 + 1) * scale, 7 * scale, BLACK);
+        for (i, digit) in digits.bytes().enumerate() {
+            for (row, bits) in DIGITS[(digit - b'0') as usize].iter().enumerate() {
+                for column in 0..3 {
+                    if bits & (0b100 >> column) != 0 {
+                        self.fill((1 + i * 4 + column) * scale, (1 + row) * scale, scale, scale, WHITE);
+                    }
+                }
+            }
+        }
+    }
+    
+    /// Fills a rectangle with a colour, clipped to the frame.
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: [u8; 3]) {
+        for line in self.buf.chunks_exact_mut(self.width * 3).skip(y).take(height) {
+            for px in line.chunks_exact_mut(3).skip(x).take(width) {
+                px.copy_from_slice(&color);
+            }
+        }
+    }
+}
+impl CaptureSource for PatternSource {
+    fn next_frame(&mut self) -> io::Result<CapturedFrame<'_>> {
+        self.pacer.wait();
+        self.draw();
+        self.frame = self.frame.wrapping_add(1);
+        
+        Ok(CapturedFrame {
+            data: &self.buf,
+            layout: Layout::Progressive,
+            captured_at: self.clock.now(),
+        })
+    }
+    
+    fn converter(&self) -> Converter {
+        Converter::new(PixelFormat::Rgb3, self.width, self.height, 0)
+    }
+    
+    fn frame_rate(&self) -> Option<f64> { Some(self.frame_rate) }
+}
+
+/// Position within `0..=len` of something moving back and forth, after travelling `distance`.
+fn bounce(distance: usize, len: usize) -> usize {
+    if len == 0 { return 0 }
+    
+    let pos = distance % (len * 2);
+    if pos <= len { pos } else { len * 2 - pos }
+}
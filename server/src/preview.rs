@@ -0,0 +1,152 @@
+use std::time::Duration;
+use minifb::{Key, KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
+use remote64_common::Component;
+use remote64_common::intercom::QueueStatus;
+use crate::board::Power;
+use crate::overlay;
+
+
+/// Everything shown on the preview's overlay.
+#[derive(Clone, Debug, Default)]
+pub struct Status {
+    pub queue: QueueStatus,
+    /// Frames captured per second, recently.
+    pub capture_fps: f64,
+    /// The console's power, if a controller board is connected.
+    pub power: Option<Power>,
+    pub recording: bool,
+    pub recording_paused: bool,
+    /// Components that have failed, and are being recovered.
+    pub degraded: Vec<Component>,
+}
+
+/// Actions the operator can take from the preview window.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Hotkey {
+    /// S: ends the active client's session.
+    SkipClient,
+    /// P: pauses or resumes servicing the queue.
+    PauseQueue,
+    /// C: switches the console off and on again.
+    PowerCycle,
+    /// R: pauses or resumes the session's recording.
+    ToggleRecording,
+}
+
+/// Window on the server showing the frames being sent to clients, for the operator. Closing it, or
+/// pressing escape, shuts the server down.
+/// 
+/// The frames are shown under an overlay describing the queue, capture and console, which can be
+/// hidden by pressing O.
+pub struct Preview {
+    window: Window,
+    /// Frame in the window's format (u32 0RGB).
+    window_buf: Vec<u32>,
+    width: usize,
+    height: usize,
+    overlay: bool,
+}
+impl Preview {
+    pub fn open(width: usize, height: usize) -> Result<Self, String> {
+        let mut window = Window::new("remote64-server", width, height, WindowOptions {
+            borderless: false,
+            title: false,
+            resize: false,
+            scale: Scale::X1,
+            scale_mode: ScaleMode::AspectRatioStretch,
+            topmost: false,
+            transparency: false,
+            none: false
+        }).map_err(|err| err.to_string())?;
+        
+        window.limit_update_rate(Some(Duration::from_secs_f32(1.0/60.0)));
+        
+        Ok(Self {
+            window,
+            window_buf: vec![0; width * height],
+            width,
+            height,
+            overlay: true,
+        })
+    }
+    
+    /// False once the window has been closed or escape was pressed.
+    pub fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+    
+    /// Shows a packed RGB (u8 u8 u8) frame, with the status drawn over it.
+    pub fn show(&mut self, rgb: &[u8], status: &Status) {
+        for (pixel, rgb) in self.window_buf.iter_mut().zip(rgb.chunks_exact(3)) {
+            *pixel = ((rgb[0] as u32) << 16) | ((rgb[1] as u32) << 8) | (rgb[2] as u32);
+        }
+        if self.overlay {
+            let scale = (self.height / 240).max(1);
+            overlay::draw_text(&mut self.window_buf, self.width, self.height, &status_lines(status), scale);
+        }
+        
+        if let Err(err) = self.window.update_with_buffer(&self.window_buf, self.width, self.height) {
+            warn!("Failed to update the preview window: {}", err);
+        }
+    }
+    
+    /// Hotkeys pressed since the window was last updated. Toggling the overlay is handled here.
+    pub fn hotkeys(&mut self) -> Vec<Hotkey> {
+        if self.window.is_key_pressed(Key::O, KeyRepeat::No) {
+            self.overlay = !self.overlay;
+        }
+        
+        [
+            (Key::S, Hotkey::SkipClient),
+            (Key::P, Hotkey::PauseQueue),
+            (Key::C, Hotkey::PowerCycle),
+            (Key::R, Hotkey::ToggleRecording),
+        ].into_iter()
+            .filter(|(key, _)| self.window.is_key_pressed(*key, KeyRepeat::No))
+            .map(|(_, hotkey)| hotkey)
+            .collect()
+    }
+}
+
+fn status_lines(status: &Status) -> Vec<String> {
+    let queue = &status.queue;
+    let session = match (&queue.active, queue.session_limit) {
+        (None, _) => "-".to_owned(),
+        (Some(_), None) => clock(queue.session_elapsed),
+        (Some(_), Some(limit)) => format!("{} / {} ({} left)", clock(queue.session_elapsed), clock(limit), clock(limit.saturating_sub(queue.session_elapsed))),
+    };
+    let power = match status.power {
+        None => "no board",
+        Some(Power::Unknown) => "unknown",
+        Some(Power::On) => "on",
+        Some(Power::Off) => "off",
+    };
+    let recording = match (status.recording, status.recording_paused) {
+        (false, _) => "off",
+        (true, false) => "on",
+        (true, true) => "paused",
+    };
+    
+    let health = match status.degraded.is_empty() {
+        true => "ok".to_owned(),
+        false => format!("lost {}", status.degraded.iter().map(|component| component.to_string()).collect::<Vec<_>>().join(", ")),
+    };
+    
+    vec![
+        format!("Health   {}", health),
+        format!("Client   {}", queue.active.as_deref().unwrap_or("none")),
+        format!("Session  {}", session),
+        format!("Queue    {} waiting{}", queue.waiting, if queue.paused { " (paused)" } else { "" }),
+        format!("Capture  {:.2} fps", status.capture_fps),
+        format!("Upload   {:.1} KiB/s", queue.upload_rate / 1024.0),
+        format!("Power    {}", power),
+        format!("Record   {}", recording),
+        "[S]kip [P]ause queue [C]ycle power [R]ecord [O]verlay".to_owned(),
+    ]
+}
+
+/// Formats a duration as minutes and seconds.
+fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
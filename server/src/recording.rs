@@ -1,125 +1,626 @@
-
-use std::io::BufWriter;
+use std::fmt;
+use std::io::{self, BufWriter, Write};
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use hound::{Sample, WavSpec, WavWriter};
-use image::RgbImage;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crossbeam_channel::{bounded, never, select, unbounded, Sender, TrySendError};
+use hound::{WavSpec, WavWriter};
+use serde::Serialize;
+use remote64_common::{INFO_VERSION, RecordingFile, RecordingInfo};
+use remote64_common::intercom::{Endpoint, InterMessage};
+use remote64_common::rom::RomIdentity;
+use remote64_common::util::sha256_file;
+use crate::audio::{CHANNELS, SAMPLE_RATE};
+
+/// Every session is recorded into its own directory in here (unless configured otherwise), named after the session's ID.
+pub const REC_PATH: &str = "recording/";
+pub const WAV_FILE: &str = "audio.wav";
+pub const VIDEO_FILE: &str = "video.mp4";
+pub const COMBINED_FILE: &str = "recording.mp4";
+pub const SIDECAR_FILE: &str = "session.json";
+
+/// Size of the queue of frames waiting to be encoded. Frames captured while it is full are dropped.
+const ENCODER_QUEUE: usize = 60;
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// The session's directory couldn't be created, so nothing can be recorded.
+    Directory(PathBuf, io::Error),
+    /// The audio file couldn't be created, or written to.
+    Audio(hound::Error),
+    /// The encoder couldn't be started, or stopped taking frames.
+    Encoder(io::Error),
+    /// The encoded video and the audio couldn't be combined into the final recording.
+    Mux(String),
+    /// The session's metadata couldn't be saved.
+    Sidecar(PathBuf, String),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Directory(path, err) => write!(f, "failed to create {}: {}", path.display(), err),
+            Error::Audio(err) => write!(f, "failed to record audio: {}", err),
+            Error::Encoder(err) => write!(f, "failed to encode video: {}", err),
+            Error::Mux(err) => write!(f, "failed to mux the recording: {}", err),
+            Error::Sidecar(path, err) => write!(f, "failed to save {}: {}", path.display(), err),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// Encodes frames into an H.264 video by piping them into `ffmpeg`, on its own thread so that the
+/// capture loop never waits on the encoder or the disk.
+struct Encoder {
+    frames: Sender<Vec<u8>>,
+    thread: JoinHandle<()>,
+}
+impl Encoder {
+    fn spawn(path: &Path, width: u32, height: u32, frame_rate: f64) -> Result<Self, Error> {
+        let mut child = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-y", "-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .args(["-s", &format!("{}x{}", width, height), "-framerate", &format!("{:.3}", frame_rate), "-i", "-"])
+            .args(["-c:v", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(Error::Encoder)?;
+        let mut stdin = child.stdin.take().ok_or_else(|| Error::Encoder(io::Error::other("ffmpeg has no stdin")))?;
+        
+        let (frames, recv) = bounded::<Vec<u8>>(ENCODER_QUEUE);
+        let thread = std::thread::Builder::new().name("Encoder".to_owned()).spawn(move || {
+            while let Ok(frame) = recv.recv() {
+                if let Err(err) = stdin.write_all(&frame) {
+                    error!("Failed to pass frame to the encoder: {}", err);
+                    break;
+                }
+            }
+            drop(stdin);
+            
+            match child.wait() {
+                Ok(status) if !status.success() => error!("Encoder exited with {}", status),
+                Err(err) => error!("Failed to wait for the encoder: {}", err),
+                _ => ()
+            }
+        }).map_err(Error::Encoder)?;
+        
+        Ok(Self {
+            frames,
+            thread,
+        })
+    }
+    
+    /// Encodes any queued frames, and waits for the video to be finalized.
+    fn finish(self) {
+        drop(self.frames);
+        self.thread.join().unwrap_or_default();
+    }
+}
+
+
+/// Metadata saved next to a session's recording, as `session.json`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sidecar {
+    pub session: String,
+    pub client: String,
+    pub server_version: String,
+    pub protocol_version: u16,
+    /// Unix time at which the session started.
+    pub started_at: u64,
+    pub ended_at: Option<u64>,
+    pub rom: Option<RomSummary>,
+    pub frame_rate: f64,
+    pub frames: u32,
+    pub dropped_frames: u32,
+    pub events: Vec<Event>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RomSummary {
+    pub size: usize,
+    pub sha256: String,
+    pub title: Option<String>,
+    pub game_code: Option<String>,
+    pub version: Option<u8>,
+}
+impl From<RomIdentity> for RomSummary {
+    fn from(rom: RomIdentity) -> Self { Self {
+        size: rom.size,
+        sha256: rom.sha256,
+        title: rom.header.as_ref().map(|header| header.title.clone()),
+        game_code: rom.header.as_ref().map(|header| header.game_code.clone()),
+        version: rom.header.as_ref().map(|header| header.version),
+    }}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// Seconds since the session started.
+    pub at_secs: f64,
+    pub message: String,
+}
 
-pub const REC_PATH: &'static str = "recording/";
-pub const WAV_PATH: &'static str = "recording/audio.wav";
 
 pub struct Recording {
-    wav_writer: WavWriter<BufWriter<File>>,
-    img: RgbImage,
+    root: PathBuf,
+    wav_writer: Option<WavWriter<BufWriter<File>>>,
+    width: u32,
+    height: u32,
+    frame_rate: f64,
+    encoder: Option<Encoder>,
+    session: Option<u64>,
+    sidecar: Option<Sidecar>,
+    started_at: Instant,
     
     frame_index: u32,
+    dropped_frames: u32,
     started: bool,
+    /// Frames aren't recorded while paused, by the operator.
+    paused: bool,
 }
 impl Recording {
-    pub fn new(width: u32, height: u32) -> Self {
+    /// `frame_rate` should match the rate frames are captured at, so the video plays back in real time.
+    pub fn new(root: PathBuf, width: u32, height: u32, frame_rate: f64) -> Self {
         Self {
-            wav_writer: get_wav_writer(WAV_PATH, 2, 44100.0).unwrap(),
-            img: RgbImage::new(width, height),
+            root,
+            wav_writer: None,
+            width,
+            height,
+            frame_rate,
+            encoder: None,
+            session: None,
+            sidecar: None,
+            started_at: Instant::now(),
             frame_index: 0,
+            dropped_frames: 0,
             started: false,
+            paused: false,
         }
     }
     
-    /// Starts the recording.
+    /// Starts recording a session into its own directory, within `root`.
     /// 
     /// If recording was already started, it must be ended otherwise this does nothing.
     /// 
-    /// When started, the framebuffer is cleared, frame counter reset to 0, and audio writer restarted.
-    pub fn start(&mut self) {
-        if self.started { return }
-        
-        let path = Path::new(REC_PATH);
-        if path.is_dir() {
-            for path in path.read_dir().unwrap() {
-                match path {
-                    Ok(entry) => {
-                        let path = entry.path();
-                        if path.is_file() && (
-                                path.extension().unwrap_or_default().eq_ignore_ascii_case("mp4") ||
-                                path.extension().unwrap_or_default().eq_ignore_ascii_case("bmp") ||
-                                path.extension().unwrap_or_default().eq_ignore_ascii_case("wav")
-                            ) {
-                            std::fs::remove_file(path).unwrap();
-                        }
-                    },
-                    Err(_) => ()
-                }
-            }
-        }
+    /// When started, the frame counter is reset to 0, and a new audio writer and encoder are started.
+    /// Fails if nothing at all can be recorded. If only the audio writer or the encoder fails to
+    /// start, recording carries on without it, noting the failure in the session's metadata.
+    pub fn start(&mut self, session: u64, client: String) -> Result<(), Error> {
+        if self.started { return Ok(()) }
+        
+        let dir = session_dir(&self.root, session);
+        std::fs::create_dir_all(&dir).map_err(|err| Error::Directory(dir.clone(), err))?;
         
-        self.wav_writer = get_wav_writer(WAV_PATH, 2, 44100.0).unwrap();
-        self.img.fill(0);
         self.frame_index = 0;
+        self.dropped_frames = 0;
+        self.paused = false;
+        
+        self.session = Some(session);
+        self.started_at = Instant::now();
+        self.sidecar = Some(Sidecar {
+            session: format!("{:016x}", session),
+            client,
+            server_version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol_version: INFO_VERSION,
+            started_at: unix_time(),
+            ended_at: None,
+            rom: None,
+            frame_rate: self.frame_rate,
+            frames: 0,
+            dropped_frames: 0,
+            events: vec![],
+        });
+        self.save_sidecar();
+        
+        self.wav_writer = match get_wav_writer(&dir.join(WAV_FILE), CHANNELS as i32, SAMPLE_RATE as f64) {
+            Ok(writer) => Some(writer),
+            Err(err) => {
+                self.failed(err, "only video will be recorded");
+                None
+            }
+        };
+        self.encoder = match Encoder::spawn(&dir.join(VIDEO_FILE), self.width, self.height, self.frame_rate) {
+            Ok(encoder) => Some(encoder),
+            Err(err) => {
+                self.failed(err, "only audio will be recorded");
+                None
+            }
+        };
         
         self.started = true;
+        Ok(())
     }
     
-    /// Saves the current frame data, and increments the internal frame counter.
+    /// Logs a failure to record part of the session, and notes it in the session's metadata.
+    fn failed(&mut self, err: Error, consequence: &str) {
+        error!("{}, {}.", err, consequence);
+        self.event(format!("Recording failed ({}), {}.", err, consequence));
+    }
+    
+    /// Queues a frame (packed RGB) for encoding, and records the audio that goes with it.
     /// 
-    /// Recording must have been started, otherwise this does nothing.
-    pub fn frame(&mut self) {
-        if !self.started { return }
+    /// Frames are expected at a steady `frame_rate`, each with the audio captured during it, as
+    /// produced by `AvAligner`. Recording must have been started and not paused, otherwise this does nothing.
+    pub fn frame(&mut self, video: &[u8], audio: &[f32]) {
+        if !self.started || self.paused { return }
         
-        self.img.save(format!("recording/output-{:08}.bmp", self.frame_index)).unwrap();
+        // A dropped frame also drops its audio, so the two stay in sync
+        let queued = self.encoder.as_ref().map(|encoder| encoder.frames.try_send(video.to_vec()));
+        match queued {
+            Some(Err(TrySendError::Full(_))) => {
+                warn!("Encoder is falling behind, dropped frame {}.", self.frame_index);
+                self.dropped_frames += 1;
+                self.frame_index += 1;
+                return;
+            },
+            Some(Err(TrySendError::Disconnected(_))) => {
+                if let Some(encoder) = self.encoder.take() {
+                    encoder.finish();
+                }
+                self.failed(Error::Encoder(io::Error::other("the encoder exited")), "only audio will be recorded from now on");
+            },
+            _ => (),
+        }
+        
+        if let Some(writer) = &mut self.wav_writer {
+            if let Err(err) = audio.iter().try_for_each(|sample| writer.write_sample(*sample)) {
+                self.wav_writer = None;
+                self.failed(Error::Audio(err), "only video will be recorded from now on");
+            }
+        }
         
         self.frame_index += 1;
     }
     
-    pub fn set_pixel_i(&mut self, i: u32, r: u8, g: u8, b: u8) {
-        let width = self.img.width();
-        let x = i % width;
-        let y = i / width;
+    pub fn started(&self) -> bool { self.started }
+    
+    pub fn paused(&self) -> bool { self.paused }
+    
+    /// Pauses or resumes recording frames, noting it in the session's metadata. Returns true if now paused.
+    /// Does nothing unless recording was started.
+    pub fn toggle_pause(&mut self) -> bool {
+        if !self.started { return false }
         
-        self.set_pixel_xy(x, y, r, g, b);
+        self.paused = !self.paused;
+        self.event(if self.paused { "Recording paused by the operator." } else { "Recording resumed by the operator." }.to_owned());
+        self.paused
+    }
+    
+    /// Notes an event in the session's metadata.
+    pub fn event(&mut self, message: String) {
+        let at_secs = self.started_at.elapsed().as_secs_f64();
+        if let Some(sidecar) = &mut self.sidecar {
+            sidecar.events.push(Event {
+                at_secs,
+                message,
+            });
+        }
+        self.save_sidecar();
     }
     
-    pub fn set_pixel_xy(&mut self, x: u32, y: u32, r: u8, g: u8, b: u8) {
-        (*self.img.get_pixel_mut(x, y)).0 = [r, g, b];
+    /// Notes the ROM that was uploaded in the session's metadata.
+    pub fn rom(&mut self, rom: RomIdentity) {
+        if let Some(sidecar) = &mut self.sidecar {
+            sidecar.rom = Some(rom.into());
+        }
+        self.save_sidecar();
     }
     
-    pub fn sample<S: Sample>(&mut self, sample: S) {
-        if !self.started { return }
+    /// Ends the recording, then muxes the encoded video with the recorded audio.
+    /// 
+    /// The video is encoded while recording, so this only has to copy it alongside the audio.
+    /// Returns the ID of the session that was recorded.
+    pub fn end(&mut self) -> Option<u64> {
+        if !self.started { return None }
         
-        self.wav_writer.write_sample(sample).unwrap();
+        self.started = false;
+        let session = self.session?;
+        let dir = session_dir(&self.root, session);
+        
+        if let Some(writer) = self.wav_writer.take() {
+            if let Err(err) = writer.finalize() {
+                self.failed(Error::Audio(err), "the audio may be cut short");
+            }
+        }
+        
+        if let Some(encoder) = self.encoder.take() {
+            encoder.finish();
+            info!("Encoded {} frames at {:.3} fps.", self.frame_index, self.frame_rate);
+            
+            match mux(&dir) {
+                Ok(()) => info!("Recording saved to {}", dir.display()),
+                Err(err) => self.failed(err, "only the separate video and audio were saved"),
+            }
+        }
+        
+        let (frames, dropped_frames) = (self.frame_index, self.dropped_frames);
+        if let Some(sidecar) = &mut self.sidecar {
+            sidecar.ended_at = Some(unix_time());
+            sidecar.frames = frames;
+            sidecar.dropped_frames = dropped_frames;
+        }
+        self.save_sidecar();
+        self.sidecar = None;
+        self.session = None;
+        
+        Some(session)
     }
     
-    pub fn started(&self) -> bool { self.started }
+    /// Saves the session's metadata. Failing to is only logged, the recording itself carries on.
+    fn save_sidecar(&self) {
+        let (session, sidecar) = match (self.session, &self.sidecar) {
+            (Some(session), Some(sidecar)) => (session, sidecar),
+            _ => return,
+        };
+        
+        let path = session_dir(&self.root, session).join(SIDECAR_FILE);
+        let result = File::create(&path).map_err(|err| err.to_string())
+            .and_then(|file| serde_json::to_writer_pretty(BufWriter::new(file), sidecar).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            error!("{}", Error::Sidecar(path, err));
+        }
+    }
+}
+
+
+/// Sent to the thread that owns the `Recording`, by the capture loop.
+enum Order {
+    /// A frame (packed RGB), with the audio captured during it.
+    Frame(Vec<u8>, Vec<f32>),
+    /// Pauses or resumes recording frames, on the operator's behalf.
+    TogglePause,
+    /// Notes that the server is shutting down, ends the recording, and stops the thread.
+    Shutdown,
+}
+
+/// What the capture loop needs to know about the recording, kept up to date by its owner.
+#[derive(Default)]
+struct Shared {
+    started: AtomicBool,
+    paused: AtomicBool,
+}
+
+/// Runs a `Recording` on its own thread, which is the only one that ever touches it.
+/// 
+/// Sessions are started, noted and ended through `InterMessage`s from the rest of the server, while the
+/// capture loop passes frames and the operator's pause toggle through this handle. Once a recording
+/// ends, it's offered for download to its session's client (see `InterMessage::RecordingReady`), and the
+/// retention policy is enforced, sparing any recording that can still be downloaded.
+pub struct Recorder {
+    orders: Sender<Order>,
+    shared: Arc<Shared>,
+    thread: JoinHandle<()>,
+}
+impl Recorder {
+    pub fn spawn(mut recording: Recording, retention: RetentionPolicy, endpoint: Endpoint) -> Self {
+        // Frames are only sent while recording, and handled quickly, so the queue doesn't grow
+        let (orders, recv) = unbounded::<Order>();
+        let shared = Arc::new(Shared::default());
+        let state = shared.clone();
+        
+        let thread = std::thread::Builder::new().name("Recorder".to_owned()).spawn(move || {
+            let mut messages = endpoint.recv.clone();
+            // Sessions whose recordings were offered for download, and when
+            let mut offered: Vec<(u64, Instant)> = vec![];
+            loop {
+                select! {
+                    recv(recv) -> order => match order {
+                        Ok(Order::Frame(video, audio)) => recording.frame(&video, &audio),
+                        Ok(Order::TogglePause) => if recording.started() {
+                            let paused = recording.toggle_pause();
+                            info!("Recording {}.", if paused { "paused" } else { "resumed" });
+                        } else {
+                            info!("No session is being recorded.");
+                        },
+                        Ok(Order::Shutdown) | Err(_) => {
+                            state.started.store(false, Ordering::Relaxed);
+                            recording.event("Server shut down.".to_owned());
+                            recording.end();
+                            break;
+                        },
+                    },
+                    recv(messages) -> msg => match msg {
+                        // A session that can't be recorded at all is ended, rather than going unrecorded
+                        Ok(InterMessage::StartRecording(session, client)) => match recording.start(session, client) {
+                            Ok(()) => info!("Recording started."),
+                            Err(err) => {
+                                error!("Failed to start recording: {}", err);
+                                let reason = format!("The session couldn't be recorded: {}", err);
+                                endpoint.send.try_send(InterMessage::EndSession(reason)).unwrap_or_default();
+                            },
+                        },
+                        Ok(InterMessage::SessionEvent(message)) => recording.event(message),
+                        Ok(InterMessage::SessionRom(rom)) => recording.rom(rom),
+                        Ok(InterMessage::StopRecording(session)) => {
+                            // Muxing takes a while, during which the capture loop stops sending frames
+                            state.started.store(false, Ordering::Relaxed);
+                            info!("Recording ended.");
+                            let ended = recording.end();
+                            
+                            if let Some(session) = session {
+                                match download_info(&retention.path, session) {
+                                    Ok(info) => {
+                                        endpoint.send.try_send(InterMessage::RecordingReady(info)).unwrap_or_default();
+                                        offered.push((session, Instant::now()));
+                                    },
+                                    Err(err) => error!("Failed to prepare recording for download: {}", err),
+                                }
+                            }
+                            
+                            // Recordings that may still be downloaded are kept, along with the one that just ended
+                            offered.retain(|(_, at)| at.elapsed() < retention.expiry);
+                            let keep: Vec<u64> = ended.into_iter().chain(offered.iter().map(|(session, _)| *session)).collect();
+                            retention.enforce(&keep);
+                        },
+                        Ok(_) => (),
+                        Err(_) => {
+                            info!("Recording endpoint died.");
+                            messages = never();
+                        },
+                    },
+                }
+                
+                state.started.store(recording.started(), Ordering::Relaxed);
+                state.paused.store(recording.paused(), Ordering::Relaxed);
+            }
+        }).unwrap();
+        
+        Self {
+            orders,
+            shared,
+            thread,
+        }
+    }
+    
+    pub fn started(&self) -> bool { self.shared.started.load(Ordering::Relaxed) }
     
-    pub fn end(&mut self) {
-        if !self.started { return }
+    pub fn paused(&self) -> bool { self.shared.paused.load(Ordering::Relaxed) }
+    
+    /// Records a frame (packed RGB) and the audio that goes with it, if a session is being recorded and not paused.
+    /// See `Recording::frame()`.
+    pub fn frame(&self, video: &[u8], audio: &[f32]) {
+        if !self.started() || self.paused() { return }
         
-        self.started = false;
+        self.orders.send(Order::Frame(video.to_vec(), audio.to_vec())).unwrap_or_default();
+    }
+    
+    /// Pauses or resumes recording frames. See `Recording::toggle_pause()`.
+    pub fn toggle_pause(&self) {
+        self.orders.send(Order::TogglePause).unwrap_or_default();
+    }
+    
+    /// Ends the recording, and waits for it to be finalized.
+    pub fn shutdown(self) {
+        self.orders.send(Order::Shutdown).unwrap_or_default();
+        self.thread.join().unwrap_or_default();
+    }
+}
+
+
+/// Limits on how much recorded evidence the server keeps around.
+/// 
+/// Whole sessions are deleted, oldest first, once they are older than `max_age`, or while all
+/// sessions together take up more than `max_size` bytes.
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    /// Directory the sessions are recorded into.
+    pub path: PathBuf,
+    pub max_age: Option<Duration>,
+    pub max_size: Option<u64>,
+    /// How long a recording offered for download can be downloaded for, during which it is kept.
+    pub expiry: Duration,
+}
+impl RetentionPolicy {
+    /// Deletes any sessions that fall outside of the policy, except for the `keep` sessions.
+    pub fn enforce(&self, keep: &[u64]) {
+        if self.max_age.is_none() && self.max_size.is_none() { return }
         
-        self.wav_writer.flush().unwrap();
+        let entries = match self.path.read_dir() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
         
-        Command::new("ffmpeg")
-            .args(["-loglevel", "quiet", "-framerate", "15", "-i", "recording/output-%08d.bmp", "-c:v", "libx264", "recording/output.mp4", "-y"])
-            .stdout(Stdio::null())
-            .spawn().unwrap()
-            .wait().unwrap();
-        Command::new("ffmpeg")
-            .args(["-loglevel", "quiet", "-i", "recording/output.mp4", "-i", "recording/audio.wav", "-c:v", "copy", "-c:a", "aac", "recording/output-combined.mp4", "-y"])
-            .stdout(Stdio::null())
-            .spawn().unwrap();
-        info!("Recording save attempted.");
+        // Only directories with a sidecar are sessions; anything else is left alone
+        let mut sessions = vec![];
+        for entry in entries.flatten() {
+            let dir = entry.path();
+            let modified = match dir.join(SIDECAR_FILE).metadata().and_then(|meta| meta.modified()) {
+                Ok(modified) => modified,
+                Err(_) => continue,
+            };
+            if keep.iter().any(|keep| dir == session_dir(&self.path, *keep)) { continue }
+            
+            sessions.push((modified, dir_size(&dir), dir));
+        }
+        sessions.sort_by_key(|(modified, _, _)| *modified);
+        
+        let mut total: u64 = sessions.iter().map(|(_, size, _)| size).sum();
+        for keep in keep {
+            total += dir_size(&session_dir(&self.path, *keep));
+        }
+        
+        for (modified, size, dir) in sessions {
+            let expired = self.max_age.map(|max_age| modified.elapsed().unwrap_or_default() > max_age).unwrap_or(false);
+            let oversized = self.max_size.map(|max_size| total > max_size).unwrap_or(false);
+            if !expired && !oversized { continue }
+            
+            match std::fs::remove_dir_all(&dir) {
+                Ok(_) => {
+                    info!("Deleted recording {} ({:.1} MiB) due to the retention policy.", dir.display(), size as f64 / 1048576.0);
+                    total = total.saturating_sub(size);
+                },
+                Err(err) => warn!("Failed to delete recording {}: {}", dir.display(), err),
+            }
+        }
     }
 }
 
-fn get_wav_writer(path: &'static str, channels: i32, sample_rate: f64) -> Result<WavWriter<BufWriter<File>>, String> {
-    if let Some(parent) = Path::new(path).parent() {
-        std::fs::create_dir_all(parent).unwrap();
+fn dir_size(dir: &Path) -> u64 {
+    match dir.read_dir() {
+        Ok(entries) => entries.flatten()
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|meta| meta.is_file())
+            .map(|meta| meta.len())
+            .sum(),
+        Err(_) => 0,
     }
+}
+
+/// Directory holding a session's recording and metadata.
+pub fn session_dir(root: &Path, session: u64) -> PathBuf {
+    root.join(format!("{:016x}", session))
+}
+
+/// Describes the files of a session's recording that may be downloaded by its client.
+pub fn download_info(root: &Path, session: u64) -> std::io::Result<RecordingInfo> {
+    let dir = session_dir(root, session);
+    
+    let mut files = vec![];
+    for name in [COMBINED_FILE, WAV_FILE] {
+        let path = dir.join(name);
+        if !path.is_file() { continue }
+        
+        files.push(RecordingFile {
+            name: name.to_owned(),
+            size: path.metadata()?.len(),
+            sha256: sha256_file(&path)?,
+        });
+    }
+    
+    Ok(RecordingInfo {
+        session,
+        files,
+    })
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn get_wav_writer(path: &Path, channels: i32, sample_rate: f64) -> Result<WavWriter<BufWriter<File>>, Error> {
+    WavWriter::create(path, wav_spec(channels, sample_rate)).map_err(Error::Audio)
+}
+
+/// Combines a session's encoded video and recorded audio into the final recording.
+fn mux(dir: &Path) -> Result<(), Error> {
+    let status = Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-y"])
+        .arg("-i").arg(dir.join(VIDEO_FILE))
+        .arg("-i").arg(dir.join(WAV_FILE))
+        .args(["-c:v", "copy", "-c:a", "aac"])
+        .arg(dir.join(COMBINED_FILE))
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|err| Error::Mux(err.to_string()))?;
     
-    let spec = wav_spec(channels, sample_rate);
-    match WavWriter::create(path, spec) {
-        Ok(writer) => Ok(writer),
-        Err(error) => Err (String::from(format!("{}",error))),
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Mux(format!("ffmpeg exited with {}", status))),
     }
 }
 
@@ -130,4 +631,4 @@ fn wav_spec(channels: i32, sample_rate: f64) -> WavSpec {
         bits_per_sample: (std::mem::size_of::<f32>() * 8) as _,
         sample_format: hound::SampleFormat::Float,
     }
-}
\ No newline at end of file
+}
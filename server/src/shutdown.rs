@@ -0,0 +1,23 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+
+/// Makes SIGINT and SIGTERM ask the server to shut down gracefully, rather than killing it outright.
+pub fn install_handler() {
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // Safety: the handler only stores into an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// True once a shutdown was requested by a signal.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+extern "C" fn handle(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
@@ -1,29 +1,114 @@
 
+use std::collections::HashMap;
 use std::collections::vec_deque::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
+use crossbeam_channel::{Select, SendTimeoutError, Sender};
 use crossbeam_queue::SegQueue;
-use remote64_common::{Feature, Packet, Packet::*, ServerInfo};
-use remote64_common::intercom::{Endpoint, InterMessage};
-use remote64_common::network::{Server, SocketConnection};
+use remote64_common::{INFO_HEADER, INFO_VERSION, Packet, Packet::*, RecordingChunk, RecordingInfo, ServerInfo};
+use remote64_common::auth::{self, Challenge};
+use remote64_common::intercom::{Endpoint, InterMessage, QueueStatus};
+use remote64_common::network::{self, Event, Message, Server, SocketConnection};
+use remote64_common::network::tls::TlsServer;
+use remote64_common::rom::RomIdentity;
+use crate::config::Config;
+use crate::recording;
 
-pub const INFO_HEADER: [u8; 4] = [0x52, 0x4D, 0x36, 0x34]; // RM64
-pub const INFO_VERSION: u16 = 0x0000;
+pub const ROM_PATH: &str = "rom/upload.z64";
+/// Size of the pieces recordings are split into when downloaded.
+const CHUNK_SIZE: usize = 256 * 1024;
+/// Longest a recording download may wait for the client's connection to take the next piece.
+const DOWNLOAD_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest the manager waits for `Close` to be sent to every client, when shutting down.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the queue's status is published to the rest of the server.
+const STATUS_INTERVAL: Duration = Duration::from_millis(500);
+/// Longest the manager waits for anything to happen, before checking its timers (e.g. for pinging clients) again.
+const TICK: Duration = Duration::from_millis(100);
+/// Longest a client has to authenticate after connecting, when authentication is required.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes sent to clients since the last status was published.
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
 
 
 /// Contains the status of a connected client.
 pub struct SocketClient {
     socket: SocketConnection,
+    connected_at: Instant,
+    /// Set once the client authenticates, or from the start when authentication isn't required. Only
+    /// authenticated clients are part of the queue.
+    authenticated: bool,
+    /// User the client authenticated as.
+    user: Option<String>,
+    /// User and nonce of the challenge the client was sent, until it answers.
+    challenge: Option<(String, [u8; auth::NONCE_LEN])>,
     last_ping: Instant,
     last_pong: Instant,
     waiting: bool,
+    /// Identifies the client's session once it has been serviced. Used to claim the session's recording.
+    session: Option<u64>,
+    /// When the client started being serviced.
+    serviced_at: Option<Instant>,
+    /// Set once the client ends its session. It stays connected to download the recording, but leaves the queue.
+    finished: bool,
 }
 impl SocketClient {
-    pub fn new(socket: SocketConnection) -> Self { Self {
+    pub fn new(socket: SocketConnection, authenticated: bool) -> Self { Self {
         socket: socket,
+        connected_at: Instant::now(),
+        authenticated,
+        user: None,
+        challenge: None,
         last_ping: Instant::now(),
         last_pong: Instant::now(),
         waiting: true,
+        session: None,
+        serviced_at: None,
+        finished: false,
     }}
+    
+    /// True while the client is being serviced.
+    fn active(&self) -> bool {
+        !self.waiting && !self.finished
+    }
+    
+    /// True while the client is in the queue, waiting or being serviced.
+    fn queued(&self) -> bool {
+        self.authenticated && !self.finished
+    }
+    
+    /// The client's address, and the user it authenticated as, if any.
+    fn name(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.socket.peer),
+            None => self.socket.peer.to_string(),
+        }
+    }
+    
+    /// Ends the client's session on the server's behalf. It stays connected to download its recording,
+    /// but any further requests for the session are denied.
+    fn end_session(&mut self, reason: &str, endpoint: &Endpoint) {
+        info!("Ended client {}'s session: {}", self.socket.peer, reason);
+        self.finished = true;
+        send_packet(self, RequestDenied);
+        endpoint.send.try_send(InterMessage::SessionEvent(reason.to_owned())).unwrap_or_default();
+        endpoint.send.try_send(InterMessage::StopRecording(self.session)).unwrap_or_default();
+    }
+}
+
+/// A finished recording, waiting for its client to download it.
+struct Download {
+    info: RecordingInfo,
+    ready_at: Instant,
+    /// Thread sending the recording to the client, once it asked for it.
+    sender: Option<JoinHandle<()>>,
 }
 
 
@@ -33,35 +118,77 @@ impl SocketClient {
 /// updated on their position in the queue, and removing any clients that disconnect or
 /// fail to respond to pings.
 /// 
+/// When `auth.required` is set, clients must authenticate before they are queued, and are disconnected
+/// if they don't within `AUTH_TIMEOUT`. A client authenticates by sending `Packet::AuthRequest` with a
+/// user's name, then answering the `Packet::AuthChallenge` it gets with proof of the user's secret.
+/// 
 /// The manager also acts as a relay between the active client and the rest of the
 /// server's components (e.g. handling `Packet` transmissions).
+/// 
+/// The queue's status is published regularly as `InterMessage::QueueStatus`. The active client's session
+/// can be ended with `InterMessage::EndSession`, and the queue paused with `InterMessage::PauseQueue`, so that
+/// no further clients are serviced. The queue is also held while the server is degraded, after being
+/// told with `InterMessage::Degraded` that a component failed, which clients asking for the server's
+/// info are told about too. When sent `InterMessage::Kill`, the manager closes every client
+/// connection and its thread exits.
 pub struct SocketManager {
     socket: Server,
     client_queue: VecDeque<SocketClient>,
 }
 
 impl SocketManager {
-    pub fn init(features: Vec<Feature>, endpoint: Endpoint) {
-        let server_info = ServerInfo {
+    pub fn init(config: &Config, endpoint: Endpoint) -> Result<JoinHandle<()>, network::Error> {
+        let mut server_info = ServerInfo {
             header: INFO_HEADER,
             version: INFO_VERSION,
-            features,
+            degraded: vec![],
+            features: config.features.clone(),
         };
+        let loader = config.loader.clone();
+        let network = config.network.clone();
+        let queue = config.queue.clone();
+        let recordings = config.recording.path.clone();
+        let expiry = config.recording.expiry;
+        let auth_config = config.auth.clone();
+        // Unknown users are sent challenges that look like real users', from this key
+        let decoy_key: [u8; 32] = rand::random();
         
-        let socket = Server::new("0.0.0.0:6400");
+        let tls = match (&config.tls.cert, &config.tls.key) {
+            (Some(cert), Some(key)) => Some(TlsServer::new(cert, key)?),
+            _ => None,
+        };
+        let fingerprint = tls.as_ref().map(|tls| tls.fingerprint().to_owned());
+        let socket = Server::new(network.listen.as_str(), network.limits(), tls)?;
+        match fingerprint {
+            Some(fingerprint) => info!("Listening on {} over TLS. Certificate fingerprint: {}", network.listen, fingerprint),
+            None => info!("Listening on {}.", network.listen),
+        }
         
         let mut sm = SocketManager {
             socket,
             client_queue: VecDeque::new(),
         };
         
+        let mut downloads: HashMap<u64, Download> = HashMap::new();
+        // Set while an uploaded ROM is being loaded. There's only the one console, so uploads are denied meanwhile
+        let loading = Arc::new(AtomicBool::new(false));
+        let mut paused = false;
+        let mut last_status = Instant::now();
+        
         let frame_queue = SegQueue::new();
-        std::thread::Builder::new().name("SocketManager".to_owned()).spawn(move || {
-            loop {
+        let thread = std::thread::Builder::new().name("SocketManager".to_owned()).spawn(move || {
+            'running: loop {
                 // Accept any waiting connection requests, and add them to the queue
                 while let Some(client) = sm.socket.accept() {
+                    let queued = sm.client_queue.iter().filter(|client| client.queued()).count();
+                    if queue.max_clients.map(|max| queued >= max).unwrap_or(false) {
+                        info!("Client {} turned away, the queue is full.", client.peer);
+                        client.send.try_send(RequestDenied.serialize()).unwrap_or_default();
+                        continue;
+                    }
+                    
                     info!("Client {} connected.", client.peer);
-                    sm.client_queue.push_back(SocketClient::new(client));
+                    sm.client_queue.push_back(SocketClient::new(client, !auth_config.required));
                 }
                 
                 // Client keep-alive and message passing
@@ -70,15 +197,38 @@ impl SocketManager {
                 //   This section also handles passing of messages between the server and clients.
                 //   The client at the front of the queue is the "active" client. Image requests
                 //     from "non-active" clients will be rejected.
+                //   Finished clients are no longer part of the queue, and may only download their recording.
+                //   Clients only join the queue once authenticated, if authentication is required.
                 let mut disconnects = vec![];
+                let mut position = 0;
                 for (i, client) in sm.client_queue.iter_mut().enumerate() {
-                    if i == 0 && client.waiting {
+                    let queue_position = if client.queued() {
+                        position += 1;
+                        Some(position - 1)
+                    } else {
+                        None
+                    };
+                    
+                    if queue_position == Some(0) && client.waiting && !paused && server_info.degraded.is_empty() {
                         client.waiting = false;
-                        endpoint.send.try_send(InterMessage::StartRecording).unwrap_or_default();
-                        info!("Client {} is being serviced now.", client.socket.peer);
+                        client.session = Some(rand::random());
+                        client.serviced_at = Some(Instant::now());
+                        endpoint.send.try_send(InterMessage::StartRecording(client.session.unwrap_or_default(), client.name())).unwrap_or_default();
+                        info!("Client {} is being serviced now.", client.name());
                     }
                     
-                    while let Ok(msg) = client.socket.recv.try_recv() {
+                    while let Ok(event) = client.socket.recv.try_recv() {
+                        let msg = match event {
+                            Event::Message(msg) => msg,
+                            Event::Disconnected(err) => {
+                                disconnects.push(i);
+                                match err {
+                                    Some(err) => warn!("Client {} lost its connection: {}", client.socket.peer, err),
+                                    None => info!("Client {} disconnected.", client.socket.peer),
+                                }
+                                break;
+                            },
+                        };
                         let packet = match Packet::deserialize(&msg) {
                             Ok(packet) => packet,
                             Err(err) => {
@@ -88,8 +238,43 @@ impl SocketManager {
                         };
                         
                         match packet {
+                            QueueRequest | FrameRequest(_) | RomUpload(_) | SessionEnd | RecordingRequest(_) | RecordingReceived(_) if !client.authenticated => {
+                                send_packet(client, RequestDenied);
+                            },
+                            
+                            AuthRequest(_) if client.user.is_some() => send_packet(client, RequestDenied),
+                            AuthRequest(user) => {
+                                let challenge = Challenge::new(&user, auth_config.secret(&user), rand::random(), &decoy_key);
+                                debug!("Client {} is authenticating as {}.", client.socket.peer, user);
+                                client.challenge = Some((user, challenge.nonce));
+                                send_packet(client, AuthChallenge(challenge));
+                            },
+                            AuthResponse(proof) => {
+                                let verified = client.challenge.take().and_then(|(user, nonce)| {
+                                    let secret = auth_config.secret(&user)?;
+                                    secret.verify(&user, &nonce, &proof).then_some(user)
+                                });
+                                match verified {
+                                    Some(user) => {
+                                        info!("Client {} authenticated as {}.", client.socket.peer, user);
+                                        client.user = Some(user);
+                                        client.authenticated = true;
+                                        send_packet(client, AuthAccepted);
+                                    },
+                                    None => {
+                                        warn!("Client {} failed to authenticate.", client.socket.peer);
+                                        send_packet(client, RequestDenied);
+                                        disconnects.push(i);
+                                        break;
+                                    },
+                                }
+                            },
+                            
                             InfoRequest => send_packet(client, InfoResponse(server_info.clone())),
-                            QueueRequest => send_packet(client, QueueResponse(i as u32)),
+                            QueueRequest => match queue_position {
+                                Some(position) => send_packet(client, QueueResponse(position)),
+                                None => send_packet(client, RequestDenied),
+                            },
                             Ping => {
                                 debug!("Ping! {}", client.socket.peer);
                                 send_packet(client, Pong);
@@ -99,7 +284,7 @@ impl SocketManager {
                                 client.last_pong = Instant::now();
                             },
                             
-                            FrameRequest(requested) if !client.waiting => { // if client is at front of queue
+                            FrameRequest(requested) if client.active() => { // if client is at front of queue
                                 //debug!("Sending pong instead of frames.");
                                 //send_packet(client, Pong);
                                 //debug!("Sending blank frame.");
@@ -128,20 +313,72 @@ impl SocketManager {
                             }
                             FrameRequest(_) => send_packet(client, RequestDenied),
                             
+                            RomUpload(_) if client.active() && loading.swap(true, Ordering::AcqRel) => {
+                                info!("Client {} uploaded a ROM while another was still loading, denied.", client.socket.peer);
+                                send_packet(client, RequestDenied);
+                            },
+                            RomUpload(rom) if client.active() => {
+                                info!("Client {} uploaded a {:.2} KiB ROM.", client.socket.peer, rom.len() as f64 / 1024.0);
+                                load_rom(rom, loader.clone(), loading.clone(), client.socket.send.clone(), endpoint.send.clone());
+                            },
+                            RomUpload(_) => send_packet(client, RequestDenied),
+                            
+                            SessionEnd if client.active() => {
+                                info!("Client {} ended its session.", client.socket.peer);
+                                client.finished = true;
+                                endpoint.send.try_send(InterMessage::SessionEvent("Client ended the session.".to_owned())).unwrap_or_default();
+                                endpoint.send.try_send(InterMessage::StopRecording(client.session)).unwrap_or_default();
+                            },
+                            // A session the server ended already has its recording on the way, which is sent once it's ready
+                            SessionEnd => match client.session.filter(|_| client.finished) {
+                                Some(session) => if let Some(download) = downloads.get(&session) {
+                                    send_packet(client, RecordingReady(download.info.clone()));
+                                },
+                                None => send_packet(client, RequestDenied),
+                            },
+                            // Only the client whose session it was may download a recording
+                            RecordingRequest(session) => match downloads.get_mut(&session).filter(|_| client.session == Some(session)) {
+                                // Two senders would interleave their pieces, so a repeated request is ignored
+                                Some(download) if download.sender.as_ref().map(|sender| !sender.is_finished()).unwrap_or(false) => {
+                                    debug!("Client {} is already downloading its recording.", client.socket.peer);
+                                },
+                                Some(download) => {
+                                    info!("Client {} is downloading its recording.", client.socket.peer);
+                                    download.sender = Some(send_recording(&recordings, download.info.clone(), client.socket.bulk.clone(), client.socket.send.clone()));
+                                },
+                                None => send_packet(client, RequestDenied),
+                            },
+                            RecordingReceived(session) => if downloads.remove(&session).is_some() {
+                                info!("Client {} downloaded its recording.", client.socket.peer);
+                            },
+                            
                             Close => {
                                 disconnects.push(i);
                                 info!("Client {} disconnected.", client.socket.peer);
                                 break;
                             },
                             
-                            InfoResponse(_) | QueueResponse(_) | FrameResponse(_) | RequestDenied | Unknown(_) => (),
+                            InfoResponse(_) | QueueResponse(_) | FrameResponse(_) | RomLoaded | RecordingReady(_) | RecordingData(_) | AuthChallenge(_) | AuthAccepted | RequestDenied | Unknown(_) => (),
+                        }
+                    }
+                    if client.active() {
+                        let elapsed = client.serviced_at.map(|at| at.elapsed()).unwrap_or_default();
+                        if queue.session_limit.map(|limit| elapsed > limit).unwrap_or(false) {
+                            client.end_session("Session time limit reached.", &endpoint);
                         }
                     }
-                    if client.last_pong.elapsed() > Duration::from_secs(22) {
+                    
+                    if !client.authenticated && client.connected_at.elapsed() > AUTH_TIMEOUT {
+                        info!("Client {} didn't authenticate in time.", client.socket.peer);
+                        send_packet(client, RequestDenied);
+                        disconnects.push(i);
+                        continue;
+                    }
+                    if client.last_pong.elapsed() > network.ping_timeout {
                         disconnects.push(i);
                         continue;
                     } else {
-                        if client.last_ping.elapsed() > Duration::from_secs(10) {
+                        if client.last_ping.elapsed() > network.ping_interval {
                             debug!("Ping! {}", client.socket.peer);
                             send_packet(client, Ping);
                             client.last_ping = Instant::now();
@@ -151,8 +388,9 @@ impl SocketManager {
                 disconnects.sort();
                 disconnects.dedup();
                 for i in disconnects.iter().rev() {
-                    if !sm.client_queue[*i].waiting {
-                        endpoint.send.try_send(InterMessage::StopRecording).unwrap_or_default();
+                    if sm.client_queue[*i].active() {
+                        endpoint.send.try_send(InterMessage::SessionEvent("Client disconnected.".to_owned())).unwrap_or_default();
+                        endpoint.send.try_send(InterMessage::StopRecording(None)).unwrap_or_default();
                     }
                     sm.client_queue.remove(*i);
                 }
@@ -162,20 +400,206 @@ impl SocketManager {
                     match msg {
                         InterMessage::LatestFrame(frame) => {
                             frame_queue.push(frame);
-                            while frame_queue.len() > 60 {
+                            while frame_queue.len() > queue.frame_buffer {
                                 frame_queue.pop();
                             }
                         },
+                        InterMessage::RecordingReady(info) => {
+                            if let Some(client) = sm.client_queue.iter_mut().find(|client| client.session == Some(info.session)) {
+                                send_packet(client, RecordingReady(info.clone()));
+                            }
+                            downloads.insert(info.session, Download {
+                                info,
+                                ready_at: Instant::now(),
+                                sender: None,
+                            });
+                        },
+                        InterMessage::EndSession(reason) => match sm.client_queue.iter_mut().find(|client| client.active()) {
+                            Some(client) => client.end_session(&reason, &endpoint),
+                            None => info!("No session to end."),
+                        },
+                        InterMessage::PauseQueue(pause) if pause != paused => {
+                            paused = pause;
+                            info!("Queue {}.", if paused { "paused" } else { "resumed" });
+                        },
+                        InterMessage::Degraded(component, failed) => {
+                            let was_degraded = !server_info.degraded.is_empty();
+                            server_info.degraded.retain(|degraded| *degraded != component);
+                            if failed {
+                                server_info.degraded.push(component);
+                            }
+                            
+                            match (was_degraded, server_info.degraded.is_empty()) {
+                                (false, false) => warn!("Server is degraded, holding the queue until it recovers."),
+                                (true, true) => info!("Server recovered, the queue moves again."),
+                                _ => (),
+                            }
+                        },
+                        InterMessage::Kill => break 'running,
                         _ => ()
                     }
                 }
                 
-                std::thread::sleep(Duration::from_nanos(1));
+                if last_status.elapsed() >= STATUS_INTERVAL {
+                    let active = sm.client_queue.iter().find(|client| client.active());
+                    let status = QueueStatus {
+                        active: active.map(|client| client.name()),
+                        session_elapsed: active.and_then(|client| client.serviced_at).map(|at| at.elapsed()).unwrap_or_default(),
+                        session_limit: queue.session_limit,
+                        waiting: sm.client_queue.iter().filter(|client| client.waiting && client.queued()).count(),
+                        paused,
+                        upload_rate: BYTES_SENT.swap(0, Ordering::Relaxed) as f64 / last_status.elapsed().as_secs_f64(),
+                    };
+                    endpoint.send.try_send(InterMessage::QueueStatus(status)).unwrap_or_default();
+                    last_status = Instant::now();
+                }
+                
+                // Stop offering recordings that weren't downloaded in time. The files themselves are
+                //   left to the retention policy.
+                downloads.retain(|session, download| {
+                    if download.ready_at.elapsed() < expiry { return true }
+                    
+                    info!("Download of session {:016x} expired.", session);
+                    false
+                });
+                
+                // Wait for a connection, a message from a client or the rest of the server, or the next tick
+                let mut select = Select::new();
+                select.recv(sm.socket.incoming());
+                select.recv(&endpoint.recv);
+                for client in sm.client_queue.iter() {
+                    select.recv(&client.socket.recv);
+                }
+                select.ready_timeout(TICK).unwrap_or_default();
+            }
+            
+            // Tell every client the server is going away, and give the messages a moment to go out
+            info!("Closing {} client connections.", sm.client_queue.len());
+            for client in sm.client_queue.iter_mut() {
+                send_packet(client, Close);
+            }
+            let closing = Instant::now();
+            while closing.elapsed() < CLOSE_TIMEOUT && sm.client_queue.iter().any(|client| !client.socket.send.is_empty()) {
+                std::thread::sleep(Duration::from_millis(10));
             }
         }).unwrap();
+        
+        Ok(thread)
     }
 }
 
 fn send_packet(client: &mut SocketClient, packet: Packet) {
-    client.socket.send.try_send(packet.serialize()).unwrap_or_default();
-}
\ No newline at end of file
+    let data = packet.serialize();
+    BYTES_SENT.fetch_add(data.len() as u64, Ordering::Relaxed);
+    client.socket.send.try_send(data).unwrap_or_default();
+}
+
+/// Saves an uploaded ROM to disk and runs the loader command on it, if one was configured.
+/// 
+/// Loading can take a while on real hardware, so this happens on its own thread. The client is sent
+/// `RomLoaded` once the loader exits successfully, or `RequestDenied` if anything fails. The ROM and
+/// the result are also noted in the session's recording. `loading` is cleared once done, before the client is told.
+fn load_rom(rom: Vec<u8>, loader: Option<String>, loading: Arc<AtomicBool>, send: Sender<Message>, events: Sender<InterMessage>) {
+    std::thread::spawn(move || {
+        let identity = RomIdentity::new(&rom);
+        let title = identity.header.as_ref().map(|header| header.title.clone()).unwrap_or_default();
+        events.try_send(InterMessage::SessionEvent(format!("ROM uploaded: {:?} ({} bytes, SHA-256 {}).", title, identity.size, identity.sha256))).unwrap_or_default();
+        events.try_send(InterMessage::SessionRom(identity)).unwrap_or_default();
+        
+        
+        let path = Path::new(ROM_PATH);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap_or_default();
+        }
+        if let Err(err) = std::fs::write(path, &rom) {
+            error!("Failed to save uploaded ROM: {}", err);
+            loading.store(false, Ordering::Release);
+            send.try_send(RequestDenied.serialize()).unwrap_or_default();
+            return;
+        }
+        
+        let loaded = match loader {
+            Some(loader) => {
+                let mut args = loader.split_whitespace();
+                match args.next() {
+                    Some(program) => match Command::new(program).args(args).arg(path).status() {
+                        Ok(status) if status.success() => true,
+                        Ok(status) => {
+                            error!("ROM loader exited with {}", status);
+                            false
+                        },
+                        Err(err) => {
+                            error!("Failed to run ROM loader: {}", err);
+                            false
+                        }
+                    },
+                    None => true,
+                }
+            },
+            None => {
+                warn!("No ROM loader configured; the uploaded ROM was only saved to {}", ROM_PATH);
+                true
+            }
+        };
+        
+        let event = if loaded { "ROM loaded." } else { "ROM failed to load." };
+        events.try_send(InterMessage::SessionEvent(event.to_owned())).unwrap_or_default();
+        
+        loading.store(false, Ordering::Release);
+        let packet = if loaded { RomLoaded } else { RequestDenied };
+        send.try_send(packet.serialize()).unwrap_or_default();
+    });
+}
+
+/// Sends every file of a recording to the client, split into `RecordingData` packets.
+/// 
+/// Recordings can be large, so this happens on its own thread, and the pieces go through the connection's
+/// bulk queue, which only holds a few at a time to keep memory use down. Gives up if the connection stops
+/// making progress. Errors are sent through `send`.
+fn send_recording(recordings: &Path, info: RecordingInfo, bulk: Sender<Message>, send: Sender<Message>) -> JoinHandle<()> {
+    let dir = recording::session_dir(recordings, info.session);
+    std::thread::spawn(move || {
+        for (index, file) in info.files.iter().enumerate() {
+            let mut reader = match File::open(dir.join(&file.name)) {
+                Ok(reader) => reader,
+                Err(err) => {
+                    error!("Failed to open recording {}: {}", file.name, err);
+                    send.try_send(RequestDenied.serialize()).unwrap_or_default();
+                    return;
+                }
+            };
+            
+            let mut offset = 0;
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            loop {
+                let len = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(err) => {
+                        error!("Failed to read recording {}: {}", file.name, err);
+                        return;
+                    }
+                };
+                
+                let chunk = RecordingChunk {
+                    session: info.session,
+                    file: index as u8,
+                    offset,
+                    data: buf[..len].to_vec(),
+                };
+                let data = RecordingData(chunk).serialize();
+                let sent = data.len() as u64;
+                match bulk.send_timeout(data, DOWNLOAD_STALL_TIMEOUT) {
+                    Ok(()) => (),
+                    Err(SendTimeoutError::Timeout(_)) => {
+                        warn!("Recording download of session {:016x} stalled.", info.session);
+                        return;
+                    },
+                    Err(SendTimeoutError::Disconnected(_)) => return,
+                }
+                BYTES_SENT.fetch_add(sent, Ordering::Relaxed);
+                offset += len as u64;
+            }
+        }
+    })
+}
@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Audio arriving this far away from where it was expected is treated as a gap or an overlap, rather
+/// than clock jitter.
+const TOLERANCE_SECS: f64 = 0.02;
+/// How long a frame may wait for its audio before being sent out with silence in its place.
+const MAX_WAIT_SECS: f64 = 0.5;
+/// Audio older than this, that no frame has claimed, is discarded.
+const MAX_BUFFERED_SECS: f64 = 2.0;
+
+
+/// Monotonic clock shared by the audio and video capture, so their timestamps can be compared.
+#[derive(Copy, Clone, Debug)]
+pub struct Clock {
+    start: Instant,
+}
+impl Clock {
+    pub fn new() -> Self { Self {
+        start: Instant::now(),
+    }}
+    
+    /// Seconds since the clock was created.
+    pub fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+}
+
+
+/// Pairs captured video frames with the audio captured alongside them, using their timestamps.
+/// 
+/// Every frame is given the audio from its own capture time, up until the next frame's. Positions are
+/// tracked in whole audio samples, so the audio handed out stays continuous over any length of time.
+/// Gaps in the audio are padded with silence, and overlapping audio is dropped. Frames missing from
+/// the video are filled in by repeating the previous frame, so the video keeps pace with the audio.
+/// 
+/// `offset` is how many seconds later audio is captured than the video it belongs to (e.g. due to
+/// device latency). Audio timestamps are shifted earlier by that much before pairing.
+pub struct AvAligner {
+    sample_rate: f64,
+    channels: usize,
+    frame_samples: i64,
+    offset: f64,
+    
+    audio: VecDeque<f32>,
+    /// Position of the first buffered audio sample.
+    audio_pos: Option<i64>,
+    frames: VecDeque<(i64, Vec<u8>)>,
+    /// Position where the next frame's audio starts.
+    next_pos: Option<i64>,
+    last_video: Vec<u8>,
+}
+impl AvAligner {
+    pub fn new(sample_rate: u32, channels: usize, frame_rate: f64, offset: f64) -> Self { Self {
+        sample_rate: sample_rate as f64,
+        channels,
+        frame_samples: (sample_rate as f64 / frame_rate).round() as i64,
+        offset,
+        audio: VecDeque::new(),
+        audio_pos: None,
+        frames: VecDeque::new(),
+        next_pos: None,
+        last_video: vec![],
+    }}
+    
+    /// Forgets any buffered audio and frames, and where the next frame was expected, so pairing starts
+    /// over. Used after capture was interrupted, rather than filling the gap with repeated frames.
+    pub fn reset(&mut self) {
+        self.audio.clear();
+        self.audio_pos = None;
+        self.frames.clear();
+        self.next_pos = None;
+    }
+    
+    /// Converts a clock time into a position in audio samples.
+    fn position(&self, time: f64) -> i64 {
+        (time * self.sample_rate).round() as i64
+    }
+    
+    /// Adds interleaved audio samples, the first of which was captured at `captured_at` (see `Clock`).
+    pub fn push_audio(&mut self, captured_at: f64, samples: &[f32]) {
+        let pos = self.position(captured_at - self.offset);
+        let mut samples = samples;
+        
+        match self.audio_pos {
+            None => self.audio_pos = Some(pos),
+            Some(audio_pos) => {
+                let expected = audio_pos + (self.audio.len() / self.channels) as i64;
+                let diff = pos - expected;
+                let tolerance = (TOLERANCE_SECS * self.sample_rate) as i64;
+                if diff > tolerance {
+                    debug!("Audio gap of {} samples, padding with silence.", diff);
+                    self.audio.extend(std::iter::repeat_n(0.0, diff as usize * self.channels));
+                } else if diff < -tolerance {
+                    let skip = ((-diff) as usize * self.channels).min(samples.len());
+                    debug!("Audio overlap of {} samples, dropping it.", -diff);
+                    samples = &samples[skip..];
+                }
+            }
+        }
+        self.audio.extend(samples);
+        
+        // Nothing is claiming the audio (e.g. no video is being captured), so don't let it pile up
+        let max = (MAX_BUFFERED_SECS * self.sample_rate) as usize * self.channels;
+        if self.audio.len() > max {
+            let excess = (self.audio.len() - max) / self.channels;
+            self.audio.drain(..(excess * self.channels));
+            self.audio_pos = self.audio_pos.map(|pos| pos + excess as i64);
+        }
+    }
+    
+    /// Adds a video frame captured at `captured_at` (see `Clock`).
+    pub fn push_frame(&mut self, captured_at: f64, video: Vec<u8>) {
+        let pos = self.position(captured_at);
+        self.frames.push_back((pos, video));
+    }
+    
+    /// Returns the next frame with its audio, once the audio for it has been captured, or has been
+    /// waited on for too long. `now` is the current clock time.
+    pub fn pop(&mut self, now: f64) -> Option<(Vec<u8>, Vec<f32>)> {
+        let frame_pos = self.frames.front()?.0;
+        let start = self.next_pos.unwrap_or(frame_pos);
+        
+        // Frames missing since the last one are filled in by repeating it
+        let repeat = self.next_pos.is_some() && frame_pos - start > self.frame_samples * 3 / 2;
+        let end = if repeat { start + self.frame_samples } else { frame_pos + self.frame_samples };
+        let end = end.max(start);
+        
+        let audio_end = self.audio_pos.map(|pos| pos + (self.audio.len() / self.channels) as i64).unwrap_or(i64::MIN);
+        let waited_too_long = self.position(now) - end > self.position(MAX_WAIT_SECS);
+        if audio_end < end && !waited_too_long {
+            return None;
+        }
+        
+        let video = if repeat {
+            self.last_video.clone()
+        } else {
+            let (_, video) = self.frames.pop_front()?;
+            self.last_video = video.clone();
+            video
+        };
+        
+        let mut audio = vec![0.0; (end - start) as usize * self.channels];
+        if let Some(audio_pos) = self.audio_pos {
+            // Audio from before this frame is no longer needed
+            if audio_pos < start {
+                let stale = ((start - audio_pos) as usize * self.channels).min(self.audio.len());
+                self.audio.drain(..stale);
+                self.audio_pos = Some(if self.audio.is_empty() { start } else { audio_pos + (stale / self.channels) as i64 });
+            }
+            
+            let audio_pos = self.audio_pos.unwrap_or(start);
+            if audio_pos < end {
+                let skip = (audio_pos - start) as usize * self.channels;
+                let take = ((end - audio_pos) as usize * self.channels).min(self.audio.len());
+                for (out, sample) in audio[skip..].iter_mut().zip(self.audio.drain(..take)) {
+                    *out = sample;
+                }
+                self.audio_pos = Some(audio_pos + (take / self.channels) as i64);
+            }
+        }
+        
+        self.next_pos = Some(end);
+        
+        Some((video, audio))
+    }
+}
@@ -0,0 +1,343 @@
+use std::str::FromStr;
+
+/// Luma above which a pixel counts as picture rather than border, when detecting borders.
+const BORDER_LUMA: u32 = 32;
+/// Fraction of a row or column that must be picture for it to count as part of the picture.
+const BORDER_COVERAGE: f64 = 0.02;
+/// Frames between border detections.
+const DETECT_INTERVAL: u32 = 30;
+/// Detections in a row that must agree (within a few pixels) before the crop is changed.
+const DETECT_STABLE: u32 = 3;
+const DETECT_SLACK: usize = 4;
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self { Self {
+        x,
+        y,
+        width,
+        height,
+    }}
+    
+    fn close_to(&self, other: &Rect) -> bool {
+        self.x.abs_diff(other.x) <= DETECT_SLACK && self.y.abs_diff(other.y) <= DETECT_SLACK
+            && self.width.abs_diff(other.width) <= DETECT_SLACK && self.height.abs_diff(other.height) <= DETECT_SLACK
+    }
+}
+
+/// Which part of the captured image is kept.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Crop {
+    None,
+    /// Detects the black borders around the picture, and follows them as they change.
+    Auto,
+    Fixed(Rect),
+}
+impl FromStr for Crop {
+    type Err = ();
+    
+    /// Parses `none`, `auto`, or a rectangle as `WIDTHxHEIGHT+X+Y`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "none" => return Ok(Crop::None),
+            "auto" => return Ok(Crop::Auto),
+            _ => (),
+        }
+        
+        let (size, offset) = text.split_once('+').ok_or(())?;
+        let (width, height) = parse_size(size).ok_or(())?;
+        let (x, y) = offset.split_once('+').ok_or(())?;
+        
+        Ok(Crop::Fixed(Rect::new(x.parse().map_err(|_| ())?, y.parse().map_err(|_| ())?, width, height)))
+    }
+}
+
+/// Parses a resolution written as `WIDTHxHEIGHT`.
+pub fn parse_size(text: &str) -> Option<(usize, usize)> {
+    let (width, height) = text.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// Parses an aspect ratio written as `WIDTH:HEIGHT` (e.g. `4:3`) into a single ratio.
+pub fn parse_aspect(text: &str) -> Option<f64> {
+    let (width, height) = text.split_once(':')?;
+    let (width, height) = (width.parse::<f64>().ok()?, height.parse::<f64>().ok()?);
+    if !(width > 0.0 && height > 0.0 && width.is_finite() && height.is_finite()) {
+        return None;
+    }
+    
+    Some(width / height)
+}
+
+
+/// Crops captured frames, and scales them to the output resolution, in packed RGB.
+/// 
+/// With an aspect ratio, the cropped picture is scaled to that shape, and centered within the
+/// output with black bars if the output has a different shape. Without one, the picture is simply
+/// stretched over the whole output.
+pub struct Transform {
+    crop: Crop,
+    aspect: Option<f64>,
+    input: (usize, usize),
+    output: (usize, usize),
+    
+    rect: Rect,
+    /// Part of the output the picture is scaled into.
+    dest: Rect,
+    /// For every output column and row within `dest`, the first input pixel sampled and the weight
+    /// (0-256) of the next one.
+    columns: Vec<(usize, u32)>,
+    rows: Vec<(usize, u32)>,
+    
+    frames: u32,
+    candidate: Option<(Rect, u32)>,
+}
+impl Transform {
+    /// `output` is the output resolution, if not derived from the cropped size and aspect ratio.
+    pub fn new(crop: Crop, aspect: Option<f64>, input: (usize, usize), output: Option<(usize, usize)>) -> Self {
+        let rect = match crop {
+            Crop::Fixed(rect) => rect,
+            Crop::None | Crop::Auto => Rect::new(0, 0, input.0, input.1),
+        };
+        let output = output.unwrap_or_else(|| match aspect {
+            Some(aspect) => (even((rect.height as f64 * aspect).round() as usize), rect.height),
+            None => (rect.width, rect.height),
+        });
+        
+        let mut transform = Self {
+            crop,
+            aspect,
+            input,
+            output,
+            rect,
+            dest: Rect::new(0, 0, output.0, output.1),
+            columns: vec![],
+            rows: vec![],
+            frames: 0,
+            candidate: None,
+        };
+        transform.set_rect(rect);
+        
+        transform
+    }
+    
+    pub fn output_size(&self) -> (usize, usize) { self.output }
+    
+    /// True if frames pass through unchanged.
+    fn identity(&self) -> bool {
+        self.input == self.output && self.rect == Rect::new(0, 0, self.input.0, self.input.1) && self.dest == Rect::new(0, 0, self.output.0, self.output.1)
+    }
+    
+    fn set_rect(&mut self, rect: Rect) {
+        self.rect = rect;
+        
+        let (out_width, out_height) = self.output;
+        self.dest = match self.aspect {
+            Some(aspect) if (out_width as f64 / out_height as f64) > aspect => {
+                let width = even((out_height as f64 * aspect).round() as usize).min(out_width);
+                Rect::new((out_width - width) / 2, 0, width, out_height)
+            },
+            Some(aspect) => {
+                let height = even((out_width as f64 / aspect).round() as usize).min(out_height);
+                Rect::new(0, (out_height - height) / 2, out_width, height)
+            },
+            None => Rect::new(0, 0, out_width, out_height),
+        };
+        
+        self.columns = sample_positions(rect.x, rect.width, self.dest.width);
+        self.rows = sample_positions(rect.y, rect.height, self.dest.height);
+    }
+    
+    /// Transforms a captured frame (`input` sized) into `out` (`output` sized).
+    pub fn apply(&mut self, src: &[u8], out: &mut [u8]) {
+        if self.crop == Crop::Auto {
+            self.detect(src);
+        }
+        
+        if self.identity() {
+            out.copy_from_slice(&src[..out.len()]);
+            return;
+        }
+        
+        let in_row = self.input.0 * 3;
+        let out_row = self.output.0 * 3;
+        if self.dest.width != self.output.0 || self.dest.height != self.output.1 {
+            out.fill(0);
+        }
+        
+        for (dy, &(sy, wy)) in self.rows.iter().enumerate() {
+            let sy1 = (sy + 1).min(self.rect.y + self.rect.height - 1);
+            let (row0, row1) = (&src[(sy * in_row)..][..in_row], &src[(sy1 * in_row)..][..in_row]);
+            let line = &mut out[((self.dest.y + dy) * out_row)..][..out_row];
+            
+            for (dx, &(sx, wx)) in self.columns.iter().enumerate() {
+                let sx1 = (sx + 1).min(self.rect.x + self.rect.width - 1);
+                let px = (self.dest.x + dx) * 3;
+                for c in 0..3 {
+                    let top = row0[sx * 3 + c] as u32 * (256 - wx) + row0[sx1 * 3 + c] as u32 * wx;
+                    let bottom = row1[sx * 3 + c] as u32 * (256 - wx) + row1[sx1 * 3 + c] as u32 * wx;
+                    line[px + c] = ((top * (256 - wy) + bottom * wy + (1 << 15)) >> 16) as u8;
+                }
+            }
+        }
+    }
+    
+    /// Every so often, finds the picture's bounding box, and crops to it once it has stayed put.
+    fn detect(&mut self, src: &[u8]) {
+        self.frames += 1;
+        if !self.frames.is_multiple_of(DETECT_INTERVAL) { return }
+        
+        let rect = match find_picture(src, self.input.0, self.input.1) {
+            Some(rect) => rect,
+            None => return, // a black screen says nothing about the borders
+        };
+        
+        let count = match self.candidate {
+            Some((candidate, count)) if candidate.close_to(&rect) => count + 1,
+            _ => 1,
+        };
+        self.candidate = Some((rect, count));
+        
+        if count >= DETECT_STABLE && !rect.close_to(&self.rect) {
+            debug!("Cropping to {}x{}+{}+{}.", rect.width, rect.height, rect.x, rect.y);
+            self.set_rect(rect);
+        }
+    }
+}
+
+/// Bounding box of the rows and columns that aren't mostly black, rounded to even sizes.
+fn find_picture(src: &[u8], width: usize, height: usize) -> Option<Rect> {
+    let bright = |px: &[u8]| (px[0] as u32 * 77 + px[1] as u32 * 150 + px[2] as u32 * 29) >> 8 > BORDER_LUMA;
+    
+    let mut columns = vec![0usize; width];
+    let mut rows = vec![0usize; height];
+    for (line, row) in src.chunks_exact(width * 3).zip(rows.iter_mut()) {
+        for (px, column) in line.chunks_exact(3).zip(columns.iter_mut()) {
+            if bright(px) {
+                *column += 1;
+                *row += 1;
+            }
+        }
+    }
+    
+    let column_min = ((height as f64 * BORDER_COVERAGE) as usize).max(1);
+    let row_min = ((width as f64 * BORDER_COVERAGE) as usize).max(1);
+    let left = columns.iter().position(|count| *count >= column_min)?;
+    let right = columns.iter().rposition(|count| *count >= column_min)?;
+    let top = rows.iter().position(|count| *count >= row_min)?;
+    let bottom = rows.iter().rposition(|count| *count >= row_min)?;
+    
+    // Rounding up to the smallest size can run past the frame's edge, so the box is moved back within it
+    let (crop_width, crop_height) = (even(right + 1 - left).max(2).min(width), even(bottom + 1 - top).max(2).min(height));
+    Some(Rect::new(left.min(width - crop_width), top.min(height - crop_height), crop_width, crop_height))
+}
+
+/// Maps each of `count` output positions onto the input span starting at `start`, as the input
+/// pixel before the sample point and the weight of the one after it.
+fn sample_positions(start: usize, len: usize, count: usize) -> Vec<(usize, u32)> {
+    let scale = len as f64 / count as f64;
+    (0..count).map(|i| {
+        let pos = ((i as f64 + 0.5) * scale - 0.5).clamp(0.0, (len - 1) as f64);
+        let base = pos.floor();
+        (start + base as usize, ((pos - base) * 256.0) as u32)
+    }).collect()
+}
+
+fn even(value: usize) -> usize {
+    value & !1
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// A black frame, with the given rectangles filled in white.
+    fn frame(width: usize, height: usize, white: &[Rect]) -> Vec<u8> {
+        let mut src = vec![0; width * height * 3];
+        for rect in white {
+            for y in rect.y..(rect.y + rect.height) {
+                src[((y * width + rect.x) * 3)..][..(rect.width * 3)].fill(255);
+            }
+        }
+        
+        src
+    }
+    
+    #[test]
+    fn finds_the_picture_within_borders() {
+        let src = frame(64, 48, &[Rect::new(8, 6, 48, 36)]);
+        assert_eq!(find_picture(&src, 64, 48), Some(Rect::new(8, 6, 48, 36)));
+        
+        // Odd sizes are rounded down
+        let src = frame(64, 48, &[Rect::new(8, 6, 47, 35)]);
+        assert_eq!(find_picture(&src, 64, 48), Some(Rect::new(8, 6, 46, 34)));
+        
+        assert_eq!(find_picture(&frame(64, 48, &[]), 64, 48), None);
+    }
+    
+    #[test]
+    fn picture_at_the_edge_stays_within_the_frame() {
+        // A bright column at the right edge, and a few bright pixels on the bottom row
+        let src = frame(720, 480, &[Rect::new(719, 470, 1, 10), Rect::new(700, 479, 20, 1)]);
+        assert_eq!(find_picture(&src, 720, 480), Some(Rect::new(718, 478, 2, 2)));
+        
+        let mut transform = Transform::new(Crop::Auto, None, (720, 480), None);
+        let mut out = vec![0; 720 * 480 * 3];
+        for _ in 0..(DETECT_INTERVAL * DETECT_STABLE) {
+            transform.apply(&src, &mut out);
+        }
+        assert_eq!(transform.rect, Rect::new(718, 478, 2, 2));
+    }
+    
+    #[test]
+    fn sample_positions_cover_the_span() {
+        assert_eq!(sample_positions(4, 4, 4), [(4, 0), (5, 0), (6, 0), (7, 0)]);
+        // Upscaling samples between pixels, clamped to the span at the ends
+        assert_eq!(sample_positions(0, 2, 4), [(0, 0), (0, 64), (0, 192), (1, 0)]);
+        // Downscaling samples between each pair of pixels
+        assert_eq!(sample_positions(10, 4, 2), [(10, 128), (12, 128)]);
+    }
+    
+    #[test]
+    fn wider_output_is_pillarboxed() {
+        let transform = Transform::new(Crop::None, Some(4.0 / 3.0), (640, 480), Some((854, 480)));
+        assert_eq!(transform.dest, Rect::new(107, 0, 640, 480));
+        
+        let mut transform = Transform::new(Crop::None, Some(4.0 / 3.0), (4, 3), Some((8, 3)));
+        assert_eq!(transform.dest, Rect::new(2, 0, 4, 3));
+        let mut out = vec![0x55; 8 * 3 * 3];
+        transform.apply(&frame(4, 3, &[Rect::new(0, 0, 4, 3)]), &mut out);
+        for line in out.chunks_exact(8 * 3) {
+            assert_eq!(line, [[0; 6], [255; 6], [255; 6], [0; 6]].concat());
+        }
+    }
+    
+    #[test]
+    fn taller_output_is_letterboxed() {
+        let transform = Transform::new(Crop::None, Some(4.0 / 3.0), (640, 480), Some((640, 640)));
+        assert_eq!(transform.dest, Rect::new(0, 80, 640, 480));
+        
+        let mut transform = Transform::new(Crop::None, Some(2.0), (4, 2), Some((4, 4)));
+        assert_eq!(transform.dest, Rect::new(0, 1, 4, 2));
+        let mut out = vec![0x55; 4 * 4 * 3];
+        transform.apply(&frame(4, 2, &[Rect::new(0, 0, 4, 2)]), &mut out);
+        assert_eq!(out, [vec![0; 12], vec![255; 24], vec![0; 12]].concat());
+    }
+    
+    #[test]
+    fn without_an_aspect_ratio_the_picture_is_stretched() {
+        let transform = Transform::new(Crop::Fixed(Rect::new(8, 0, 624, 480)), None, (640, 480), Some((640, 480)));
+        
+        assert_eq!(transform.dest, Rect::new(0, 0, 640, 480));
+        assert_eq!(transform.columns.first(), Some(&(8, 0)));
+        assert_eq!(transform.columns.last().map(|column| column.0), Some(631));
+    }
+}
@@ -1,78 +1,99 @@
 #![allow(unused)]
 
-use std::path::Path;
-use v4l::{Device, Format, FourCC};
-use v4l::buffer::Type;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use v4l::{Device, Format, FourCC, Fraction};
+use v4l::buffer::{Metadata, Type};
+use v4l::capability::Flags;
+use v4l::context;
 use v4l::format::{Colorspace, FieldOrder};
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
 use v4l::video::Capture;
+use v4l::video::capture::Parameters;
 use v4l::io::mmap::Stream;
+use crate::config::CaptureConfig;
+use crate::convert::{Converter, PixelFormat};
 
 
-pub const SUPPORTED_FOURCC: [[u8; 4]; 1] = [*b"RGBP",];
-
 #[derive(Debug)]
 pub enum Error {
     IoError(std::io::Error),
     NoDeviceFound,
+    /// No device matches the given path, index or name.
+    DeviceNotFound(String),
     NoSupportedColorFormat,
+    /// The requested pixel format isn't offered by the device, or can't be decoded by the server.
+    UnsupportedFormat(FourCC),
+    /// The device can't capture at the requested resolution, with the chosen pixel format.
+    UnsupportedResolution(FourCC, u32, u32),
 }
 use Error::*;
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoError(err) => write!(f, "{}", err),
+            NoDeviceFound => write!(f, "no capture devices found"),
+            DeviceNotFound(selector) => write!(f, "no capture device matches {:?}", selector),
+            NoSupportedColorFormat => write!(f, "the device offers none of the supported pixel formats ({})", supported_list()),
+            UnsupportedFormat(fourcc) => write!(f, "pixel format {} is not offered by the device, or not supported ({})", fourcc, supported_list()),
+            UnsupportedResolution(fourcc, width, height) => write!(f, "the device can't capture {} at {}x{} (see --list-devices)", fourcc, width, height),
+        }
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        IoError(err)
+    }
+}
+
+fn supported_list() -> String {
+    PixelFormat::ALL.iter().map(|format| FourCC::new(&format.fourcc()).to_string()).collect::<Vec<_>>().join(", ")
+}
 
 pub struct VideoStream<'a> {
     pub dev: Device,
     pub stream: Stream<'a>,
 }
 impl<'a> VideoStream<'a> {
-    pub fn devices() -> Vec<Device> {
-        let mut devices = vec![];
-        
-        for i in 0..20 {
-            match Device::new(i) {
-                Ok(dev) => devices.push(dev),
-                Err(_) => ()
-            }
-        }
+    /// Every V4L device node on the system, in order of their index, with their paths.
+    pub fn devices() -> Vec<(PathBuf, Device)> {
+        let mut nodes = context::enum_devices();
+        nodes.sort_by_key(|node| node.index());
         
-        devices
+        nodes.iter()
+            .filter_map(|node| Device::with_path(node.path()).ok().map(|dev| (node.path().to_owned(), dev)))
+            .collect()
     }
     
     pub fn with_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        match Device::with_path(path) {
-            Ok(dev) => {
-                let stream = Stream::with_buffers(&dev, Type::VideoCapture, 4).unwrap();
-                
-                Ok(Self {
-                    dev,
-                    stream,
-                })
-            },
-            Err(err) => Err(IoError(err))
-        }
+        Self::with(Device::with_path(path)?)
     }
     
-    pub fn with(dev: Device) -> Self {
-        let stream = Stream::with_buffers(&dev, Type::VideoCapture, 4).unwrap();
+    pub fn with(dev: Device) -> Result<Self, Error> {
+        let stream = Stream::with_buffers(&dev, Type::VideoCapture, 4)?;
         
-        Self {
+        Ok(Self {
             dev,
             stream,
-        }
+        })
     }
     
-    pub fn new() -> Result<Self, Error> {
-        let mut devices = Self::devices();
-        if devices.is_empty() {
-            return Err(NoDeviceFound);
-        }
-        
-        let mut dev = devices.remove(0);
-        let fmt = init_fmt(&mut dev);
+    /// Opens the configured device, and sets it up to capture in the configured format.
+    pub fn open(config: &CaptureConfig) -> Result<Self, Error> {
+        let (path, dev) = find_device(config.device.as_deref())?;
+        let fmt = set_format(&dev, config)?;
+        info!("Capturing from {} in {} at {}x{}.", path.display(), fmt.fourcc, fmt.width, fmt.height);
         debug!("Capture device format:\n{}", fmt);
-        if !SUPPORTED_FOURCC.contains(&fmt.fourcc.repr) {
-            return Err(NoSupportedColorFormat);
+        
+        if let Some(frame_rate) = config.frame_rate {
+            let interval = Fraction::new(1000, (frame_rate * 1000.0).round() as u32);
+            if let Err(err) = dev.set_params(&Parameters::new(interval)) {
+                warn!("Failed to set the capture frame rate: {}", err);
+            }
         }
         
-        let stream = Stream::with_buffers(&dev, Type::VideoCapture, 4).unwrap();
+        let stream = Stream::with_buffers(&dev, Type::VideoCapture, 4)?;
         
         Ok(Self {
             dev,
@@ -83,27 +104,204 @@ impl<'a> VideoStream<'a> {
     /// Attempts to resize the resolution of the captured video.
     /// 
     /// Returns the resulting format.
-    pub fn resize(&mut self, width: u32, height: u32) -> Format {
-        let mut fmt = self.dev.format().unwrap();
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<Format, Error> {
+        let mut fmt = self.dev.format()?;
         fmt.width = width;
         fmt.height = height;
         
-        self.dev.set_format(&fmt).unwrap()
+        Ok(self.dev.set_format(&fmt)?)
     }
     
     /// Sets the maximum possible resolution of the captured video.
-    pub fn resize_max(&mut self) -> Format {
+    pub fn resize_max(&mut self) -> Result<Format, Error> {
         self.resize(u32::MAX, u32::MAX)
     }
+    
+    /// Creates a converter for the format the device is currently capturing in. When capturing one
+    /// field at a time, each buffer only holds half of the frame's lines.
+    pub fn converter(&self) -> Result<Converter, Error> {
+        let fmt = self.dev.format()?;
+        let format = PixelFormat::from_fourcc(&fmt.fourcc.repr).ok_or(UnsupportedFormat(fmt.fourcc))?;
+        let height = match fmt.field_order {
+            FieldOrder::Alternate | FieldOrder::Top | FieldOrder::Bottom => fmt.height / 2,
+            _ => fmt.height,
+        };
+        
+        Ok(Converter::new(format, fmt.width as usize, height as usize, fmt.stride as usize))
+    }
+    
+    /// Frames per second the device is configured to capture at, if it reports one.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let interval = self.dev.params().ok()?.interval;
+        if interval.numerator == 0 || interval.denominator == 0 {
+            return None;
+        }
+        
+        Some(interval.denominator as f64 / interval.numerator as f64)
+    }
+}
+
+/// The part of a captured buffer holding the frame. Compressed frames don't fill the whole buffer.
+pub fn used<'b>(buf: &'b [u8], meta: &Metadata) -> &'b [u8] {
+    match meta.bytesused as usize {
+        0 => buf,
+        used => &buf[..used.min(buf.len())],
+    }
+}
+
+/// Finds the device to capture from. `selector` may be a device path (e.g. `/dev/video2`), an index
+/// (e.g. `2`), or part of the device's name (e.g. `USB Video`). Without one, the first device able
+/// to capture video is used.
+pub fn find_device(selector: Option<&str>) -> Result<(PathBuf, Device), Error> {
+    let selector = match selector {
+        Some(selector) => selector,
+        None => return VideoStream::devices().into_iter()
+            .find(|(_, dev)| can_capture(dev))
+            .ok_or(NoDeviceFound),
+    };
+    
+    if selector.starts_with('/') {
+        return Device::with_path(selector)
+            .map(|dev| (PathBuf::from(selector), dev))
+            .map_err(|_| DeviceNotFound(selector.to_owned()));
+    }
+    if let Ok(index) = selector.parse::<usize>() {
+        return Device::new(index)
+            .map(|dev| (PathBuf::from(format!("/dev/video{}", index)), dev))
+            .map_err(|_| DeviceNotFound(selector.to_owned()));
+    }
+    
+    let name = selector.to_lowercase();
+    VideoStream::devices().into_iter()
+        .filter(|(_, dev)| can_capture(dev))
+        .find(|(_, dev)| dev.query_caps().map(|caps| caps.card.to_lowercase().contains(&name)).unwrap_or(false))
+        .ok_or_else(|| DeviceNotFound(selector.to_owned()))
+}
+
+fn can_capture(dev: &Device) -> bool {
+    dev.query_caps().map(|caps| caps.capabilities.contains(Flags::VIDEO_CAPTURE)).unwrap_or(false)
 }
 
-fn init_fmt(dev: &mut Device) -> Format {
-    let mut fmt = dev.format().unwrap();
-    fmt.width = u32::MAX;
-    fmt.height = u32::MAX;
-    fmt.fourcc = FourCC::new(b"RGBP");
+/// Sets the device's format to the configured pixel format and resolution, after checking that the
+/// device offers them. Without a configured pixel format, the most preferred one offered is used (see `PixelFormat::ALL`).
+fn set_format(dev: &Device, config: &CaptureConfig) -> Result<Format, Error> {
+    let offered: Vec<FourCC> = dev.enum_formats()?.iter().map(|desc| desc.fourcc).collect();
+    
+    let fourcc = match &config.format {
+        Some(format) => {
+            let fourcc = FourCC::new(&fourcc_bytes(format));
+            if PixelFormat::from_fourcc(&fourcc.repr).is_none() || !offered.contains(&fourcc) {
+                return Err(UnsupportedFormat(fourcc));
+            }
+            fourcc
+        },
+        None => PixelFormat::ALL.iter()
+            .map(|format| FourCC::new(&format.fourcc()))
+            .find(|fourcc| offered.contains(fourcc))
+            .ok_or(NoSupportedColorFormat)?,
+    };
+    
+    let (width, height) = (config.width as u32, config.height as u32);
+    let sizes = dev.enum_framesizes(fourcc)?;
+    // Some drivers don't list their sizes, in which case setting the format is the only way to find out
+    if !sizes.is_empty() && !sizes.iter().any(|size| size_matches(&size.size, width, height)) {
+        return Err(UnsupportedResolution(fourcc, width, height));
+    }
+    
+    let mut fmt = dev.format()?;
+    fmt.width = width;
+    fmt.height = height;
+    fmt.fourcc = fourcc;
     fmt.colorspace = Colorspace::NTSC;
     fmt.field_order = FieldOrder::Alternate;
     
-    dev.set_format(&fmt).unwrap()
-}
\ No newline at end of file
+    let fmt = dev.set_format(&fmt)?;
+    if fmt.fourcc != fourcc || fmt.width != width || fmt.height != height {
+        return Err(UnsupportedResolution(fourcc, width, height));
+    }
+    
+    Ok(fmt)
+}
+
+/// Pads or truncates a pixel format name into a FourCC code. Checked by the configuration beforehand.
+pub fn fourcc_bytes(format: &str) -> [u8; 4] {
+    let mut repr = [b' '; 4];
+    for (byte, char) in repr.iter_mut().zip(format.bytes()) {
+        *byte = char;
+    }
+    
+    repr
+}
+
+fn size_matches(size: &FrameSizeEnum, width: u32, height: u32) -> bool {
+    match size {
+        FrameSizeEnum::Discrete(size) => size.width == width && size.height == height,
+        FrameSizeEnum::Stepwise(size) => {
+            (size.min_width..=size.max_width).contains(&width) && (size.min_height..=size.max_height).contains(&height)
+                && (width - size.min_width).is_multiple_of(size.step_width.max(1))
+                && (height - size.min_height).is_multiple_of(size.step_height.max(1))
+        },
+    }
+}
+
+/// Prints every V4L device, along with the pixel formats, resolutions and frame rates it offers.
+pub fn list_devices() {
+    let devices = VideoStream::devices();
+    if devices.is_empty() {
+        println!("No capture devices found.");
+    }
+    
+    for (path, dev) in devices {
+        match dev.query_caps() {
+            Ok(caps) if caps.capabilities.contains(Flags::VIDEO_CAPTURE) => println!("{}: {} ({}, {})", path.display(), caps.card, caps.driver, caps.bus),
+            Ok(caps) => {
+                println!("{}: {} (not a capture device)", path.display(), caps.card);
+                continue;
+            },
+            Err(err) => {
+                println!("{}: failed to query device: {}", path.display(), err);
+                continue;
+            }
+        }
+        
+        let formats = match dev.enum_formats() {
+            Ok(formats) => formats,
+            Err(err) => {
+                println!("    failed to list formats: {}", err);
+                continue;
+            }
+        };
+        for desc in formats {
+            let note = if PixelFormat::from_fourcc(&desc.fourcc.repr).is_some() { "" } else { ", not supported" };
+            println!("    {} ({}{})", desc.fourcc, desc.description, note);
+            
+            for size in dev.enum_framesizes(desc.fourcc).unwrap_or_default() {
+                let (label, width, height) = match &size.size {
+                    FrameSizeEnum::Discrete(size) => (format!("{}x{}", size.width, size.height), size.width, size.height),
+                    FrameSizeEnum::Stepwise(size) => (format!("{}x{} to {}x{} in steps of {}x{}",
+                        size.min_width, size.min_height, size.max_width, size.max_height, size.step_width, size.step_height), size.max_width, size.max_height),
+                };
+                
+                let rates: Vec<String> = dev.enum_frameintervals(desc.fourcc, width, height).unwrap_or_default().iter().map(|interval| match &interval.interval {
+                    FrameIntervalEnum::Discrete(interval) => format_fps(interval),
+                    FrameIntervalEnum::Stepwise(interval) => format!("{} to {}", format_fps(&interval.max), format_fps(&interval.min)),
+                }).collect();
+                
+                if rates.is_empty() {
+                    println!("        {}", label);
+                } else {
+                    println!("        {} at {} fps", label, rates.join(", "));
+                }
+            }
+        }
+    }
+}
+
+fn format_fps(interval: &Fraction) -> String {
+    if interval.numerator == 0 {
+        return "?".to_owned();
+    }
+    
+    let fps = interval.denominator as f64 / interval.numerator as f64;
+    if fps.fract() == 0.0 { format!("{}", fps) } else { format!("{:.2}", fps) }
+}
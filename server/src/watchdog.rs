@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crossbeam_channel::Sender;
+use remote64_common::Component;
+use remote64_common::intercom::InterMessage;
+use crate::board::Board;
+
+/// Capture that hasn't delivered anything for this long is considered stalled.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+/// Frames in a row that can't be converted before the capture is considered to have lost its signal.
+pub const BAD_FRAME_LIMIT: u32 = 30;
+/// How often a failed capture is checked on, while waiting to reopen it.
+pub const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+/// Shortest and longest waits between attempts at recovering a failed component. The wait doubles after every failed attempt.
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// How often the controller board is pinged, while it's connected.
+const BOARD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+
+/// Keeps track of whether one of the server's components works, and when to next try recovering it
+/// once it has failed.
+/// 
+/// The rest of the server is told whenever the component fails or recovers (see `InterMessage::Degraded`),
+/// and both are noted in the session's recording.
+pub struct Watchdog {
+    component: Component,
+    send: Sender<InterMessage>,
+    failed: bool,
+    backoff: Duration,
+    retry_at: Instant,
+}
+impl Watchdog {
+    pub fn new(component: Component, send: Sender<InterMessage>) -> Self { Self {
+        component,
+        send,
+        failed: false,
+        backoff: BACKOFF_MIN,
+        retry_at: Instant::now(),
+    }}
+    
+    pub fn failed(&self) -> bool { self.failed }
+    
+    /// Notes that the component failed, or that an attempt at recovering it did.
+    pub fn fail(&mut self, reason: &str) {
+        if self.failed {
+            self.backoff = (self.backoff * 2).min(BACKOFF_MAX);
+            warn!("Failed to recover the {}, retrying in {}s: {}", self.component, self.backoff.as_secs(), reason);
+        } else {
+            error!("Lost the {}: {}", self.component, reason);
+            self.failed = true;
+            self.backoff = BACKOFF_MIN;
+            self.send.try_send(InterMessage::Degraded(self.component, true)).unwrap_or_default();
+            self.send.try_send(InterMessage::SessionEvent(format!("Lost the {}: {}", self.component, reason))).unwrap_or_default();
+        }
+        self.retry_at = Instant::now() + self.backoff;
+    }
+    
+    /// Notes that the component works.
+    pub fn ok(&mut self) {
+        if !self.failed { return }
+        
+        info!("Recovered the {}.", self.component);
+        self.failed = false;
+        self.send.try_send(InterMessage::Degraded(self.component, false)).unwrap_or_default();
+        self.send.try_send(InterMessage::SessionEvent(format!("Recovered the {}.", self.component))).unwrap_or_default();
+    }
+    
+    /// True once it's time for another attempt at recovering the failed component.
+    pub fn retry_due(&self) -> bool {
+        self.failed && Instant::now() >= self.retry_at
+    }
+    
+    /// How long until the next attempt at recovering the failed component.
+    pub fn retry_in(&self) -> Duration {
+        self.retry_at.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Pings the controller board regularly from a thread of its own, reconnecting to it whenever it stops answering.
+pub fn watch_board(board: Arc<Board>, send: Sender<InterMessage>) {
+    std::thread::Builder::new().name("BoardWatchdog".to_owned()).spawn(move || {
+        let mut watchdog = Watchdog::new(Component::ControllerBoard, send);
+        loop {
+            let result = if board.connected() { board.ping() } else { board.connect() };
+            match result {
+                Ok(()) => watchdog.ok(),
+                Err(err) => watchdog.fail(&err.to_string()),
+            }
+            
+            std::thread::sleep(if watchdog.failed() { watchdog.retry_in() } else { BOARD_CHECK_INTERVAL });
+        }
+    }).unwrap();
+}